@@ -23,7 +23,7 @@ fi
 LOG=debug,hyper=info
 
 tmux select-pane -t 1
-tmux send-keys "clear && RUST_LOG=debug cargo run -q --bin node_directory" C-m
+tmux send-keys "clear && RUST_LOG=debug cargo run -q --bin node_directory -- --voting-interval 5" C-m
 sleep 2
 
 for i in {2..6}; do
@@ -38,5 +38,5 @@ if [ "$1" == "test" ]; then
 	tmux send-keys -t 7 "clear && RUST_LOG=$LOG cargo run -q --bin client" C-m
 	tmux send-keys -t 8 "python3 ./src/tor/node/test_server.py 12345" C-m
 else
-	tmux send-keys -t 7 "clear && RUST_LOG=$LOG cargo run -q --bin proxy" C-m
+	tmux send-keys -t 7 "clear && RUST_LOG=$LOG cargo run -q --bin proxy -- --allow-same-subnet --authority-key \$(cat directory.key.pub)" C-m
 fi
@@ -1,7 +1,11 @@
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
-use log::info;
-use rustor::tor::client::nodes_handshake;
+use clap::Parser;
+use rustor::{
+    logging::{self, LogFormat},
+    tor::client::nodes_handshake,
+};
+use tracing::{info, level_filters::LevelFilter};
 
 macro_rules! localhost {
     ($name:ident,$port:expr) => {
@@ -18,16 +22,22 @@ localhost!(NODE5, 10004);
 localhost!(NODE6, 10005);
 localhost!(FAKE_SERVER, 12345);
 
+#[derive(clap::Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::builder().format_timestamp(None).init();
-
-    info!("Connected to node!");
+    let args = Args::parse();
+    logging::init(args.log_format, LevelFilter::INFO)?;
 
     let (mut reader, mut writer) =
         nodes_handshake(vec![NODE1, NODE2, NODE3, NODE4, NODE5, NODE6], FAKE_SERVER).await?;
 
-    info!("Finised handshake with!");
+    info!("Finished handshake");
     tokio::spawn(async move {
         for i in 1..20 {
             let message = format!("Hello {}", i);
@@ -38,6 +48,6 @@ async fn main() -> anyhow::Result<()> {
     loop {
         let message = reader.read().await?;
         let message = String::from_utf8(message)?;
-        info!("Received message: {:?}", message);
+        info!(bytes = message.len(), "Received message");
     }
 }
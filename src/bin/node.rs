@@ -1,34 +1,203 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use anyhow::Context;
 use clap::Parser;
-use log::info;
-use rustor::tor::{node::handle_connection, node_directory::add_node};
-use tokio::net::TcpListener;
+use rustor::{
+    logging::{self, LogFormat},
+    tor::{bandwidth::BandwidthLimit, exit_policy::ExitPolicy, node_config::NodeConfig},
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
-#[derive(clap::Parser)]
+#[derive(clap::Parser, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Port to use for tor node
-    #[arg(short, long, default_value_t = 0)]
-    port: u16,
+    /// TOML configuration file, command line options override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Port to use for tor node, overrides the port of the bind address
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Address to listen on
+    #[arg(long)]
+    bind: Option<SocketAddr>,
+
+    /// Address registered at the directory
+    #[arg(long)]
+    advertise: Option<SocketAddr>,
+
+    /// Directory url, can be repeated
+    #[arg(long = "directory")]
+    directories: Vec<String>,
+
+    #[arg(long)]
+    nickname: Option<String>,
+
+    #[arg(long)]
+    contact: Option<String>,
+
+    /// Shared by every node of the operator
+    #[arg(long)]
+    family: Option<String>,
+
+    /// Comma separated exit rules, e.g. "accept *:443, reject *:*"
+    #[arg(long)]
+    exit_policy: Option<ExitPolicy>,
+
+    /// Bandwidth rate in bytes per second
+    #[arg(long)]
+    bandwidth_rate: Option<u64>,
+
+    /// Bandwidth burst in bytes, defaults to the rate
+    #[arg(long, requires = "bandwidth_rate")]
+    bandwidth_burst: Option<u64>,
+
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Seconds existing circuits get to finish after a shutdown signal
+    #[arg(long)]
+    drain_secs: Option<u64>,
+
+    /// Seconds between heartbeats to the directories
+    #[arg(long)]
+    heartbeat_secs: Option<u64>,
+
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Log output format
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// Loopback address for the control port, the password is taken from the config file
+    #[arg(long)]
+    control_address: Option<SocketAddr>,
+
+    /// Address to serve prometheus metrics at
+    #[arg(long)]
+    metrics_address: Option<SocketAddr>,
+}
+
+impl Args {
+    /// Loads the config file and applies the command line overrides on top of it
+    fn load_config(&self) -> anyhow::Result<NodeConfig> {
+        let mut config = match &self.config {
+            Some(path) => NodeConfig::load(path)?,
+            None => NodeConfig::default(),
+        };
+
+        let args = self.clone();
+        if let Some(bind) = args.bind {
+            config.bind_address = bind;
+        }
+        if let Some(port) = args.port {
+            config.bind_address.set_port(port);
+        }
+        if let Some(advertise) = args.advertise {
+            config.advertised_address = Some(advertise);
+        }
+        if !args.directories.is_empty() {
+            config.directory_urls = args.directories;
+        }
+        if let Some(nickname) = args.nickname {
+            config.nickname = nickname;
+        }
+        if let Some(contact) = args.contact {
+            config.contact_info = Some(contact);
+        }
+        if let Some(family) = args.family {
+            config.family = Some(family);
+        }
+        if let Some(exit_policy) = args.exit_policy {
+            config.exit_policy = exit_policy;
+        }
+        if let Some(rate) = args.bandwidth_rate {
+            config.bandwidth = Some(BandwidthLimit {
+                rate,
+                burst: args.bandwidth_burst.unwrap_or(rate),
+            });
+        }
+        if let Some(data_dir) = args.data_dir {
+            config.data_directory = Some(data_dir);
+        }
+        if let Some(drain_secs) = args.drain_secs {
+            config.drain_secs = drain_secs;
+        }
+        if let Some(heartbeat_secs) = args.heartbeat_secs {
+            config.heartbeat_secs = heartbeat_secs;
+        }
+        if let Some(log_level) = args.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = args.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(control_address) = args.control_address {
+            config.control_address = Some(control_address);
+        }
+        if let Some(metrics_address) = args.metrics_address {
+            config.metrics_address = Some(metrics_address);
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::builder().format_timestamp(None).init();
+async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    let mut config = args.load_config()?;
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", args.port)).await?;
-    let local_addr = listener.local_addr()?;
-    add_node(&local_addr).await?;
+    logging::init(config.log_format, config.log_level())?;
 
-    let local_addr = listener.local_addr()?;
-    println!("Listening on {}", local_addr);
+    if let Some(data_directory) = &config.data_directory {
+        std::fs::create_dir_all(data_directory).with_context(|| {
+            format!(
+                "Failed creating data directory {}",
+                data_directory.display()
+            )
+        })?;
+    }
 
-    loop {
-        let Ok((stream, addr)) = listener.accept().await else {
-            continue;
-        };
-        info!("New connection!, {}", addr);
+    let node = config.node_server()?.start().await?;
+    println!("Listening on {}", node.local_addr());
 
-        tokio::spawn(handle_connection(stream));
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+            _ = hangup.recv() => {
+                if args.config.is_none() {
+                    warn!("Received SIGHUP but the node was started without a config file");
+                    continue;
+                }
+                info!("Reloading configuration");
+                let reloaded = match args.load_config() {
+                    Ok(reloaded) => reloaded,
+                    Err(err) => {
+                        error!("Keeping the current configuration: {:?}", err);
+                        continue;
+                    }
+                };
+                for option in config.restart_required(&reloaded) {
+                    warn!("{} changed, the change takes effect after a restart", option);
+                }
+                match reloaded.apply_live(&node).await {
+                    Ok(()) => config = reloaded,
+                    Err(err) => error!("Keeping the current configuration: {:?}", err),
+                }
+            }
+        }
     }
+
+    info!("Shutting down");
+    node.shutdown().await;
+    Ok(())
 }
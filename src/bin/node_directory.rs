@@ -1,93 +1,124 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use const_format::concatcp;
-use env_logger::Env;
-use log::info;
-use serde::Deserialize;
-use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
-use tokio::{
-    net::TcpStream,
-    sync::Mutex,
-    time::{interval, timeout},
+use clap::Parser;
+use prometheus::Registry;
+use rustor::{
+    logging::{self, LogFormat},
+    tor::{
+        authority::{Authority, AuthorityPeer, RegistrationLimits},
+        keys,
+    },
 };
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, level_filters::LevelFilter};
 
-const PORT: u16 = 30000;
+#[derive(clap::Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Address to serve prometheus metrics at
+    #[arg(long)]
+    metrics_address: Option<SocketAddr>,
 
-type Valid = bool;
-#[derive(Default)]
-struct AppState {
-    nodes: tokio::sync::RwLock<BTreeMap<SocketAddr, Mutex<Valid>>>,
-}
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
 
-#[derive(Deserialize)]
-struct GetNodesQuery {
-    amount: Option<usize>,
-}
+    #[arg(long, default_value = "0.0.0.0:30000")]
+    listen: SocketAddr,
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-    let app_state = Arc::new(AppState::default());
-    let app_state_for_task = app_state.clone();
-    let data = web::Data::new(app_state);
-
-    // Clone the app state for the background task
-
-    // Spawn a background task to invalidate/update the app state
-    tokio::task::spawn(async move {
-        let mut interval = interval(Duration::from_secs(5));
-        loop {
-            println!("Invalidating");
-            interval.tick().await;
-            let nodes = app_state_for_task.nodes.read().await;
-            for (node, is_valid) in &*nodes {
-                if let Ok(Ok(_)) =
-                    timeout(Duration::from_secs_f32(3.0), TcpStream::connect(node)).await
-                {
-                    let mut is_valid_guard = is_valid.lock().await;
-                    println!("Valid node: {:?}", node);
-                    *is_valid_guard = true;
-                    drop(is_valid_guard)
-                } else {
-                    let mut is_valid_guard = is_valid.lock().await;
-                    println!("Invalid node: {:?}", node);
-                    *is_valid_guard = false;
-                    drop(is_valid_guard)
-                };
-            }
-        }
-    });
-
-    HttpServer::new(move || {
-        App::new()
-            .app_data(data.clone())
-            .route("/add_node", web::post().to(add_node))
-            .route("/get_nodes", web::get().to(get_nodes))
-    })
-    .bind(concatcp!("0.0.0.0:", PORT))?
-    .run()
-    .await
-}
+    /// Key votes and the consensus are signed with, generated when missing. The public key clients
+    /// and other authorities pin is written next to it with a .pub extension
+    #[arg(long, default_value = "directory.key")]
+    key_file: PathBuf,
+
+    /// Directory the registered nodes and the consensus are kept in across restarts
+    #[arg(long, default_value = "directory-data")]
+    data_dir: PathBuf,
+
+    /// Another authority as `url=key`, every authority has to list all the others
+    #[arg(long = "peer")]
+    peers: Vec<AuthorityPeer>,
+
+    /// Seconds a node gets to complete the probe's handshake
+    #[arg(long, default_value_t = 3)]
+    probe_timeout: u64,
+
+    /// Nodes probed at the same time
+    #[arg(long, default_value_t = 32)]
+    probe_concurrency: usize,
+
+    /// Address nodes relay to when their bandwidth is measured, a free port by default
+    #[arg(long)]
+    echo_address: Option<SocketAddr>,
 
-async fn add_node(data: web::Data<Arc<AppState>>, node: web::Json<SocketAddr>) -> impl Responder {
-    let nodes = &mut *data.nodes.write().await;
-    nodes.insert(node.into_inner(), Mutex::new(false));
+    /// Seconds between consensus votes, has to be the same at every authority
+    #[arg(long, default_value_t = 60)]
+    voting_interval: u64,
 
-    HttpResponse::Ok().body("Node added")
+    /// New nodes accepted from one IP address per hour
+    #[arg(long, default_value_t = RegistrationLimits::default().per_ip)]
+    registrations_per_ip: usize,
+
+    /// New nodes accepted from one /24 or IPv6 /48 per hour
+    #[arg(long, default_value_t = RegistrationLimits::default().per_subnet)]
+    registrations_per_subnet: usize,
+
+    /// Approved running nodes a family may have before no more can register with it
+    #[arg(long, default_value_t = RegistrationLimits::default().max_family_size)]
+    max_family_size: usize,
+
+    /// Leave new nodes out of the consensus until they're approved through the admin API
+    #[arg(long)]
+    require_approval: bool,
+
+    /// File with the bearer token of the /admin endpoints, they're disabled without one
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
 }
 
-async fn get_nodes(
-    data: web::Data<Arc<AppState>>,
-    query: web::Query<GetNodesQuery>,
-) -> impl Responder {
-    let nodes = &*data.nodes.read().await;
-    let amount = query.amount.unwrap_or(5);
-    let mut valid_nodes = vec![];
-    for (node, is_valid) in nodes.iter().take(amount) {
-        let is_valid = *is_valid.lock().await;
-        if is_valid {
-            valid_nodes.push(node)
-        }
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    logging::init(args.log_format, LevelFilter::INFO)?;
+    let signing_key = keys::load_or_generate_signing_key(&args.key_file)?;
+    let public_key = keys::encode_key(&signing_key.verifying_key());
+    let mut public_key_file = args.key_file.into_os_string();
+    public_key_file.push(".pub");
+    std::fs::write(public_key_file, &public_key)?;
+    info!(%public_key, peers = args.peers.len(), "Signing votes and the consensus");
+
+    let mut authority = args
+        .peers
+        .into_iter()
+        .fold(Authority::new(signing_key), |authority, peer| {
+            authority.peer(peer)
+        })
+        .bind(args.listen)
+        .data_dir(args.data_dir)
+        .probe_timeout(Duration::from_secs(args.probe_timeout))
+        .probe_concurrency(args.probe_concurrency)
+        .voting_interval(Duration::from_secs(args.voting_interval))
+        .registration_limits(RegistrationLimits {
+            per_ip: args.registrations_per_ip,
+            per_subnet: args.registrations_per_subnet,
+            max_family_size: args.max_family_size,
+            ..Default::default()
+        })
+        .require_approval(args.require_approval);
+    if let Some(echo_address) = args.echo_address {
+        authority = authority.echo_address(echo_address);
+    }
+    if let Some(admin_token_file) = args.admin_token_file {
+        authority = authority.admin_token(std::fs::read_to_string(admin_token_file)?.trim());
+    }
+    let authority = authority.start().await?;
+    if let Some(metrics_address) = args.metrics_address {
+        let registry = Registry::new();
+        authority.metrics().register(&registry)?;
+        let (_, server) =
+            rustor::metrics::bind(metrics_address, registry, CancellationToken::new()).await?;
+        tokio::spawn(server);
     }
 
-    HttpResponse::Ok().json(&valid_nodes)
+    tokio::signal::ctrl_c().await?;
+    authority.shutdown().await;
+    Ok(())
 }
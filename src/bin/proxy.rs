@@ -1,33 +1,136 @@
-use gerevs::{
-    auth::NoAuthAuthenticator,
-    method_handlers::{AssociateDenier, BindDenier},
-    Socks5Socket,
+use std::{net::SocketAddr, num::NonZeroUsize, path::PathBuf, sync::Arc, time::Duration};
+
+use clap::Parser;
+use ed25519_dalek::VerifyingKey;
+use prometheus::Registry;
+use rustor::{
+    logging::{self, LogFormat},
+    metrics,
+    proxy::{self, ListenerConfig, ProxyMetrics},
+    tor::{keys, node_directory::DEFAULT_DIRECTORY, path::PathSelector},
+    Client,
 };
-use rustor::proxy::TorConnect;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::level_filters::LevelFilter;
+
+#[derive(clap::Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Address to serve prometheus metrics at
+    #[arg(long)]
+    metrics_address: Option<SocketAddr>,
+
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// SOCKS listener as `addr` or `addr=rule,rule`, streams differing in any rule never share
+    /// a circuit. Rules are auth, client-ip, dest-port and dest-host
+    #[arg(long = "listen", default_value = "0.0.0.0:1080=auth")]
+    listeners: Vec<ListenerConfig>,
+
+    /// Directory the consensus is fetched from, repeat to fall back to other authorities
+    #[arg(long = "directory", default_value = DEFAULT_DIRECTORY)]
+    directories: Vec<String>,
+
+    /// Base64 key of a directory authority whose signed consensus is trusted
+    #[arg(long = "authority-key", required = true, value_parser = keys::parse_key)]
+    authority_keys: Vec<VerifyingKey>,
+
+    /// Authorities that have to sign the consensus, defaults to a majority of the keys
+    #[arg(long)]
+    authority_threshold: Option<usize>,
+
+    /// Amount of nodes in every circuit
+    #[arg(long, default_value_t = NonZeroUsize::new(3).unwrap())]
+    hops: NonZeroUsize,
+
+    /// Nodes allowed as the first hop
+    #[arg(long, value_delimiter = ',')]
+    entry_nodes: Vec<SocketAddr>,
+
+    /// Nodes allowed as the last hop
+    #[arg(long, value_delimiter = ',')]
+    exit_nodes: Vec<SocketAddr>,
+
+    /// Nodes never used
+    #[arg(long, value_delimiter = ',')]
+    exclude_nodes: Vec<SocketAddr>,
+
+    /// Let hops share a subnet, needed when every node runs on one machine
+    #[arg(long)]
+    allow_same_subnet: bool,
+
+    /// Amount of long lived entry nodes, 0 picks a fresh entry for every circuit
+    #[arg(long, default_value_t = 3)]
+    guards: usize,
+
+    /// Directory the guards and the consensus are kept in across restarts
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Idle circuits kept ready for new connections
+    #[arg(long, default_value_t = 2)]
+    pool_size: usize,
+
+    /// Seconds an idle circuit may wait before it's closed
+    #[arg(long, default_value_t = 300)]
+    circuit_max_age: u64,
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let server = TcpListener::bind("0.0.0.0:1080").await?;
-    loop {
-        let (client, _addr) = server.accept().await?;
-
-        tokio::spawn(async move {
-            let result = handle_connection(client).await;
-            if let Err(err) = result {
-                eprintln!("Failed: {:?}", err);
-            }
-        });
+    let args = Args::parse();
+    logging::init(args.log_format, LevelFilter::INFO)?;
+    let mut tor = args
+        .authority_keys
+        .into_iter()
+        .fold(Client::builder(), |builder, key| builder.authority_key(key));
+    tor = args
+        .directories
+        .into_iter()
+        .fold(tor, |builder, directory| builder.directory(directory))
+        .path_selector(
+            PathSelector::default()
+                .hops(args.hops.get())
+                .entry_nodes(args.entry_nodes)
+                .exit_nodes(args.exit_nodes)
+                .exclude_nodes(args.exclude_nodes)
+                .distinct_subnets(!args.allow_same_subnet),
+        )
+        .guards(args.guards)
+        .pool_size(args.pool_size)
+        .circuit_max_age(Duration::from_secs(args.circuit_max_age));
+    if let Some(threshold) = args.authority_threshold {
+        tor = tor.authority_threshold(threshold);
+    }
+    if let Some(state_dir) = args.state_dir {
+        tor = tor.state_dir(state_dir);
+    }
+    let tor = tor.build();
+
+    let proxy_metrics = Arc::new(ProxyMetrics::default());
+    if let Some(metrics_address) = args.metrics_address {
+        let registry = Registry::new();
+        proxy_metrics.register(&registry)?;
+        tor.metrics().register(&registry)?;
+        let (_, server) =
+            metrics::bind(metrics_address, registry, CancellationToken::new()).await?;
+        tokio::spawn(server);
     }
-}
 
-async fn handle_connection(client: TcpStream) -> gerevs::Result<()> {
-    let socks5_stream = Socks5Socket::new(
-        client,
-        NoAuthAuthenticator,
-        TorConnect,
-        BindDenier,
-        AssociateDenier,
-    );
-    socks5_stream.run().await
+    let mut servers = JoinSet::new();
+    for config in args.listeners {
+        let listener = TcpListener::bind(config.addr).await?;
+        servers.spawn(proxy::serve(
+            listener,
+            config,
+            tor.clone(),
+            proxy_metrics.clone(),
+        ));
+    }
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+    Ok(())
 }
@@ -0,0 +1,724 @@
+//! High level client, picks nodes from the directory and builds circuits through them.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), rustor::tor::error::TorError> {
+//! use tokio::io::AsyncWriteExt;
+//!
+//! # let authority = rustor::tor::keys::parse_key("").unwrap();
+//! let client = rustor::Client::builder()
+//!     .authority_key(authority)
+//!     .hops(3)
+//!     .build();
+//! let mut stream = client.connect("example.com", 80).await?;
+//! stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+//! stream.flush().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod build_timeout;
+mod guards;
+mod isolation;
+mod network_cache;
+mod pool;
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use build_timeout::BuildTimeEstimator;
+use ed25519_dalek::VerifyingKey;
+use guards::{unix_now, GuardSet};
+pub use guards::{Guard, GuardConfig};
+use isolation::IsolatedPaths;
+use network_cache::NetworkCache;
+use pool::CircuitPool;
+use prometheus::{HistogramOpts, HistogramVec, IntGauge, Registry};
+use tokio::sync::Mutex;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, info, warn};
+
+use crate::{
+    proxy::IsolationKey,
+    tor::{
+        client::{prepare_circuit, CircuitTimeouts, PendingCircuit},
+        error::TorError,
+        node_directory::{get_network, Network, NetworkDocument, DEFAULT_DIRECTORY},
+        path::{PathSelector, Position, Relay},
+        stream::TorStream,
+    },
+};
+
+pub struct ClientBuilder {
+    directories: Vec<String>,
+    authority_keys: Vec<VerifyingKey>,
+    authority_threshold: Option<usize>,
+    path: PathSelector,
+    timeouts: CircuitTimeouts,
+    directory_timeout: Duration,
+    retries: usize,
+    node_cache_ttl: Duration,
+    pool_size: usize,
+    circuit_max_age: Duration,
+    guards: GuardConfig,
+    adaptive_build_timeout: bool,
+    state_dir: Option<PathBuf>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            directories: vec![],
+            authority_keys: vec![],
+            authority_threshold: None,
+            path: PathSelector::default(),
+            timeouts: CircuitTimeouts::default(),
+            directory_timeout: Duration::from_secs(10),
+            retries: 2,
+            node_cache_ttl: Duration::from_secs(60),
+            pool_size: 0,
+            circuit_max_age: Duration::from_secs(5 * 60),
+            guards: GuardConfig::default(),
+            adaptive_build_timeout: true,
+            state_dir: None,
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Adds a directory the consensus is fetched from, later ones are asked when the earlier ones
+    /// fail. Defaults to a directory on localhost
+    pub fn directory(mut self, url: impl Into<String>) -> Self {
+        self.directories.push(url.into());
+        self
+    }
+
+    /// Trusts consensus documents signed by `key`, every consensus is rejected without one
+    pub fn authority_key(mut self, key: VerifyingKey) -> Self {
+        self.authority_keys.push(key);
+        self
+    }
+
+    /// Authorities that have to sign a consensus, defaults to a majority of the trusted keys
+    pub fn authority_threshold(mut self, threshold: usize) -> Self {
+        self.authority_threshold = Some(threshold);
+        self
+    }
+
+    /// Amount of nodes in every circuit
+    pub fn hops(mut self, hops: usize) -> Self {
+        self.path = self.path.hops(hops);
+        self
+    }
+
+    /// Replaces the path selection, including the hop count
+    pub fn path_selector(mut self, path: PathSelector) -> Self {
+        self.path = path;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+
+    pub fn exit_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.exit_connect = timeout;
+        self
+    }
+
+    /// Time every hop together gets to complete its handshake, only used until enough builds
+    /// were observed when the timeout is adaptive
+    pub fn build_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.build = timeout;
+        self
+    }
+
+    /// Learns the build timeout from observed build times, kept in the state directory
+    pub fn adaptive_build_timeout(mut self, adaptive: bool) -> Self {
+        self.adaptive_build_timeout = adaptive;
+        self
+    }
+
+    pub fn directory_timeout(mut self, timeout: Duration) -> Self {
+        self.directory_timeout = timeout;
+        self
+    }
+
+    /// Circuits built through a fresh path after a failure
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// How long the downloaded consensus is used before it's downloaded again
+    pub fn node_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.node_cache_ttl = ttl;
+        self
+    }
+
+    /// Idle circuits kept ready for new connections, 0 disables the pool
+    pub fn pool_size(mut self, size: usize) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    /// Idle circuits older than this are closed instead of used
+    pub fn circuit_max_age(mut self, age: Duration) -> Self {
+        self.circuit_max_age = age;
+        self
+    }
+
+    /// Amount of long lived entry nodes, 0 picks a fresh entry for every circuit
+    pub fn guards(mut self, count: usize) -> Self {
+        self.guards.count = count;
+        self
+    }
+
+    pub fn guard_config(mut self, config: GuardConfig) -> Self {
+        self.guards = config;
+        self
+    }
+
+    /// Directory the guards and the consensus are kept in across restarts, they are only kept in
+    /// memory when missing
+    pub fn state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(dir.into());
+        self
+    }
+
+    fn threshold(&self) -> usize {
+        self.authority_threshold
+            .unwrap_or(self.authority_keys.len() / 2 + 1)
+    }
+
+    /// Spawns the task filling the pool, which requires a tokio runtime when a pool is configured
+    pub fn build(self) -> Client {
+        let metrics = ClientMetrics::default();
+        let pool = (self.pool_size > 0).then(|| {
+            Arc::new(CircuitPool::new(
+                self.pool_size,
+                self.circuit_max_age,
+                metrics.idle_circuits.clone(),
+            ))
+        });
+        let guards = (self.guards.count > 0).then(|| {
+            let guards =
+                GuardSet::load(self.guards, self.state_dir.as_deref()).unwrap_or_else(|err| {
+                    warn!("Starting with new guards: {:?}", err);
+                    GuardSet::load(self.guards, None).expect("Nothing to read")
+                });
+            Arc::new(Mutex::new(guards))
+        });
+        let build_times = self.adaptive_build_timeout.then(|| {
+            let build_times =
+                BuildTimeEstimator::load(self.timeouts.build, self.state_dir.as_deref())
+                    .unwrap_or_else(|err| {
+                        warn!("Starting without build times: {:?}", err);
+                        BuildTimeEstimator::load(self.timeouts.build, None)
+                            .expect("Nothing to read")
+                    });
+            Arc::new(Mutex::new(build_times))
+        });
+        let network = NetworkCache::load(
+            self.state_dir.as_deref(),
+            &self.authority_keys,
+            self.threshold(),
+            SystemTime::now(),
+        )
+        .unwrap_or_else(|err| {
+            warn!("Keeping the consensus in memory only: {:?}", err);
+            NetworkCache::load(None, &[], 0, SystemTime::now()).expect("Nothing to read")
+        });
+        let mut client = Client {
+            config: Arc::new(self),
+            network: Arc::new(Mutex::new(network)),
+            guards,
+            build_times,
+            pool: pool.clone(),
+            isolated: Default::default(),
+            metrics,
+            _pool_task: None,
+        };
+        if let Some(pool) = pool {
+            let cancellation = CancellationToken::new();
+            tokio::spawn(client.clone().fill_pool(pool, cancellation.clone()));
+            // Stops filling the pool once every clone of the client is dropped
+            client._pool_task = Some(Arc::new(cancellation.drop_guard()));
+        }
+        client
+    }
+}
+
+/// Time until the pool retries building circuits and retires expired ones
+const POOL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Client counters, exported as prometheus metrics
+#[derive(Clone)]
+pub struct ClientMetrics {
+    pub(crate) circuit_setup: HistogramVec,
+    pub(crate) idle_circuits: IntGauge,
+}
+
+impl Default for ClientMetrics {
+    fn default() -> Self {
+        Self {
+            circuit_setup: HistogramVec::new(
+                HistogramOpts::new(
+                    "rustor_client_circuit_setup_seconds",
+                    "Time until a circuit was connected to the server, by where the circuit came from",
+                )
+                .buckets(
+                    prometheus::exponential_buckets(0.001, 2.0, 15).expect("Buckets are valid"),
+                ),
+                &["source"],
+            )
+            .expect("Metric is valid"),
+            idle_circuits: IntGauge::new(
+                "rustor_client_idle_circuits",
+                "Circuits waiting in the pool",
+            )
+            .expect("Metric is valid"),
+        }
+    }
+}
+
+impl ClientMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.circuit_setup.clone()))?;
+        registry.register(Box::new(self.idle_circuits.clone()))?;
+        Ok(())
+    }
+}
+
+/// Cheap to clone, clones share the cached consensus, the circuit pool and the paths of the
+/// isolation keys
+#[derive(Clone)]
+pub struct Client {
+    config: Arc<ClientBuilder>,
+    network: Arc<Mutex<NetworkCache>>,
+    guards: Option<Arc<Mutex<GuardSet>>>,
+    build_times: Option<Arc<Mutex<BuildTimeEstimator>>>,
+    pool: Option<Arc<CircuitPool>>,
+    isolated: Arc<std::sync::Mutex<IsolatedPaths>>,
+    metrics: ClientMetrics,
+    _pool_task: Option<Arc<DropGuard>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    pub fn metrics(&self) -> &ClientMetrics {
+        &self.metrics
+    }
+
+    /// The current entry guards, empty when guards are disabled
+    pub async fn guards(&self) -> Vec<Guard> {
+        match &self.guards {
+            Some(guards) => guards.lock().await.guards().to_vec(),
+            None => vec![],
+        }
+    }
+
+    /// Resolves `host` locally and builds a circuit to it
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TorStream, TorError> {
+        let server = resolve(host, port).await?;
+        self.connect_addr(server).await
+    }
+
+    /// Like [`Client::connect`], streams with an equal `isolation` key take the same path for a
+    /// while. Streams with different keys never share a circuit or a path picked for another key
+    pub async fn connect_isolated(
+        &self,
+        host: &str,
+        port: u16,
+        isolation: &IsolationKey,
+    ) -> Result<TorStream, TorError> {
+        let server = resolve(host, port).await?;
+        self.open_stream(server, Some(isolation)).await
+    }
+
+    /// Connects an idle circuit to `server` or builds a new one, retrying without the relay that
+    /// failed
+    pub async fn connect_addr(&self, server: SocketAddr) -> Result<TorStream, TorError> {
+        self.open_stream(server, None).await
+    }
+
+    /// Reuses the path of the isolation key, or takes an idle circuit or builds a new one and
+    /// keeps its path for the key
+    async fn open_stream(
+        &self,
+        server: SocketAddr,
+        isolation: Option<&IsolationKey>,
+    ) -> Result<TorStream, TorError> {
+        let start = Instant::now();
+        if let Some(key) = isolation {
+            let path = self.isolated.lock().unwrap().get(key, start);
+            if let Some(path) = path {
+                match self.build_circuit(path, server).await {
+                    Ok(stream) => {
+                        self.observe_setup("isolated", start);
+                        return Ok(stream);
+                    }
+                    Err(err) if is_retriable(&err) => {
+                        warn!("Circuit of the isolation key failed: {}", err);
+                        self.isolated.lock().unwrap().remove(key);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        let remember = |path: &[SocketAddr]| {
+            if let Some(key) = isolation {
+                self.isolated
+                    .lock()
+                    .unwrap()
+                    .insert(key, path, Instant::now());
+            }
+        };
+
+        if let Some(circuit) = self.pool.as_ref().and_then(|pool| pool.take()) {
+            let path = circuit.path().to_vec();
+            match self.connect_circuit(circuit, server).await {
+                Ok(stream) => {
+                    self.observe_setup("pool", start);
+                    remember(&path);
+                    return Ok(stream);
+                }
+                Err(err) if is_retriable(&err) => warn!("Idle circuit failed: {}", err),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut excluded = vec![];
+        let mut attempt = 0;
+        loop {
+            let path = self.select_path(&excluded, Some(server.port())).await?;
+            match self.build_circuit(path.clone(), server).await {
+                Ok(stream) => {
+                    self.observe_setup("fresh", start);
+                    remember(&path);
+                    return Ok(stream);
+                }
+                Err(err) if attempt < self.config.retries && is_retriable(&err) => {
+                    attempt += 1;
+                    let failed = match err {
+                        TorError::PolicyRejected(_) => path.last().copied(),
+                        _ => err.failed_hop().map(|hop| path[hop - 1]),
+                    };
+                    warn!(attempt, ?failed, "Retrying circuit: {}", err);
+                    excluded.extend(failed);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn observe_setup(&self, source: &str, start: Instant) {
+        self.metrics
+            .circuit_setup
+            .with_label_values(&[source])
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    async fn build_circuit(
+        &self,
+        path: Vec<SocketAddr>,
+        server: SocketAddr,
+    ) -> Result<TorStream, TorError> {
+        let circuit = self.prepare_circuit(path).await?;
+        self.connect_circuit(circuit, server).await
+    }
+
+    async fn prepare_circuit(&self, path: Vec<SocketAddr>) -> Result<PendingCircuit, TorError> {
+        debug!(?path, "Selected path");
+        let entry = path[0];
+        let mut timeouts = self.config.timeouts;
+        if let Some(build_times) = &self.build_times {
+            timeouts.build = build_times.lock().await.timeout();
+        }
+
+        let start = Instant::now();
+        let circuit = prepare_circuit(path, timeouts).await;
+        self.record_build_time(&circuit, start.elapsed(), timeouts.build)
+            .await;
+        self.record_entry(entry, &circuit).await;
+        circuit
+    }
+
+    /// Teaches the build timeout estimator, failures other than timeouts say nothing about it
+    async fn record_build_time(
+        &self,
+        circuit: &Result<PendingCircuit, TorError>,
+        elapsed: Duration,
+        timeout: Duration,
+    ) {
+        let Some(build_times) = &self.build_times else {
+            return;
+        };
+        let save = {
+            let mut build_times = build_times.lock().await;
+            match circuit {
+                Ok(_) => build_times.record(elapsed),
+                Err(TorError::BuildTimeout { .. }) => build_times.record_timeout(timeout),
+                Err(_) => return,
+            }
+            build_times.save_if_due(Instant::now())
+        };
+        // Other builds don't wait for the disk
+        if let Some(save) = save {
+            if let Err(err) = save.await {
+                warn!("Failed saving build times: {:?}", err);
+            }
+        }
+    }
+
+    /// Updates the reachability of the entry if it's a guard
+    async fn record_entry(&self, entry: SocketAddr, circuit: &Result<PendingCircuit, TorError>) {
+        let Some(guards) = &self.guards else {
+            return;
+        };
+        let mut guards = guards.lock().await;
+        let changed = match circuit {
+            Ok(_) => guards.mark_up(entry, unix_now()),
+            Err(
+                TorError::Io(_)
+                | TorError::HopUnreachable { hop: 1 }
+                | TorError::HandshakeTimeout { hop: 1 }
+                | TorError::BuildTimeout { hop: 1 }
+                | TorError::HopDecryptFailed { hop: 1 },
+            ) => {
+                warn!(%entry, "Entry failed");
+                guards.mark_down(entry, unix_now())
+            }
+            Err(_) => false,
+        };
+        if changed {
+            if let Err(err) = guards.save().await {
+                warn!("Failed saving guards: {:?}", err);
+            }
+        }
+    }
+
+    async fn connect_circuit(
+        &self,
+        circuit: PendingCircuit,
+        server: SocketAddr,
+    ) -> Result<TorStream, TorError> {
+        let (reader, writer) = circuit.connect(server, self.config.timeouts).await?;
+        Ok(TorStream::new(reader, writer))
+    }
+
+    /// Keeps the pool full until cancelled
+    async fn fill_pool(self, pool: Arc<CircuitPool>, cancellation: CancellationToken) {
+        info!(size = self.config.pool_size, "Filling circuit pool");
+        loop {
+            for _ in 0..pool.retire_expired() {
+                let circuit = tokio::select! {
+                    _ = cancellation.cancelled() => return,
+                    circuit = async {
+                        let path = self.select_path(&[], None).await?;
+                        self.prepare_circuit(path).await
+                    } => circuit,
+                };
+                match circuit {
+                    Ok(circuit) => pool.push(circuit),
+                    Err(err) => {
+                        warn!("Failed building idle circuit: {}", err);
+                        break;
+                    }
+                }
+            }
+            tokio::select! {
+                _ = cancellation.cancelled() => return,
+                _ = pool.refill.notified() => {}
+                _ = tokio::time::sleep(POOL_CHECK_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Picks a path avoiding `excluded` on top of the configured excluded nodes, through an exit
+    /// accepting `port` when it's known
+    async fn select_path(
+        &self,
+        excluded: &[SocketAddr],
+        port: Option<u16>,
+    ) -> Result<Vec<SocketAddr>, TorError> {
+        let relays = self.relays().await?;
+        let mut selector = self.config.path.excluding(excluded);
+        if let Some(port) = port {
+            selector = selector.exit_port(port);
+        }
+        let path = match &self.guards {
+            Some(guards) => {
+                self.select_guarded_path(&selector, &mut *guards.lock().await, &relays)
+                    .await
+            }
+            None => selector.select(&relays),
+        };
+        if path.is_err() {
+            // The consensus might be stale, the next circuit downloads a new one
+            self.network.lock().await.mark_stale();
+        }
+        path
+    }
+
+    /// Picks one of the guards as the entry, choosing new guards when needed
+    async fn select_guarded_path(
+        &self,
+        selector: &PathSelector,
+        guards: &mut GuardSet,
+        relays: &[Relay],
+    ) -> Result<Vec<SocketAddr>, TorError> {
+        let now = unix_now();
+        let candidates = relays
+            .iter()
+            .filter(|relay| self.config.path.allows(relay, Position::Entry))
+            .collect::<Vec<_>>();
+        if guards.rotate(&candidates, now) {
+            info!(guards = ?guards.guards().iter().map(|guard| guard.addr).collect::<Vec<_>>(), "Guards changed");
+            if let Err(err) = guards.save().await {
+                warn!("Failed saving guards: {:?}", err);
+            }
+        }
+
+        let entries = guards
+            .usable(relays, now)
+            .into_iter()
+            .filter(|entry| {
+                relays
+                    .iter()
+                    .any(|relay| relay.addr == *entry && selector.allows(relay, Position::Entry))
+            })
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            return Err(TorError::NoEligibleRelay { hop: 1 });
+        }
+        selector.clone().entry_nodes(entries).select(relays)
+    }
+
+    /// Nodes of the cached consensus, downloaded again once it's older than the node cache TTL.
+    /// Paths are selected from it locally, so the directory never learns which nodes a circuit
+    /// uses. The cached consensus is used until it expires when no directory can be reached,
+    /// downloads are retried with backoff meanwhile.
+    async fn relays(&self) -> Result<Vec<Relay>, TorError> {
+        let mut network = self.network.lock().await;
+        let now = SystemTime::now();
+        if let Some(relays) = network.fresh(self.config.node_cache_ttl, now) {
+            return Ok(relays);
+        }
+        let cached = network.current(now);
+        if let Some(relays) = cached.as_ref().filter(|_| !network.retry_due(now)) {
+            return Ok(relays.clone());
+        }
+        network.attempt(now);
+        // Circuits keep using the cached consensus while it's downloaded again, without one
+        // they wait for the download
+        let (mut network, fetched) = if cached.is_some() {
+            drop(network);
+            let fetched = self.fetch_network(now).await;
+            (self.network.lock().await, fetched)
+        } else {
+            let fetched = self.fetch_network(now).await;
+            (network, fetched)
+        };
+
+        match fetched {
+            Ok((document, verified)) => {
+                debug!(
+                    count = verified.consensus().nodes.len(),
+                    "Downloaded the consensus"
+                );
+                if let Err(err) = network.update(document, &verified, now).await {
+                    warn!("Failed saving the consensus: {:?}", err);
+                }
+            }
+            Err(err) => {
+                network.failed(now);
+                match network.current(now) {
+                    Some(_) => warn!("Using the cached consensus: {}", err),
+                    None => return Err(err),
+                }
+            }
+        }
+        Ok(network.current(now).unwrap_or_default())
+    }
+
+    /// Asks the directories in turn until one serves a consensus enough authorities signed
+    async fn fetch_network(&self, now: SystemTime) -> Result<(NetworkDocument, Network), TorError> {
+        let config = &self.config;
+        let default = [DEFAULT_DIRECTORY.to_string()];
+        let directories = match config.directories.as_slice() {
+            [] => &default[..],
+            directories => directories,
+        };
+        let mut last_err = None;
+        for directory in directories {
+            let network = tokio::time::timeout(config.directory_timeout, get_network(directory))
+                .await
+                .map_err(|err| TorError::DirectoryUnavailable(err.into()))
+                .and_then(|document| {
+                    let document = document?;
+                    let network =
+                        document.verify(&config.authority_keys, config.threshold(), now)?;
+                    Ok((document, network))
+                });
+            match network {
+                Ok(network) => return Ok(network),
+                Err(err) => {
+                    warn!("Failed fetching the consensus from {}: {}", directory, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("There is at least one directory"))
+    }
+}
+
+/// Resolves `host` locally
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr, TorError> {
+    let resolve_failed = |source| TorError::ResolveFailed {
+        host: host.to_string(),
+        source,
+    };
+    tokio::net::lookup_host((host, port))
+        .await
+        .map_err(resolve_failed)?
+        .next()
+        .ok_or_else(|| resolve_failed(io::ErrorKind::NotFound.into()))
+}
+
+/// Failures caused by the chosen nodes, a different path might succeed
+fn is_retriable(err: &TorError) -> bool {
+    match err {
+        TorError::HandshakeTimeout { .. }
+        | TorError::HopUnreachable { .. }
+        | TorError::BuildTimeout { .. }
+        | TorError::HopDecryptFailed { .. }
+        | TorError::CircuitDestroyed(_)
+        | TorError::UnexpectedMessage(_)
+        | TorError::Serialization(_)
+        | TorError::Io(_)
+        // Exits may have different policies
+        | TorError::PolicyRejected(_) => true,
+        TorError::DirectoryUnavailable(_)
+        | TorError::ConsensusRejected(_)
+        | TorError::ResolveFailed { .. }
+        | TorError::NotEnoughNodes { .. }
+        | TorError::NoEligibleRelay { .. }
+        | TorError::ExitConnectRefused(_)
+        | TorError::HostUnreachable(_) => false,
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state;
+
+const STATE_FILE: &str = "build_times.json";
+/// Recent builds the fit is based on
+const MAX_SAMPLES: usize = 1000;
+/// Builds needed before the learned timeout replaces the initial one
+const MIN_SAMPLES: usize = 20;
+/// Share of builds expected to finish within the timeout
+const QUANTILE: f64 = 0.8;
+/// Width of the histogram bins the mode is taken from
+const BIN_MS: u32 = 50;
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(120);
+/// Time between saves, builds since the last save are lost when the client crashes
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Sample {
+    ms: u32,
+    /// The build was abandoned after `ms`
+    timed_out: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct BuildTimeState {
+    samples: VecDeque<Sample>,
+}
+
+/// Learns the circuit build deadline from observed build times by fitting a Pareto
+/// distribution like Tor, so slow networks aren't cut off and black holes are abandoned early
+pub(crate) struct BuildTimeEstimator {
+    initial: Duration,
+    samples: VecDeque<Sample>,
+    state_file: Option<PathBuf>,
+    /// Whether builds were recorded since the last save
+    unsaved: bool,
+    last_save: Option<Instant>,
+}
+
+impl BuildTimeEstimator {
+    /// Reads the build times saved in `state_dir`, `initial` is used until enough are known
+    pub(crate) fn load(initial: Duration, state_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let state_file = state_dir
+            .map(|state_dir| state::file(state_dir, STATE_FILE))
+            .transpose()?;
+        let state: BuildTimeState = match &state_file {
+            Some(state_file) => state::read(state_file)?,
+            None => BuildTimeState::default(),
+        };
+        Ok(Self {
+            initial,
+            samples: state.samples,
+            state_file,
+            unsaved: false,
+            last_save: None,
+        })
+    }
+
+    /// Saves the build times when builds were recorded and the last save is [`SAVE_INTERVAL`]
+    /// ago. The save works on a copy, so it can run after the estimator is unlocked
+    pub(crate) fn save_if_due(
+        &mut self,
+        now: Instant,
+    ) -> Option<impl Future<Output = anyhow::Result<()>> + 'static> {
+        let due = self.unsaved
+            && self
+                .last_save
+                .is_none_or(|last_save| last_save + SAVE_INTERVAL <= now);
+        if !due {
+            return None;
+        }
+        self.unsaved = false;
+        self.last_save = Some(now);
+        let state_file = self.state_file.clone();
+        let state = BuildTimeState {
+            samples: self.samples.clone(),
+        };
+        Some(async move {
+            match state_file {
+                Some(state_file) => state::write(&state_file, &state).await,
+                None => Ok(()),
+            }
+        })
+    }
+
+    pub(crate) fn record(&mut self, elapsed: Duration) {
+        self.push(elapsed, false);
+    }
+
+    /// A build abandoned at `timeout`, it would have taken at least that long
+    pub(crate) fn record_timeout(&mut self, timeout: Duration) {
+        self.push(timeout, true);
+    }
+
+    fn push(&mut self, duration: Duration, timed_out: bool) {
+        self.unsaved = true;
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            ms: duration.as_millis().try_into().unwrap_or(u32::MAX),
+            timed_out,
+        });
+    }
+
+    /// The time [`QUANTILE`] of the builds finish within according to the fit
+    pub(crate) fn timeout(&self) -> Duration {
+        let completed = self
+            .samples
+            .iter()
+            .filter(|sample| !sample.timed_out)
+            .map(|sample| sample.ms)
+            .collect::<Vec<_>>();
+        if completed.len() < MIN_SAMPLES {
+            return self.initial;
+        }
+
+        // The scale is the most common build time, ties go to the faster bin
+        let mut bins = BTreeMap::<u32, usize>::new();
+        for ms in &completed {
+            *bins.entry(ms / BIN_MS).or_default() += 1;
+        }
+        let mode = bins
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| **count)
+            .map(|(bin, _)| *bin)
+            .expect("Has samples");
+        let scale = f64::from(mode * BIN_MS + BIN_MS / 2);
+
+        // Maximum likelihood shape, timed out builds count with the time they were abandoned at
+        let log_sum = self
+            .samples
+            .iter()
+            .map(|sample| (f64::from(sample.ms).max(scale) / scale).ln())
+            .sum::<f64>();
+        let timeout_ms = if log_sum > 0.0 {
+            let shape = completed.len() as f64 / log_sum;
+            scale / (1.0 - QUANTILE).powf(1.0 / shape)
+        } else {
+            scale
+        };
+        Duration::from_secs_f64(timeout_ms / 1000.0).clamp(MIN_TIMEOUT, MAX_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build times spread evenly over the quantiles of a Pareto distribution
+    fn pareto(scale_ms: f64, shape: f64, amount: usize) -> impl Iterator<Item = Duration> {
+        (0..amount).map(move |i| {
+            let quantile = (i as f64 + 0.5) / amount as f64;
+            Duration::from_secs_f64(scale_ms / (1.0 - quantile).powf(1.0 / shape) / 1000.0)
+        })
+    }
+
+    fn estimator() -> BuildTimeEstimator {
+        BuildTimeEstimator::load(Duration::from_secs(60), None).unwrap()
+    }
+
+    #[test]
+    fn initial_timeout_until_enough_builds() {
+        let mut estimator = estimator();
+        for elapsed in pareto(2000.0, 3.0, MIN_SAMPLES - 1) {
+            estimator.record(elapsed);
+        }
+        assert_eq!(estimator.timeout(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn fits_pareto_build_times() {
+        let mut estimator = estimator();
+        for elapsed in pareto(2000.0, 3.0, 500) {
+            estimator.record(elapsed);
+        }
+        // 2000 / 0.2^(1/3)
+        let expected = 3420.0;
+        let timeout = estimator.timeout().as_secs_f64() * 1000.0;
+        assert!(
+            (timeout - expected).abs() < expected * 0.1,
+            "{timeout} isn't close to {expected}"
+        );
+    }
+
+    #[test]
+    fn timeouts_raise_the_estimate() {
+        let mut estimator = estimator();
+        for elapsed in pareto(2000.0, 3.0, 100) {
+            estimator.record(elapsed);
+        }
+        let before = estimator.timeout();
+        for _ in 0..30 {
+            estimator.record_timeout(before);
+        }
+        assert!(estimator.timeout() > before);
+    }
+
+    #[test]
+    fn keeps_recent_builds() {
+        let mut estimator = estimator();
+        for _ in 0..MAX_SAMPLES {
+            estimator.record(Duration::from_secs(30));
+        }
+        for elapsed in pareto(200.0, 3.0, MAX_SAMPLES) {
+            estimator.record(elapsed);
+        }
+        assert!(estimator.timeout() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn build_times_survive_restarts() -> anyhow::Result<()> {
+        let state_dir =
+            std::env::temp_dir().join(format!("rustor-build-times-{}", rand::random::<u64>()));
+        let mut estimator = BuildTimeEstimator::load(Duration::from_secs(60), Some(&state_dir))?;
+        for elapsed in pareto(2000.0, 3.0, 100) {
+            estimator.record(elapsed);
+        }
+        let now = Instant::now();
+        estimator
+            .save_if_due(now)
+            .expect("Builds were recorded")
+            .await?;
+
+        let reloaded = BuildTimeEstimator::load(Duration::from_secs(60), Some(&state_dir))?;
+        assert_eq!(reloaded.samples, estimator.samples);
+        assert_eq!(reloaded.timeout(), estimator.timeout());
+
+        // Later builds wait for the next save
+        estimator.record(Duration::from_secs(1));
+        assert!(estimator.save_if_due(now + SAVE_INTERVAL / 2).is_none());
+        assert!(estimator.save_if_due(now + SAVE_INTERVAL).is_some());
+        assert!(estimator.save_if_due(now + SAVE_INTERVAL * 2).is_none());
+
+        std::fs::remove_dir_all(state_dir)?;
+        Ok(())
+    }
+}
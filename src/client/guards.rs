@@ -0,0 +1,398 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand::{rngs::OsRng, seq::SliceRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::{state, tor::path::Relay};
+
+const STATE_FILE: &str = "guards.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuardConfig {
+    /// Amount of guards kept, 0 picks a fresh entry for every circuit
+    pub count: usize,
+    /// Guards kept at most, including the ones standing in for unlisted or failing guards
+    pub max_sampled: usize,
+    /// Time until a guard is replaced
+    pub lifetime: Duration,
+    /// Time a guard that left the consensus or keeps failing is kept before it's replaced, new
+    /// guards stand in for it meanwhile
+    pub replace_after: Duration,
+    /// Time a guard is skipped after its first failure, doubled for every further failure
+    pub retry_base: Duration,
+    pub retry_max: Duration,
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        Self {
+            count: 3,
+            max_sampled: 20,
+            lifetime: Duration::from_secs(30 * 24 * 60 * 60),
+            replace_after: Duration::from_secs(24 * 60 * 60),
+            retry_base: Duration::from_secs(60),
+            retry_max: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// A long lived entry node, times are unix seconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Guard {
+    pub addr: SocketAddr,
+    pub chosen_at: u64,
+    pub last_success: Option<u64>,
+    pub last_failure: Option<u64>,
+    /// Failures since the last success
+    pub failures: u32,
+    /// The guard is considered down until then
+    pub retry_at: Option<u64>,
+    /// First failure since the last success
+    #[serde(default)]
+    pub down_since: Option<u64>,
+    /// Since when the guard is missing from the consensus
+    #[serde(default)]
+    pub unlisted_since: Option<u64>,
+}
+
+impl Guard {
+    fn is_down(&self, now: u64) -> bool {
+        self.retry_at.is_some_and(|retry_at| now < retry_at)
+    }
+
+    fn is_usable(&self, now: u64) -> bool {
+        self.unlisted_since.is_none() && !self.is_down(now)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct GuardState {
+    guards: Vec<Guard>,
+}
+
+/// The client's guards, saved in the state directory when there is one
+pub(crate) struct GuardSet {
+    config: GuardConfig,
+    guards: Vec<Guard>,
+    state_file: Option<PathBuf>,
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is after the epoch")
+        .as_secs()
+}
+
+impl GuardSet {
+    /// Reads the guards saved in `state_dir`, creating the directory if needed
+    pub(crate) fn load(config: GuardConfig, state_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let state_file = state_dir
+            .map(|state_dir| state::file(state_dir, STATE_FILE))
+            .transpose()?;
+        let state: GuardState = match &state_file {
+            Some(state_file) => state::read(state_file)?,
+            None => GuardState::default(),
+        };
+        Ok(Self {
+            config,
+            guards: state.guards,
+            state_file,
+        })
+    }
+
+    pub(crate) async fn save(&self) -> anyhow::Result<()> {
+        let Some(state_file) = &self.state_file else {
+            return Ok(());
+        };
+        state::write(
+            state_file,
+            &GuardState {
+                guards: self.guards.clone(),
+            },
+        )
+        .await
+    }
+
+    pub(crate) fn guards(&self) -> &[Guard] {
+        &self.guards
+    }
+
+    /// Drops guards that expired, or left `candidates` or kept failing for too long, and adds
+    /// guards from `candidates` until enough are usable or `max_sampled` are kept. Returns
+    /// whether the set changed
+    pub(crate) fn rotate(&mut self, candidates: &[&Relay], now: u64) -> bool {
+        let before = self.guards.clone();
+        for guard in &mut self.guards {
+            let listed = candidates.iter().any(|relay| relay.addr == guard.addr);
+            if listed {
+                guard.unlisted_since = None;
+            } else {
+                guard.unlisted_since.get_or_insert(now);
+            }
+        }
+        let lifetime = self.config.lifetime.as_secs();
+        let replace_after = self.config.replace_after.as_secs();
+        let gone = |since: Option<u64>| {
+            since.is_some_and(|since| now >= since.saturating_add(replace_after))
+        };
+        self.guards.retain(|guard| {
+            now < guard.chosen_at.saturating_add(lifetime)
+                && !gone(guard.unlisted_since)
+                && !gone(guard.down_since)
+        });
+
+        let mut fresh = candidates
+            .iter()
+            .filter(|relay| !self.guards.iter().any(|guard| guard.addr == relay.addr))
+            .collect::<Vec<_>>();
+        fresh.shuffle(&mut OsRng);
+        // With every guard down the client is more likely offline than its guards, they are
+        // retried instead of standing in for them
+        let offline = self
+            .guards
+            .iter()
+            .filter(|guard| guard.unlisted_since.is_none())
+            .all(|guard| guard.is_down(now));
+        for relay in fresh {
+            let kept = self.guards.iter().filter(|guard| {
+                if offline {
+                    guard.unlisted_since.is_none()
+                } else {
+                    guard.is_usable(now)
+                }
+            });
+            if kept.count() >= self.config.count || self.guards.len() >= self.config.max_sampled {
+                break;
+            }
+            self.guards.push(Guard {
+                addr: relay.addr,
+                chosen_at: now,
+                last_success: None,
+                last_failure: None,
+                failures: 0,
+                retry_at: None,
+                down_since: None,
+                unlisted_since: None,
+            });
+        }
+        self.guards != before
+    }
+
+    /// Up to `count` guards in `relays` that aren't down, oldest first, or the listed guards
+    /// once all of them are down
+    pub(crate) fn usable(&self, relays: &[Relay], now: u64) -> Vec<SocketAddr> {
+        let listed = self
+            .guards
+            .iter()
+            .filter(|guard| relays.iter().any(|relay| relay.addr == guard.addr))
+            .collect::<Vec<_>>();
+        let up = listed
+            .iter()
+            .filter(|guard| !guard.is_down(now))
+            .map(|guard| guard.addr)
+            .take(self.config.count)
+            .collect::<Vec<_>>();
+        if up.is_empty() {
+            listed
+                .iter()
+                .map(|guard| guard.addr)
+                .take(self.config.count)
+                .collect()
+        } else {
+            up
+        }
+    }
+
+    /// Returns whether `addr` is a guard
+    pub(crate) fn mark_up(&mut self, addr: SocketAddr, now: u64) -> bool {
+        let Some(guard) = self.guards.iter_mut().find(|guard| guard.addr == addr) else {
+            return false;
+        };
+        guard.last_success = Some(now);
+        guard.failures = 0;
+        guard.retry_at = None;
+        guard.down_since = None;
+        true
+    }
+
+    /// Skips the guard with exponential backoff, returns whether `addr` is a guard
+    pub(crate) fn mark_down(&mut self, addr: SocketAddr, now: u64) -> bool {
+        let Some(guard) = self.guards.iter_mut().find(|guard| guard.addr == addr) else {
+            return false;
+        };
+        let backoff = self
+            .config
+            .retry_base
+            .saturating_mul(2u32.saturating_pow(guard.failures))
+            .min(self.config.retry_max);
+        guard.last_failure = Some(now);
+        guard.down_since.get_or_insert(now);
+        guard.failures += 1;
+        guard.retry_at = Some(now + backoff.as_secs());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relays(amount: u8) -> Vec<Relay> {
+        (0..amount)
+            .map(|i| Relay::from(SocketAddr::from(([10, i, 0, 1], 9001))))
+            .collect()
+    }
+
+    fn config(count: usize) -> GuardConfig {
+        GuardConfig {
+            count,
+            max_sampled: 6,
+            lifetime: Duration::from_secs(1000),
+            replace_after: Duration::from_secs(100),
+            retry_base: Duration::from_secs(10),
+            retry_max: Duration::from_secs(35),
+        }
+    }
+
+    #[test]
+    fn guards_are_kept_until_they_expire() {
+        let relays = relays(10);
+        let candidates = relays.iter().collect::<Vec<_>>();
+        let mut guards = GuardSet::load(config(3), None).unwrap();
+
+        assert!(guards.rotate(&candidates, 0));
+        let chosen = guards.usable(&relays, 0);
+        assert_eq!(chosen.len(), 3);
+        assert!(!guards.rotate(&candidates, 999));
+        assert_eq!(guards.usable(&relays, 999), chosen);
+
+        assert!(guards.rotate(&candidates, 1000));
+        assert_eq!(guards.guards().len(), 3);
+        assert!(guards.guards().iter().all(|guard| guard.chosen_at == 1000));
+    }
+
+    #[test]
+    fn failed_guards_back_off() {
+        let relays = relays(2);
+        let candidates = relays.iter().collect::<Vec<_>>();
+        let mut guards = GuardSet::load(config(2), None).unwrap();
+        guards.rotate(&candidates, 0);
+        let (first, second) = (guards.guards()[0].addr, guards.guards()[1].addr);
+
+        assert!(guards.mark_down(first, 100));
+        assert_eq!(guards.usable(&relays, 109), vec![second]);
+        assert_eq!(guards.usable(&relays, 110).len(), 2);
+
+        // 10, 20 and then capped at 35 seconds
+        guards.mark_down(first, 110);
+        assert_eq!(guards.guards()[0].retry_at, Some(130));
+        guards.mark_down(first, 130);
+        assert_eq!(guards.guards()[0].retry_at, Some(165));
+
+        // Every guard is tried again once all of them are down
+        guards.mark_down(second, 130);
+        assert_eq!(guards.usable(&relays, 131).len(), 2);
+
+        assert!(guards.mark_up(first, 139));
+        assert_eq!(guards.usable(&relays, 139), vec![first]);
+        assert_eq!(guards.guards()[0].failures, 0);
+        assert!(!guards.mark_up("10.9.0.1:9001".parse().unwrap(), 139));
+    }
+
+    #[test]
+    fn unlisted_guards_are_skipped() {
+        let relays = relays(3);
+        let candidates = relays.iter().collect::<Vec<_>>();
+        let mut guards = GuardSet::load(config(3), None).unwrap();
+        guards.rotate(&candidates, 0);
+
+        let usable = guards.usable(&relays[1..], 0);
+        assert_eq!(usable.len(), 2);
+        assert!(!usable.contains(&relays[0].addr));
+    }
+
+    #[test]
+    fn lost_guards_are_replaced() {
+        let relays = relays(6);
+        let candidates = relays.iter().collect::<Vec<_>>();
+        let mut guards = GuardSet::load(config(2), None).unwrap();
+        guards.rotate(&candidates, 0);
+        let chosen = guards.usable(&relays, 0);
+
+        // Both guards leave the consensus, new ones stand in right away
+        let remaining = relays
+            .iter()
+            .filter(|relay| !chosen.contains(&relay.addr))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(guards.rotate(&remaining.iter().collect::<Vec<_>>(), 10));
+        let standing_in = guards.usable(&remaining, 10);
+        assert_eq!(standing_in.len(), 2);
+        assert!(standing_in.iter().all(|addr| !chosen.contains(addr)));
+
+        // The old guards are preferred again when they come back before they're replaced
+        guards.rotate(&candidates, 50);
+        assert_eq!(guards.usable(&relays, 50), chosen);
+
+        // A guard failing for too long is dropped
+        let failing = chosen[0];
+        guards.mark_down(failing, 50);
+        guards.rotate(&candidates, 60);
+        assert_eq!(guards.usable(&relays, 60).len(), 2);
+        guards.mark_down(failing, 100);
+        guards.rotate(&candidates, 150);
+        assert!(guards.guards().iter().all(|guard| guard.addr != failing));
+        assert_eq!(guards.usable(&relays, 150).len(), 2);
+    }
+
+    #[test]
+    fn outages_dont_add_guards() {
+        let relays = relays(30);
+        let candidates = relays.iter().collect::<Vec<_>>();
+        let mut guards = GuardSet::load(config(3), None).unwrap();
+        guards.rotate(&candidates, 0);
+        let chosen = guards.usable(&relays, 0);
+
+        // Every entry fails while the client is offline
+        for now in 1..50 {
+            for guard in chosen.clone() {
+                guards.mark_down(guard, now);
+            }
+            assert!(!guards.rotate(&candidates, now));
+        }
+        assert_eq!(guards.guards().len(), 3);
+        assert_eq!(guards.usable(&relays, 50), chosen);
+
+        // Guards failing one after another stop being replaced once enough are kept
+        for now in 50..90 {
+            let usable = guards.usable(&relays, now);
+            guards.mark_down(usable[0], now);
+            guards.rotate(&candidates, now);
+            assert!(guards.guards().len() <= 6);
+        }
+    }
+
+    #[tokio::test]
+    async fn guards_survive_restarts() -> anyhow::Result<()> {
+        let state_dir =
+            std::env::temp_dir().join(format!("rustor-guards-{}", rand::random::<u64>()));
+        let relays = relays(5);
+        let candidates = relays.iter().collect::<Vec<_>>();
+
+        let mut guards = GuardSet::load(config(3), Some(&state_dir))?;
+        guards.rotate(&candidates, 0);
+        guards.mark_down(guards.guards()[0].addr, 5);
+        guards.save().await?;
+
+        let reloaded = GuardSet::load(config(3), Some(&state_dir))?;
+        assert_eq!(reloaded.guards(), guards.guards());
+
+        std::fs::remove_dir_all(state_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::proxy::IsolationKey;
+
+/// Time the streams of one isolation key keep using the path of the key's first circuit
+pub(crate) const ISOLATED_PATH_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Paths of the circuits built for each isolation key. Streams of a key reuse its path, a path
+/// is never handed to another key
+#[derive(Default)]
+pub(crate) struct IsolatedPaths {
+    /// Path and the time it was first used, by key
+    paths: HashMap<IsolationKey, (Vec<SocketAddr>, Instant)>,
+}
+
+impl IsolatedPaths {
+    /// The path of the key's circuits unless it's used for too long already
+    pub(crate) fn get(&mut self, key: &IsolationKey, now: Instant) -> Option<Vec<SocketAddr>> {
+        self.paths.retain(|_, (_, first_used)| {
+            now.saturating_duration_since(*first_used) < ISOLATED_PATH_LIFETIME
+        });
+        self.paths.get(key).map(|(path, _)| path.clone())
+    }
+
+    /// Has the key's further streams use `path`, unless it already has one
+    pub(crate) fn insert(&mut self, key: &IsolationKey, path: &[SocketAddr], now: Instant) {
+        if !self.paths.contains_key(key) {
+            self.paths.insert(key.clone(), (path.to_vec(), now));
+        }
+    }
+
+    /// Forgets a path that failed, the key's next stream picks a new one
+    pub(crate) fn remove(&mut self, key: &IsolationKey) {
+        self.paths.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ListenerConfig;
+
+    #[test]
+    fn keys_keep_their_path_until_it_expires() {
+        let listener: ListenerConfig = "127.0.0.1:1080=dest-host".parse().unwrap();
+        let client = "10.0.0.1:5000".parse().unwrap();
+        let key = |host| IsolationKey::new(&listener, client, None, host, 80);
+        let (alice, bob) = (key("alice.example"), key("bob.example"));
+        let mut paths = IsolatedPaths::default();
+        let now = Instant::now();
+
+        let path: Vec<SocketAddr> = vec!["127.0.0.1:9001".parse().unwrap()];
+        paths.insert(&alice, &path, now);
+        assert_eq!(paths.get(&alice, now), Some(path.clone()));
+        assert_eq!(paths.get(&bob, now), None);
+
+        // Later circuits of the key don't extend its path's lifetime
+        let other: Vec<SocketAddr> = vec!["127.0.0.1:9002".parse().unwrap()];
+        paths.insert(&alice, &other, now + Duration::from_secs(1));
+        assert_eq!(paths.get(&alice, now), Some(path));
+        assert_eq!(paths.get(&alice, now + ISOLATED_PATH_LIFETIME), None);
+
+        paths.insert(&bob, &other, now);
+        paths.remove(&bob);
+        assert_eq!(paths.get(&bob, now), None);
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    state,
+    tor::{
+        consensus::{unix_secs, Flag},
+        descriptor::PROTOCOL_VERSION,
+        node_directory::{Network, NetworkDocument},
+        path::Relay,
+    },
+};
+
+const STATE_FILE: &str = "consensus.json";
+/// Time until a failed download is retried, doubled for every further failure
+const RETRY_BASE: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Default, Serialize, Deserialize)]
+struct NetworkState {
+    document: Option<NetworkDocument>,
+    /// Unix seconds the document was downloaded
+    fetched: u64,
+}
+
+struct CachedNetwork {
+    document: NetworkDocument,
+    relays: Vec<Relay>,
+    valid_until: u64,
+    fetched: u64,
+}
+
+/// The downloaded consensus paths are selected from, saved in the state directory when there
+/// is one
+pub(crate) struct NetworkCache {
+    network: Option<CachedNetwork>,
+    state_file: Option<PathBuf>,
+    /// Downloads failed since the last success
+    failures: u32,
+    /// Unix seconds until which the cached consensus is used without downloading it again
+    retry_at: u64,
+}
+
+impl NetworkCache {
+    /// Reads the consensus saved in `state_dir`, it's dropped unless enough of `authorities`
+    /// signed it and it's still valid. Fails only when the state directory can't be created
+    pub(crate) fn load(
+        state_dir: Option<&Path>,
+        authorities: &[VerifyingKey],
+        threshold: usize,
+        now: SystemTime,
+    ) -> anyhow::Result<Self> {
+        let state_file = state_dir
+            .map(|state_dir| state::file(state_dir, STATE_FILE))
+            .transpose()?;
+        let state: NetworkState = match &state_file {
+            Some(state_file) => state::read(state_file).unwrap_or_else(|err| {
+                warn!("Dropping the saved consensus: {:?}", err);
+                NetworkState::default()
+            }),
+            None => NetworkState::default(),
+        };
+        let mut cache = Self {
+            network: None,
+            state_file,
+            failures: 0,
+            retry_at: 0,
+        };
+        if let Some(document) = state.document {
+            match document.verify(authorities, threshold, now) {
+                Ok(network) => cache.set(document, &network, state.fetched),
+                Err(err) => info!("Dropping the saved consensus: {}", err),
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Nodes of the consensus if it was downloaded less than `ttl` ago
+    pub(crate) fn fresh(&self, ttl: Duration, now: SystemTime) -> Option<Vec<Relay>> {
+        self.network
+            .as_ref()
+            .filter(|network| network.fetched.saturating_add(ttl.as_secs()) > unix_secs(now))
+            .and_then(|_| self.current(now))
+    }
+
+    /// Nodes of the consensus until it expires
+    pub(crate) fn current(&self, now: SystemTime) -> Option<Vec<Relay>> {
+        self.network
+            .as_ref()
+            .filter(|network| unix_secs(now) < network.valid_until)
+            .map(|network| network.relays.clone())
+    }
+
+    /// Whether a stale consensus should be downloaded again, false while backing off after
+    /// failed downloads
+    pub(crate) fn retry_due(&self, now: SystemTime) -> bool {
+        unix_secs(now) >= self.retry_at
+    }
+
+    /// Notes a download starting, the cached consensus is used meanwhile
+    pub(crate) fn attempt(&mut self, now: SystemTime) {
+        self.retry_at = unix_secs(now).saturating_add(self.backoff().as_secs());
+    }
+
+    /// Backs off from downloading again
+    pub(crate) fn failed(&mut self, now: SystemTime) {
+        self.failures = self.failures.saturating_add(1);
+        self.attempt(now);
+    }
+
+    fn backoff(&self) -> Duration {
+        RETRY_BASE
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(RETRY_MAX)
+    }
+
+    /// Downloads the consensus again before the next path, it's still used if that fails
+    pub(crate) fn mark_stale(&mut self) {
+        if let Some(network) = &mut self.network {
+            network.fetched = 0;
+        }
+    }
+
+    /// Replaces the consensus with a newly downloaded one and saves it
+    pub(crate) async fn update(
+        &mut self,
+        document: NetworkDocument,
+        network: &Network,
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        self.set(document, network, unix_secs(now));
+        self.failures = 0;
+        self.retry_at = 0;
+        self.save().await
+    }
+
+    fn set(&mut self, document: NetworkDocument, network: &Network, fetched: u64) {
+        let relays = network
+            .consensus()
+            .nodes
+            .iter()
+            .zip(network.nodes())
+            .filter(|(_, descriptor)| descriptor.protocols.contains(&PROTOCOL_VERSION))
+            .map(|(node, descriptor)| {
+                let mut relay = Relay::from(descriptor);
+                relay.bandwidth = node.bandwidth;
+                if node.flags.contains(&Flag::BadExit) {
+                    relay.exit_ports.clear();
+                }
+                relay
+            })
+            .collect();
+        self.network = Some(CachedNetwork {
+            document,
+            relays,
+            valid_until: network.consensus().valid_until,
+            fetched,
+        });
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        let Some(state_file) = &self.state_file else {
+            return Ok(());
+        };
+        let network = self.network.as_ref();
+        state::write(
+            state_file,
+            &NetworkState {
+                document: network.map(|network| network.document.clone()),
+                fetched: network.map_or(0, |network| network.fetched),
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    use super::*;
+    use crate::tor::{
+        consensus::{Consensus, ConsensusNode, CONSENSUS_LIFETIME},
+        descriptor::NodeInfo,
+        exit_policy::ExitPolicy,
+    };
+
+    fn document(key: &SigningKey, now: SystemTime) -> NetworkDocument {
+        let signed = NodeInfo::default().descriptor(
+            "127.0.0.1:9001".parse().unwrap(),
+            &ExitPolicy::default(),
+            None,
+            now,
+        );
+        let node = ConsensusNode::new(
+            &signed,
+            &signed.verify(now).unwrap(),
+            BTreeSet::from([Flag::Valid]),
+            None,
+        );
+        NetworkDocument {
+            consensus: Consensus::new(vec![node], now).sign(key),
+            descriptors: vec![signed],
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_the_consensus_until_it_expires() {
+        let state_dir =
+            std::env::temp_dir().join(format!("rustor-network-{}", rand::random::<u64>()));
+        let key = SigningKey::generate(&mut OsRng);
+        let authorities = [key.verifying_key()];
+        let now = SystemTime::now();
+        let ttl = Duration::from_secs(60);
+
+        let mut cache = NetworkCache::load(Some(&state_dir), &authorities, 1, now).unwrap();
+        assert!(cache.current(now).is_none());
+        let document = document(&key, now);
+        let network = document.verify(&authorities, 1, now).unwrap();
+        cache.update(document, &network, now).await.unwrap();
+        assert_eq!(cache.fresh(ttl, now).unwrap().len(), 1);
+
+        // A restarted client reuses the saved consensus
+        let later = now + ttl * 2;
+        let cache = NetworkCache::load(Some(&state_dir), &authorities, 1, later).unwrap();
+        assert!(cache.fresh(ttl, later).is_none());
+        assert_eq!(cache.current(later).unwrap().len(), 1);
+
+        // An expired consensus isn't used
+        let expired = now + CONSENSUS_LIFETIME;
+        assert!(cache.current(expired).is_none());
+        let cache = NetworkCache::load(Some(&state_dir), &authorities, 1, expired).unwrap();
+        assert!(cache.current(now).is_none());
+
+        // Nor is a consensus of other authorities
+        let other = [SigningKey::generate(&mut OsRng).verifying_key()];
+        let cache = NetworkCache::load(Some(&state_dir), &other, 1, now).unwrap();
+        assert!(cache.current(now).is_none());
+
+        // A corrupt state file is replaced by the next download
+        std::fs::write(state_dir.join(STATE_FILE), "{").unwrap();
+        let mut cache = NetworkCache::load(Some(&state_dir), &authorities, 1, now).unwrap();
+        assert!(cache.current(now).is_none());
+        let downloaded = self::document(&key, now);
+        let network = downloaded.verify(&authorities, 1, now).unwrap();
+        cache.update(downloaded, &network, now).await.unwrap();
+        let cache = NetworkCache::load(Some(&state_dir), &authorities, 1, now).unwrap();
+        assert!(cache.current(now).is_some());
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+
+    #[test]
+    fn backs_off_after_failed_downloads() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut cache = NetworkCache::load(None, &[], 1, now).unwrap();
+        assert!(cache.retry_due(now));
+        cache.attempt(now);
+        assert!(!cache.retry_due(now));
+
+        cache.failed(now);
+        assert!(!cache.retry_due(now + RETRY_BASE));
+        assert!(cache.retry_due(now + RETRY_BASE * 2));
+        for _ in 0..20 {
+            cache.failed(now);
+        }
+        assert!(cache.retry_due(now + RETRY_MAX));
+    }
+}
@@ -0,0 +1,54 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use prometheus::IntGauge;
+use tokio::sync::Notify;
+
+use crate::tor::client::PendingCircuit;
+
+/// Idle circuits built ahead of time, each one is used by a single connection
+pub(crate) struct CircuitPool {
+    idle: Mutex<VecDeque<PendingCircuit>>,
+    pub(crate) refill: Notify,
+    size: usize,
+    max_age: Duration,
+    idle_circuits: IntGauge,
+}
+
+impl CircuitPool {
+    pub(crate) fn new(size: usize, max_age: Duration, idle_circuits: IntGauge) -> Self {
+        Self {
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            refill: Notify::new(),
+            size,
+            max_age,
+            idle_circuits,
+        }
+    }
+
+    /// Takes the oldest circuit that didn't expire yet and wakes the refill task
+    pub(crate) fn take(&self) -> Option<PendingCircuit> {
+        let circuit = {
+            let mut idle = self.idle.lock().unwrap();
+            let circuit = std::iter::from_fn(|| idle.pop_front())
+                .find(|circuit| circuit.age() < self.max_age);
+            self.idle_circuits.set(idle.len() as i64);
+            circuit
+        };
+        self.refill.notify_one();
+        circuit
+    }
+
+    pub(crate) fn push(&self, circuit: PendingCircuit) {
+        let mut idle = self.idle.lock().unwrap();
+        idle.push_back(circuit);
+        self.idle_circuits.set(idle.len() as i64);
+    }
+
+    /// Drops expired circuits, returns how many circuits are missing
+    pub(crate) fn retire_expired(&self) -> usize {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|circuit| circuit.age() < self.max_age);
+        self.idle_circuits.set(idle.len() as i64);
+        self.size.saturating_sub(idle.len())
+    }
+}
@@ -65,7 +65,8 @@ impl Encryptor {
 
     pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>> {
         if bytes.len() < NONCE_LENGTH {
-            anyhow::bail!("Invalid message for encryption")
+            // Reported like any other message that fails authentication
+            return Err(aes_gcm::Error.into());
         }
 
         let nonce = Nonce::from_slice(&bytes[0..NONCE_LENGTH]);
@@ -1,4 +1,10 @@
+pub mod client;
 pub mod encryption;
+pub mod logging;
+pub mod metrics;
 pub mod node_io;
 pub mod proxy;
+pub(crate) mod state;
 pub mod tor;
+
+pub use client::{Client, ClientBuilder};
@@ -0,0 +1,55 @@
+//! Tracing setup shared by the binaries.
+//!
+//! Nodes log inside a `circuit` span carrying a node local circuit id, the peer address
+//! and once known the role of the node in the circuit. Clients log inside `circuit` and
+//! `hop` spans, the proxy adds a `stream` span per SOCKS connection. Payloads are never
+//! logged, only their sizes.
+
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, including the fields of every enclosing span
+    Json,
+}
+
+/// Installs the global subscriber, `RUST_LOG` can only lower the given level
+pub fn init(format: LogFormat, level: LevelFilter) -> anyhow::Result<()> {
+    let (level, handle) = reload::Layer::new(level);
+    let env = EnvFilter::builder()
+        .with_default_directive(LevelFilter::TRACE.into())
+        .from_env_lossy();
+    let (text, json) = match format {
+        LogFormat::Text => (Some(fmt::layer()), None),
+        LogFormat::Json => (None, Some(fmt::layer().json().with_span_list(true))),
+    };
+
+    tracing_subscriber::registry()
+        .with(level)
+        .with(env)
+        .with(text)
+        .with(json)
+        .try_init()?;
+    let _ = LEVEL.set(handle);
+    Ok(())
+}
+
+/// Changes the maximum level of a subscriber installed with [`init`]
+pub fn set_level(level: LevelFilter) -> anyhow::Result<()> {
+    let Some(handle) = LEVEL.get() else {
+        anyhow::bail!("Logging was not initialized by rustor")
+    };
+    handle.reload(level)?;
+    Ok(())
+}
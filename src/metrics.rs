@@ -0,0 +1,143 @@
+use std::{net::SocketAddr, time::Duration};
+
+use prometheus::{Encoder, Registry, TextEncoder};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// Time a client gets to send its request and read the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes of the request line and headers read, the rest is ignored
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Binds the metrics endpoint, returns the bound address and the future serving it
+pub async fn bind(
+    addr: SocketAddr,
+    registry: Registry,
+    shutdown: CancellationToken,
+) -> anyhow::Result<(SocketAddr, impl std::future::Future<Output = ()>)> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    info!("Metrics available at http://{}/metrics", addr);
+    Ok((addr, serve(listener, registry, shutdown)))
+}
+
+async fn serve(listener: TcpListener, registry: Registry, shutdown: CancellationToken) {
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!("Failed accepting metrics connection: {:?}", err);
+                    continue;
+                }
+            }
+        };
+
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            match timeout(REQUEST_TIMEOUT, respond(stream, &registry)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => error!("Failed serving metrics: {:?}", err),
+                Err(_) => debug!("Metrics client timed out"),
+            }
+        });
+    }
+}
+
+/// Minimal HTTP/1.1 handling, only `GET /metrics` is supported
+async fn respond(mut stream: TcpStream, registry: &Registry) -> anyhow::Result<()> {
+    let request_line = read_request(&mut stream).await?;
+
+    let (status, content_type, body) = if request_line.starts_with("GET /metrics ") {
+        let encoder = TextEncoder::new();
+        let mut body = vec![];
+        encoder.encode(&registry.gather(), &mut body)?;
+        ("200 OK", encoder.format_type().to_string(), body)
+    } else {
+        (
+            "404 Not Found",
+            "text/plain".to_string(),
+            b"Not found".to_vec(),
+        )
+    };
+
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                content_type,
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The request line, the headers are skipped. Reads at most [`MAX_REQUEST_SIZE`] bytes
+async fn read_request(stream: impl AsyncRead + Unpin) -> std::io::Result<String> {
+    let mut stream = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    let mut header = String::new();
+    while stream.read_line(&mut header).await? > 2 {
+        header.clear();
+    }
+    Ok(request_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::IntCounter;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn get(addr: SocketAddr, path: &str) -> anyhow::Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() -> anyhow::Result<()> {
+        let registry = Registry::new();
+        let counter = IntCounter::new("test_total", "A test counter")?;
+        registry.register(Box::new(counter.clone()))?;
+        counter.inc_by(3);
+
+        let shutdown = CancellationToken::new();
+        let (addr, server) = bind("127.0.0.1:0".parse()?, registry, shutdown.clone()).await?;
+        tokio::spawn(server);
+
+        let response = get(addr, "/metrics").await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("test_total 3"));
+
+        assert!(get(addr, "/other").await?.starts_with("HTTP/1.1 404"));
+        shutdown.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_the_request_size() -> anyhow::Result<()> {
+        let endless = vec![b'a'; 1024 * 1024];
+        let request_line = read_request(endless.as_slice()).await?;
+        assert_eq!(request_line.len() as u64, MAX_REQUEST_SIZE);
+        Ok(())
+    }
+}
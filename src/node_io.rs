@@ -3,6 +3,8 @@ use std::marker::PhantomData;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::tor::error::TorError;
+
 pub struct NodeIO<T, R, W> {
     inner: T,
     _phantom_data: PhantomData<(R, W)>,
@@ -30,7 +32,7 @@ where
         Ok(buf)
     }
 
-    pub async fn read(&mut self) -> anyhow::Result<R> {
+    pub async fn read(&mut self) -> Result<R, TorError> {
         let result = self.read_length_prefixed().await?;
         let value: R = bincode::deserialize(&result[..])?;
         Ok(value)
@@ -50,14 +52,19 @@ where
         Ok(())
     }
 
-    pub async fn node_write(&mut self, value: W) -> anyhow::Result<()> {
+    pub async fn node_write(&mut self, value: W) -> Result<(), TorError> {
         let bytes = bincode::serialize(&value)?;
         self.write_length_prefiexed(&bytes[..]).await?;
         Ok(())
     }
-    pub async fn write_raw(&mut self, value: &[u8]) -> anyhow::Result<()> {
+    pub async fn write_raw(&mut self, value: &[u8]) -> Result<(), TorError> {
         self.inner.write_all(value).await?;
         self.inner.flush().await?;
         Ok(())
     }
+
+    pub async fn shutdown(&mut self) -> Result<(), TorError> {
+        self.inner.shutdown().await?;
+        Ok(())
+    }
 }
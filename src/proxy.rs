@@ -1,53 +1,299 @@
-use crate::tor::{
-    client::{nodes_handshake, TorClient},
-    node_directory::get_nodes,
+use crate::{
+    tor::{error::TorError, stream::TorStream},
+    Client,
 };
 use gerevs::{
-    method_handlers::{Connect, SocksSocketAddr},
-    Socks5Error,
+    auth::{
+        username_password_authenticator::{User, UserAuthenticator, UsernamePasswordAuthenticator},
+        AuthMethod, Authenticator,
+    },
+    method_handlers::{AssociateDenier, BindDenier, Connect, SocksSocketAddr},
+    Socks5Error, Socks5Socket,
+};
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry};
+use std::{
+    hash::{BuildHasher, Hash, RandomState},
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Instant,
 };
-use rand::{rngs::OsRng, seq::SliceRandom, Rng};
-use std::{io, net::SocketAddr};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
-    net::TcpStream,
-    task::JoinHandle,
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
 };
+use tracing::{debug, error, info_span, warn, Instrument};
+
+/// What separates streams onto different circuits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IsolationRule {
+    /// The SOCKS5 username and password, which are accepted whatever they are
+    Auth,
+    ClientIp,
+    DestPort,
+    DestHost,
+}
+
+/// A SOCKS listener and its isolation rules, written as `addr` or `addr=rule,rule`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub isolation: Vec<IsolationRule>,
+}
+
+impl FromStr for ListenerConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, rules) = s.split_once('=').unwrap_or((s, ""));
+        let isolation = rules
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                <IsolationRule as clap::ValueEnum>::from_str(rule, true)
+                    .map_err(|_| anyhow::anyhow!("Unknown isolation rule {:?}", rule))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            addr: addr.trim().parse()?,
+            isolation,
+        })
+    }
+}
+
+/// Streams with different keys never share a circuit, streams of different listeners always
+/// differ
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IsolationKey {
+    listener: SocketAddr,
+    auth: Option<(String, String)>,
+    client_ip: Option<IpAddr>,
+    dest_port: Option<u16>,
+    dest_host: Option<String>,
+}
+
+impl IsolationKey {
+    pub fn new(
+        listener: &ListenerConfig,
+        client: SocketAddr,
+        credentials: Option<&SocksCredentials>,
+        dest_host: &str,
+        dest_port: u16,
+    ) -> Self {
+        let rule = |rule| listener.isolation.contains(&rule);
+        Self {
+            listener: listener.addr,
+            auth: credentials
+                .filter(|_| rule(IsolationRule::Auth))
+                .map(|credentials| (credentials.username.clone(), credentials.password.clone())),
+            client_ip: rule(IsolationRule::ClientIp).then(|| client.ip()),
+            dest_port: rule(IsolationRule::DestPort).then_some(dest_port),
+            dest_host: rule(IsolationRule::DestHost).then(|| dest_host.to_string()),
+        }
+    }
+
+    /// Identifies the key in logs without revealing it, only comparable within one process
+    pub fn log_id(&self) -> u64 {
+        static HASHER: OnceLock<RandomState> = OnceLock::new();
+        HASHER.get_or_init(RandomState::new).hash_one(self)
+    }
+}
+
+/// Username and password sent by a SOCKS5 client, only used for isolation
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SocksCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+struct AcceptAnyUser;
+
+impl UserAuthenticator for AcceptAnyUser {
+    type Credentials = Option<SocksCredentials>;
+
+    async fn authenticate_user(&mut self, user: User) -> io::Result<Option<Self::Credentials>> {
+        Ok(Some(Some(SocksCredentials {
+            username: user.username,
+            password: user.password,
+        })))
+    }
+}
+
+/// Lets clients choose between no authentication and any username and password
+pub struct IsolationAuthenticator(UsernamePasswordAuthenticator<AcceptAnyUser>);
+
+impl Default for IsolationAuthenticator {
+    fn default() -> Self {
+        Self(UsernamePasswordAuthenticator::new(AcceptAnyUser))
+    }
+}
+
+impl<T> Authenticator<T> for IsolationAuthenticator
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Credentials = Option<SocksCredentials>;
+
+    fn select_method(&self, methods: &[AuthMethod]) -> AuthMethod {
+        if methods.contains(&AuthMethod::UsernamePassword) {
+            AuthMethod::UsernamePassword
+        } else if methods.contains(&AuthMethod::NoAuthRequired) {
+            AuthMethod::NoAuthRequired
+        } else {
+            AuthMethod::NoAcceptableMethods
+        }
+    }
+
+    async fn authenticate(
+        &mut self,
+        conn: &mut T,
+        method: AuthMethod,
+    ) -> io::Result<Option<Self::Credentials>> {
+        match method {
+            AuthMethod::UsernamePassword => self.0.authenticate(conn, method).await,
+            _ => Ok(Some(None)),
+        }
+    }
+}
+
+/// Proxy wide counters, exported as prometheus metrics
+pub struct ProxyMetrics {
+    pub connections: IntCounter,
+    circuit_build_time: Histogram,
+    errors: IntCounterVec,
+}
 
-async fn get_nodes_randomized() -> anyhow::Result<Vec<SocketAddr>> {
-    const MIN_NODES: u8 = 5;
-    const MAX_NODES: u8 = 15;
-    let amount_of_nodes: u8 = rand::thread_rng().gen_range(MIN_NODES..=MAX_NODES);
-    let mut nodes = get_nodes(amount_of_nodes).await?;
+impl Default for ProxyMetrics {
+    fn default() -> Self {
+        Self {
+            connections: IntCounter::new(
+                "rustor_proxy_socks_connections_total",
+                "SOCKS connections accepted",
+            )
+            .expect("Metric is valid"),
+            circuit_build_time: Histogram::with_opts(
+                HistogramOpts::new(
+                    "rustor_proxy_circuit_build_seconds",
+                    "Time it took to build a circuit through every node",
+                )
+                .buckets(
+                    prometheus::exponential_buckets(0.01, 2.0, 12).expect("Buckets are valid"),
+                ),
+            )
+            .expect("Metric is valid"),
+            errors: IntCounterVec::new(
+                Opts::new("rustor_proxy_errors_total", "Failed SOCKS requests"),
+                &["reason"],
+            )
+            .expect("Metric is valid"),
+        }
+    }
+}
+
+impl ProxyMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.connections.clone()))?;
+        registry.register(Box::new(self.circuit_build_time.clone()))?;
+        registry.register(Box::new(self.errors.clone()))?;
+        Ok(())
+    }
+
+    fn error(&self, reason: &str) {
+        self.errors.with_label_values(&[reason]).inc();
+    }
+}
+
+/// gerevs derives the SOCKS5 reply code from the kind of the io error
+fn socks_reply_kind(err: &TorError) -> io::ErrorKind {
+    match err {
+        // Network unreachable
+        TorError::DirectoryUnavailable(_)
+        | TorError::ConsensusRejected(_)
+        | TorError::NotEnoughNodes { .. }
+        | TorError::NoEligibleRelay { .. }
+        | TorError::HopUnreachable { .. } => io::ErrorKind::NotConnected,
+        // TTL expired
+        TorError::HandshakeTimeout { .. } | TorError::BuildTimeout { .. } => {
+            io::ErrorKind::TimedOut
+        }
+        TorError::ExitConnectRefused(_) => io::ErrorKind::ConnectionRefused,
+        // Host unreachable
+        TorError::HostUnreachable(_) | TorError::ResolveFailed { .. } => io::ErrorKind::NotFound,
+        // Connection not allowed by ruleset
+        TorError::PolicyRejected(_) => io::ErrorKind::PermissionDenied,
+        // General SOCKS server failure
+        TorError::HopDecryptFailed { .. }
+        | TorError::CircuitDestroyed(_)
+        | TorError::UnexpectedMessage(_)
+        | TorError::Serialization(_)
+        | TorError::Io(_) => io::ErrorKind::Other,
+    }
+}
 
-    // Shuffle the nodes
-    nodes.shuffle(&mut OsRng);
-    Ok(nodes)
+/// Builds a circuit of its own for every stream, streams with the same isolation key take the
+/// path of the key's earlier circuits
+pub struct TorConnect {
+    client: Client,
+    metrics: Arc<ProxyMetrics>,
+    listener: Arc<ListenerConfig>,
+    peer: SocketAddr,
 }
 
-pub struct TorConnect;
+impl TorConnect {
+    pub fn new(
+        client: Client,
+        metrics: Arc<ProxyMetrics>,
+        listener: Arc<ListenerConfig>,
+        peer: SocketAddr,
+    ) -> Self {
+        Self {
+            client,
+            metrics,
+            listener,
+            peer,
+        }
+    }
 
-impl Connect<()> for TorConnect {
-    type ServerConnection = (
-        TorClient<ReadHalf<TcpStream>>,
-        TorClient<WriteHalf<TcpStream>>,
-    );
+    fn fail(&self, err: TorError) -> Socks5Error {
+        warn!("Failed building circuit: {}", err);
+        self.metrics.error(err.reason());
+        Socks5Error::IoError(io::Error::new(socks_reply_kind(&err), err))
+    }
+}
+
+impl Connect<Option<SocksCredentials>> for TorConnect {
+    type ServerConnection = TorStream;
 
     async fn establish_connection(
         &mut self,
         destination: SocksSocketAddr,
-        _: (),
+        credentials: Option<SocksCredentials>,
     ) -> gerevs::Result<Self::ServerConnection> {
-        println!("New connection: {:?}", destination);
-        let nodes_local = get_nodes_randomized()
-            .await
-            .map_err(|_| Socks5Error::IoError(io::ErrorKind::ConnectionRefused.into()))?;
-
-        let destination = &*destination.to_socket_addr().await?;
-        let (reader, writer) = nodes_handshake(nodes_local, destination[0])
+        let host = destination.addr.to_string();
+        let isolation = IsolationKey::new(
+            &self.listener,
+            self.peer,
+            credentials.as_ref(),
+            &host,
+            destination.port,
+        );
+        debug!(
+            ?destination,
+            isolation = isolation.log_id(),
+            "Building circuit"
+        );
+        let start = Instant::now();
+        let stream = self
+            .client
+            .connect_isolated(&host, destination.port, &isolation)
             .await
-            .map_err(|_| gerevs::Socks5Error::IoError(io::ErrorKind::ConnectionAborted.into()))?;
-        Ok((reader, writer))
+            .map_err(|err| self.fail(err))?;
+        self.metrics
+            .circuit_build_time
+            .observe(start.elapsed().as_secs_f64());
+        Ok(stream)
     }
 
     async fn start_listening<T>(
@@ -58,44 +304,131 @@ impl Connect<()> for TorConnect {
     where
         T: tokio::io::AsyncWrite + tokio::io::AsyncRead + Send + Unpin + 'static,
     {
-        let (mut server_reader, mut server_writer) = connection;
-
+        let (mut server_reader, mut server_writer) = connection.split();
         let (mut client_reader, mut client_writer) = tokio::io::split(client);
 
-        let client_to_server =
-            tokio::spawn(async move {
-                let mut buf = vec![0u8; 1024];
-
-                loop {
-                    let n = client_reader.read(&mut buf).await.map_err(|_| {
-                        Socks5Error::IoError(io::ErrorKind::ConnectionAborted.into())
-                    })?;
-                    if n == 0 {
-                        break;
-                    }
-                    server_writer.write(&buf[..n]).await.map_err(|_| {
-                        Socks5Error::IoError(io::ErrorKind::ConnectionAborted.into())
-                    })?;
+        let client_to_server = async {
+            tokio::io::copy(&mut client_reader, &mut server_writer).await?;
+            // The client finished sending, keep relaying the response until the server is done
+            std::future::pending::<io::Result<()>>().await
+        };
+        let server_to_client = tokio::io::copy(&mut server_reader, &mut client_writer);
+
+        tokio::select! {
+            result = client_to_server => result?,
+            result = server_to_client => {
+                result?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accepts SOCKS connections on `listener` until it fails
+pub async fn serve(
+    listener: TcpListener,
+    config: ListenerConfig,
+    client: Client,
+    metrics: Arc<ProxyMetrics>,
+) -> io::Result<()> {
+    let config = Arc::new(config);
+    for id in 1u64.. {
+        let (stream, peer) = listener.accept().await?;
+        let connect = TorConnect::new(client.clone(), metrics.clone(), config.clone(), peer);
+        let metrics = metrics.clone();
+        tokio::spawn(
+            async move {
+                metrics.connections.inc();
+                let socks5_stream = Socks5Socket::new(
+                    stream,
+                    IsolationAuthenticator::default(),
+                    connect,
+                    BindDenier,
+                    AssociateDenier,
+                );
+                if let Err(err) = socks5_stream.run().await {
+                    error!("Failed: {:?}", err);
                 }
+            }
+            .instrument(info_span!("stream", listener = %config.addr, id, %peer)),
+        );
+    }
+    Ok(())
+}
 
-                Ok::<_, Socks5Error>(())
-            });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let server_to_client: JoinHandle<Result<(), Socks5Error>> =
-            tokio::spawn(async move {
-                loop {
-                    let n = server_reader.read().await.map_err(|_| {
-                        Socks5Error::IoError(io::ErrorKind::ConnectionAborted.into())
-                    })?;
+    fn credentials(username: &str) -> SocksCredentials {
+        SocksCredentials {
+            username: username.to_string(),
+            password: "password".to_string(),
+        }
+    }
 
-                    client_writer.write_all(&n[..]).await.map_err(|_| {
-                        Socks5Error::IoError(io::ErrorKind::ConnectionAborted.into())
-                    })?;
-                }
-            });
-        drop(server_to_client);
-        drop(client_to_server);
+    #[test]
+    fn parses_listeners() {
+        let listener: ListenerConfig = "127.0.0.1:1080".parse().unwrap();
+        assert_eq!(listener.addr, "127.0.0.1:1080".parse().unwrap());
+        assert!(listener.isolation.is_empty());
 
-        Ok(())
+        let listener: ListenerConfig = "[::1]:9050=auth, dest-host,client-ip".parse().unwrap();
+        assert_eq!(listener.addr, "[::1]:9050".parse().unwrap());
+        assert_eq!(
+            listener.isolation,
+            vec![
+                IsolationRule::Auth,
+                IsolationRule::DestHost,
+                IsolationRule::ClientIp
+            ]
+        );
+
+        assert!("127.0.0.1:1080=everything"
+            .parse::<ListenerConfig>()
+            .is_err());
+        assert!("localhost=auth".parse::<ListenerConfig>().is_err());
+    }
+
+    #[test]
+    fn keys_differ_only_by_enabled_rules() {
+        let listener: ListenerConfig = "127.0.0.1:1080=auth,dest-port".parse().unwrap();
+        let client: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let other_client: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let key = |client, credentials: Option<&SocksCredentials>, host, port| {
+            IsolationKey::new(&listener, client, credentials, host, port)
+        };
+        let alice = credentials("alice");
+        let bob = credentials("bob");
+
+        let base = key(client, Some(&alice), "example.com", 80);
+        assert_eq!(base, key(other_client, Some(&alice), "example.org", 80));
+        assert_ne!(base, key(client, Some(&bob), "example.com", 80));
+        assert_ne!(base, key(client, None, "example.com", 80));
+        assert_ne!(base, key(client, Some(&alice), "example.com", 443));
+
+        let other_listener: ListenerConfig = "127.0.0.1:1081=auth,dest-port".parse().unwrap();
+        assert_ne!(
+            base,
+            IsolationKey::new(&other_listener, client, Some(&alice), "example.com", 80)
+        );
+    }
+
+    #[test]
+    fn keys_ignore_disabled_rules() {
+        let listener: ListenerConfig = "127.0.0.1:1080=client-ip,dest-host".parse().unwrap();
+        let client: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let key = IsolationKey::new(&listener, client, Some(&credentials("alice")), "a", 80);
+
+        assert_eq!(
+            key,
+            IsolationKey::new(&listener, client, Some(&credentials("bob")), "a", 443)
+        );
+        assert_ne!(key, IsolationKey::new(&listener, client, None, "b", 80));
+        assert_ne!(
+            key,
+            IsolationKey::new(&listener, "10.0.0.2:5000".parse().unwrap(), None, "a", 80)
+        );
+        assert_eq!(key.log_id(), key.clone().log_id());
     }
 }
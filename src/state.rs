@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads a JSON state file, a missing file is the default state
+pub(crate) fn read<T: DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Invalid state in {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err).context(format!("Failed reading {}", path.display())),
+    }
+}
+
+/// Writes to a temporary file first so a crash never leaves a partial state behind
+pub(crate) async fn write<T: Serialize>(path: &Path, state: &T) -> anyhow::Result<()> {
+    let content = serde_json::to_string_pretty(state)?;
+    let temporary = path.with_extension("json.tmp");
+    tokio::fs::write(&temporary, content).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+/// Path of `file` in `state_dir`, creating the directory if needed
+pub(crate) fn file(state_dir: &Path, file: &str) -> anyhow::Result<std::path::PathBuf> {
+    std::fs::create_dir_all(state_dir)
+        .with_context(|| format!("Failed creating state directory {}", state_dir.display()))?;
+    Ok(state_dir.join(file))
+}
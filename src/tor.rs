@@ -1,6 +1,19 @@
+pub mod authority;
+pub mod bandwidth;
 pub mod circuit_manager;
+pub mod circuit_registry;
 pub mod client;
+pub mod consensus;
+pub mod control;
+pub mod descriptor;
+pub mod error;
+pub mod exit_policy;
+pub mod keys;
 pub mod node;
+pub mod node_config;
 pub mod node_directory;
+pub mod node_server;
 pub mod onion;
+pub mod path;
+pub mod stream;
 pub mod tor_message;
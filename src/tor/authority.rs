@@ -0,0 +1,1440 @@
+//! Directory authority, keeps the descriptors nodes register, probes the nodes, measures their
+//! bandwidth and agrees with the other authorities on a jointly signed consensus.
+//!
+//! Time is split into voting periods aligned to the unix epoch. At the start of a period every
+//! authority signs a vote of the nodes it knows and fetches the votes of its peers until half
+//! the period passed. The votes are merged with [`Consensus::from_votes`], so authorities that
+//! saw the same votes sign the same document. During the rest of the period the authorities
+//! collect each other's signatures and publish the consensus once a majority signed it.
+//!
+//! Nodes register by signing a challenge of the authority with their identity key. Registrations
+//! are limited per address, subnet and family, and an admin can require approving nodes before
+//! they are voted on. The `/admin` endpoints also flag and ban nodes, see [`admin`].
+
+mod admin;
+mod measurement;
+mod registration;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{dev::ServerHandle, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use prometheus::{Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::state;
+use admin::AdminState;
+pub use admin::{AdminFlag, BanTarget, FlagChange};
+use measurement::{MEASURE_INTERVAL, PEAK_HALF_LIFE};
+pub use registration::RegistrationLimits;
+use registration::{Challenges, RateLimiter};
+
+use super::{
+    client::{prepare_circuit, CircuitTimeouts},
+    consensus::{
+        unix_secs, Consensus, ConsensusNode, Flag, NodeQuery, NodeSelection, SignedConsensus,
+        SignedVote, Vote, MAX_SELECTION_BANDWIDTH,
+    },
+    descriptor::{Deregistration, NodeDescriptor, Registration, SignedDescriptor, SignedHeartbeat},
+    keys::{encode_key, parse_key},
+};
+
+const STATE_FILE: &str = "directory.json";
+/// How long a node has to be known before it can be [`Flag::Stable`]
+const STABLE_AGE: Duration = Duration::from_secs(60 * 60);
+/// Ports a node has to exit to for [`Flag::Exit`]
+const EXIT_FLAG_PORTS: [u16; 2] = [80, 443];
+/// Share of probes a node has to pass for [`Flag::Stable`]
+const STABLE_UPTIME: f64 = 0.9;
+/// Age at which a probe counts half as much towards the uptime
+const UPTIME_HALF_LIFE: Duration = Duration::from_secs(60 * 60);
+/// Nodes a client gets from `/get_nodes` when it doesn't ask for an amount
+const DEFAULT_NODE_AMOUNT: usize = 5;
+
+/// Another authority, written as `url=key`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorityPeer {
+    pub url: String,
+    pub key: VerifyingKey,
+}
+
+impl FromStr for AuthorityPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (url, key) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected url=key, got {:?}", s))?;
+        Ok(Self {
+            url: url.trim().to_string(),
+            key: parse_key(key)?,
+        })
+    }
+}
+
+/// Directory counters, exported as prometheus metrics
+pub struct DirectoryMetrics {
+    registered_nodes: IntGauge,
+    valid_nodes: IntGauge,
+    consensus_signatures: IntGauge,
+    probes: IntCounterVec,
+    probe_latency: Histogram,
+    requests: IntCounterVec,
+}
+
+impl Default for DirectoryMetrics {
+    fn default() -> Self {
+        Self {
+            registered_nodes: IntGauge::new(
+                "rustor_directory_registered_nodes",
+                "Nodes registered at the directory",
+            )
+            .expect("Metric is valid"),
+            valid_nodes: IntGauge::new(
+                "rustor_directory_valid_nodes",
+                "Registered nodes that passed the last probe",
+            )
+            .expect("Metric is valid"),
+            consensus_signatures: IntGauge::new(
+                "rustor_directory_consensus_signatures",
+                "Authorities that signed the published consensus",
+            )
+            .expect("Metric is valid"),
+            probes: IntCounterVec::new(
+                Opts::new("rustor_directory_probes_total", "Node probes by result"),
+                &["result"],
+            )
+            .expect("Metric is valid"),
+            probe_latency: Histogram::with_opts(
+                HistogramOpts::new(
+                    "rustor_directory_probe_seconds",
+                    "Time passed probes took to complete the handshake",
+                )
+                .buckets(
+                    prometheus::exponential_buckets(0.001, 2.0, 13).expect("Buckets are valid"),
+                ),
+            )
+            .expect("Metric is valid"),
+            requests: IntCounterVec::new(
+                Opts::new("rustor_directory_requests_total", "Requests by endpoint"),
+                &["endpoint"],
+            )
+            .expect("Metric is valid"),
+        }
+    }
+}
+
+impl DirectoryMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.registered_nodes.clone()))?;
+        registry.register(Box::new(self.valid_nodes.clone()))?;
+        registry.register(Box::new(self.consensus_signatures.clone()))?;
+        registry.register(Box::new(self.probes.clone()))?;
+        registry.register(Box::new(self.probe_latency.clone()))?;
+        registry.register(Box::new(self.requests.clone()))?;
+        Ok(())
+    }
+
+    fn request(&self, endpoint: &str) {
+        self.requests.with_label_values(&[endpoint]).inc();
+    }
+}
+
+/// Configuration of a directory authority
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use rustor::tor::authority::{Authority, AuthorityPeer};
+///
+/// # let peer_key = rustor::tor::keys::parse_key("").unwrap();
+/// let key = ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng);
+/// let authority = Authority::new(key)
+///     .bind("0.0.0.0:30000".parse()?)
+///     .peer(AuthorityPeer {
+///         url: "http://10.0.0.2:30000".to_string(),
+///         key: peer_key,
+///     })
+///     .start()
+///     .await?;
+/// authority.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Authority {
+    bind: SocketAddr,
+    key: SigningKey,
+    peers: Vec<AuthorityPeer>,
+    probe_interval: Duration,
+    probe_timeout: Duration,
+    probe_concurrency: usize,
+    echo_address: Option<SocketAddr>,
+    node_ttl: Duration,
+    voting_interval: Duration,
+    data_dir: Option<PathBuf>,
+    registration_limits: RegistrationLimits,
+    require_approval: bool,
+    admin_token: Option<String>,
+}
+
+impl Authority {
+    pub fn new(key: SigningKey) -> Self {
+        Self {
+            bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+            key,
+            peers: vec![],
+            probe_interval: Duration::from_secs(5),
+            probe_timeout: Duration::from_secs(3),
+            probe_concurrency: 32,
+            echo_address: None,
+            node_ttl: Duration::from_secs(5 * 60),
+            voting_interval: Duration::from_secs(60),
+            data_dir: None,
+            registration_limits: RegistrationLimits::default(),
+            require_approval: false,
+            admin_token: None,
+        }
+    }
+
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// Adds an authority to exchange votes and signatures with, every authority has to know all
+    /// the others
+    pub fn peer(mut self, peer: AuthorityPeer) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    pub fn probe_interval(mut self, interval: Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    /// Time a node gets to complete the probe's handshake
+    pub fn probe_timeout(mut self, timeout: Duration) -> Self {
+        self.probe_timeout = timeout;
+        self
+    }
+
+    /// Nodes probed at the same time
+    pub fn probe_concurrency(mut self, concurrency: usize) -> Self {
+        self.probe_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Address of the echo nodes relay to when their bandwidth is measured, a free port on the
+    /// directory's address by default. With an unspecified ip nodes are sent to the address the
+    /// authority probes them from
+    pub fn echo_address(mut self, addr: SocketAddr) -> Self {
+        self.echo_address = Some(addr);
+        self
+    }
+
+    /// Nodes that neither registered nor sent a heartbeat for this long are dropped, they register
+    /// again when their next heartbeat is refused
+    pub fn node_ttl(mut self, ttl: Duration) -> Self {
+        self.node_ttl = ttl;
+        self
+    }
+
+    /// Length of a voting period, rounded down to whole seconds. All authorities need the same
+    pub fn voting_interval(mut self, interval: Duration) -> Self {
+        self.voting_interval = interval;
+        self
+    }
+
+    /// Keeps the registered nodes, their probe history and the published consensus in `dir`
+    /// across restarts, restored nodes are probed again right away
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    pub fn registration_limits(mut self, limits: RegistrationLimits) -> Self {
+        self.registration_limits = limits;
+        self
+    }
+
+    /// Leaves new nodes out of the votes until an admin approved them
+    pub fn require_approval(mut self, require: bool) -> Self {
+        self.require_approval = require;
+        self
+    }
+
+    /// Bearer token of the `/admin` endpoints, they refuse every request without one
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    pub async fn start(self) -> anyhow::Result<AuthorityHandle> {
+        let listener = std::net::TcpListener::bind(self.bind)?;
+        self.listen(listener)
+    }
+
+    /// Serves on an already bound listener, which lets authorities learn each other's addresses
+    /// before they start
+    pub fn listen(self, listener: std::net::TcpListener) -> anyhow::Result<AuthorityHandle> {
+        let local_addr = listener.local_addr()?;
+        let echo_listener = std::net::TcpListener::bind(
+            self.echo_address
+                .unwrap_or(SocketAddr::new(local_addr.ip(), 0)),
+        )?;
+        echo_listener.set_nonblocking(true)?;
+        let echo = echo_listener.local_addr()?;
+        let echo_listener = tokio::net::TcpListener::from_std(echo_listener)?;
+        let state_file = self
+            .data_dir
+            .as_deref()
+            .map(|dir| state::file(dir, STATE_FILE))
+            .transpose()?;
+        let (nodes, stored) = match &state_file {
+            Some(state_file) => restore(state_file)?,
+            None => Default::default(),
+        };
+        let metrics = Arc::new(DirectoryMetrics::default());
+        metrics.registered_nodes.set(nodes.len() as i64);
+        if let Some(published) = &stored.published {
+            metrics
+                .consensus_signatures
+                .set(published.signatures.len() as i64);
+        }
+        if let Some(state_file) = &state_file {
+            info!(
+                nodes = nodes.len(),
+                "Restored directory state from {}",
+                state_file.display()
+            );
+        }
+        let state = Arc::new(AuthorityState {
+            key: self.key,
+            peers: self.peers,
+            nodes: tokio::sync::RwLock::new(nodes),
+            metrics: metrics.clone(),
+            vote: Default::default(),
+            pending: Default::default(),
+            published: RwLock::new(stored.published),
+            node_ttl: self.node_ttl,
+            state_file,
+            saving: Mutex::new(()),
+            challenges: Default::default(),
+            rate_limiter: std::sync::Mutex::new(RateLimiter::new(self.registration_limits)),
+            max_family_size: self.registration_limits.max_family_size,
+            require_approval: self.require_approval,
+            admin_token: self
+                .admin_token
+                .map(|token| Sha256::digest(token.as_bytes()).into()),
+            admin: RwLock::new(stored.admin),
+        });
+
+        let data = web::Data::new(state.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/challenge", web::get().to(get_challenge))
+                .route("/add_node", web::post().to(add_node))
+                .route("/heartbeat", web::post().to(heartbeat))
+                .route("/remove_node", web::post().to(remove_node))
+                .route("/get_nodes", web::get().to(get_nodes))
+                .route("/consensus", web::get().to(get_consensus))
+                .route("/descriptor/{identity}", web::get().to(get_descriptor))
+                .route("/descriptors", web::get().to(get_descriptors))
+                .route("/vote", web::get().to(get_vote))
+                .route("/consensus/next", web::get().to(get_next_consensus))
+                .configure(admin::routes)
+        })
+        .disable_signals()
+        .listen(listener)?
+        .run();
+        let server_handle = server.handle();
+        info!(
+            "Directory authority {} listening on {}",
+            encode_key(&state.key.verifying_key()),
+            local_addr
+        );
+
+        let stop = CancellationToken::new();
+        let tasks = vec![
+            tokio::spawn(measurement::serve_echo(echo_listener, stop.clone())),
+            tokio::spawn(async move {
+                if let Err(err) = server.await {
+                    error!("Directory server failed: {:?}", err);
+                }
+            }),
+            tokio::spawn(probe_loop(
+                state.clone(),
+                Probing {
+                    interval: self.probe_interval,
+                    timeout: self.probe_timeout,
+                    concurrency: self.probe_concurrency,
+                    echo,
+                },
+                stop.clone(),
+            )),
+            tokio::spawn(voting_loop(
+                state.clone(),
+                self.voting_interval,
+                stop.clone(),
+            )),
+        ];
+
+        Ok(AuthorityHandle {
+            local_addr,
+            key: state.key.verifying_key(),
+            metrics,
+            server: server_handle,
+            stop,
+            tasks,
+        })
+    }
+}
+
+/// Handle to a running [`Authority`]
+pub struct AuthorityHandle {
+    local_addr: SocketAddr,
+    key: VerifyingKey,
+    metrics: Arc<DirectoryMetrics>,
+    server: ServerHandle,
+    stop: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl AuthorityHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.local_addr)
+    }
+
+    /// Key the authority signs votes and the consensus with
+    pub fn key(&self) -> VerifyingKey {
+        self.key
+    }
+
+    pub fn metrics(&self) -> &DirectoryMetrics {
+        &self.metrics
+    }
+
+    /// Stops probing and voting and waits for open requests to finish
+    pub async fn shutdown(self) {
+        self.stop.cancel();
+        self.server.stop(true).await;
+        for task in self.tasks {
+            if let Err(err) = task.await {
+                error!("Directory task panicked: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Probe results and heartbeats of a node
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct NodeStatus {
+    /// Unix seconds of the last passed probe
+    last_seen: Option<u64>,
+    probes: u64,
+    passed_probes: u64,
+    /// Probes weighted by their age, see [`UPTIME_HALF_LIFE`]
+    weighted_probes: f64,
+    weighted_passed: f64,
+    /// Unix seconds of the last probe
+    last_probe: u64,
+    /// Probes failed since the last passed one
+    failure_streak: u64,
+    /// Milliseconds the last passed probe took
+    latency_ms: Option<u64>,
+    /// Unix seconds of the last heartbeat or registration
+    last_heartbeat: u64,
+    /// Unix seconds the last accepted heartbeat was sent, heartbeats sent until then are replays
+    heartbeat_sent: u64,
+    /// Circuits open at the last heartbeat
+    circuits: u64,
+    /// Bytes per second the node relayed according to its last heartbeat
+    bandwidth: Option<u64>,
+    /// Highest bandwidth the authority measured, see [`NodeStatus::capacity`]
+    peak_bandwidth: Option<u64>,
+    /// Unix seconds of the last bandwidth measurement
+    measured_at: u64,
+}
+
+impl NodeStatus {
+    fn record_probe(&mut self, latency: Option<Duration>, now: u64) {
+        let age = now.saturating_sub(self.last_probe) as f64;
+        let decay = 0.5f64.powf(age / UPTIME_HALF_LIFE.as_secs_f64());
+        self.weighted_probes = self.weighted_probes * decay + 1.0;
+        self.weighted_passed =
+            self.weighted_passed * decay + if latency.is_some() { 1.0 } else { 0.0 };
+        self.last_probe = now;
+        self.probes += 1;
+        match latency {
+            Some(latency) => {
+                self.passed_probes += 1;
+                self.failure_streak = 0;
+                self.last_seen = Some(now);
+                self.latency_ms = Some(latency.as_millis() as u64);
+            }
+            None => self.failure_streak += 1,
+        }
+    }
+
+    fn record_bandwidth(&mut self, bandwidth: u64, now: u64) {
+        self.peak_bandwidth = Some(self.capacity(now).unwrap_or(0).max(bandwidth));
+        self.measured_at = now;
+    }
+
+    /// The peak bandwidth voted on as the node's capacity, it halves every
+    /// [`PEAK_HALF_LIFE`] unless a measurement reaches it again
+    fn capacity(&self, now: u64) -> Option<u64> {
+        let age = now.saturating_sub(self.measured_at) as f64;
+        let decay = 0.5f64.powf(age / PEAK_HALF_LIFE.as_secs_f64());
+        self.peak_bandwidth
+            .map(|peak| ((peak as f64 * decay) as u64).min(MAX_SELECTION_BANDWIDTH))
+    }
+
+    fn measurement_due(&self, now: u64) -> bool {
+        self.peak_bandwidth.is_none()
+            || self.measured_at.saturating_add(MEASURE_INTERVAL.as_secs()) <= now
+    }
+
+    /// Passed the last probe
+    fn running(&self) -> bool {
+        self.probes > 0 && self.failure_streak == 0
+    }
+
+    /// Share of the probes the node passed, recent probes weigh more so flapping nodes can't
+    /// live off their past
+    fn uptime(&self) -> f64 {
+        if self.weighted_probes == 0.0 {
+            return 0.0;
+        }
+        self.weighted_passed / self.weighted_probes
+    }
+
+    /// Running, known for long enough and passed nearly all probes
+    fn stable(&self, registered: u64, now: u64) -> bool {
+        self.running() && registered + STABLE_AGE.as_secs() <= now && self.uptime() >= STABLE_UPTIME
+    }
+}
+
+struct NodeEntry {
+    signed: SignedDescriptor,
+    descriptor: NodeDescriptor,
+    /// Unix seconds the node first registered at its address
+    registered: u64,
+    status: Mutex<NodeStatus>,
+}
+
+/// What the authority keeps in its data directory across restarts
+#[derive(Default, Serialize, Deserialize)]
+struct StoredState {
+    nodes: Vec<StoredNode>,
+    published: Option<SignedConsensus>,
+    /// Older state files only have the `approvals`
+    #[serde(default, alias = "approvals")]
+    admin: AdminState,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredNode {
+    descriptor: SignedDescriptor,
+    registered: u64,
+    status: NodeStatus,
+}
+
+struct AuthorityState {
+    key: SigningKey,
+    peers: Vec<AuthorityPeer>,
+    nodes: tokio::sync::RwLock<BTreeMap<SocketAddr, NodeEntry>>,
+    metrics: Arc<DirectoryMetrics>,
+    /// Own vote of the current period
+    vote: RwLock<Option<SignedVote>>,
+    /// Consensus of the current period with only this authority's signature, fetched by the peers
+    pending: RwLock<Option<SignedConsensus>>,
+    /// Newest consensus a majority of the authorities signed
+    published: RwLock<Option<SignedConsensus>>,
+    node_ttl: Duration,
+    state_file: Option<PathBuf>,
+    /// Keeps concurrent saves from writing the same temporary file
+    saving: Mutex<()>,
+    challenges: std::sync::Mutex<Challenges>,
+    rate_limiter: std::sync::Mutex<RateLimiter>,
+    max_family_size: usize,
+    require_approval: bool,
+    /// Digest of the admin token
+    admin_token: Option<[u8; 32]>,
+    admin: RwLock<AdminState>,
+}
+
+impl AuthorityState {
+    fn authorities(&self) -> usize {
+        self.peers.len() + 1
+    }
+
+    fn majority(&self) -> usize {
+        self.authorities() / 2 + 1
+    }
+
+    /// Whether the node may appear in the consensus
+    fn approved(&self, identity: &str) -> bool {
+        self.admin
+            .read()
+            .expect("Admin lock poisoned")
+            .approved(identity, self.require_approval)
+    }
+
+    /// Flags the authority votes for each of `nodes` with the admins' overrides applied, along
+    /// with the node's status
+    async fn assess<'a>(
+        &self,
+        nodes: impl IntoIterator<Item = &'a NodeEntry>,
+        now: u64,
+    ) -> Vec<(&'a NodeEntry, NodeStatus, BTreeSet<Flag>)> {
+        let mut statuses = vec![];
+        for entry in nodes {
+            statuses.push((entry, entry.status.lock().await.clone()));
+        }
+        let admin = self.admin.read().expect("Admin lock poisoned");
+        // Guards have to be at least as fast as the median valid node
+        let mut bandwidths = statuses
+            .iter()
+            .filter(|(entry, status)| {
+                status.running()
+                    && admin.approved(&entry.descriptor.identity, self.require_approval)
+            })
+            .map(|(_, status)| status.capacity(now).unwrap_or(0))
+            .collect::<Vec<_>>();
+        bandwidths.sort();
+        let guard_bandwidth = bandwidths.get(bandwidths.len() / 2).copied().unwrap_or(0);
+
+        let mut assessed = vec![];
+        for (entry, status) in statuses {
+            let mut flags = BTreeSet::new();
+            if status.running() {
+                // Clients only use nodes that are up
+                flags.insert(Flag::Running);
+                flags.insert(Flag::Valid);
+            }
+            if EXIT_FLAG_PORTS
+                .iter()
+                .all(|port| entry.descriptor.allows_exit_port(*port))
+            {
+                flags.insert(Flag::Exit);
+            }
+            if status.stable(entry.registered, now) {
+                flags.insert(Flag::Stable);
+                if status.capacity(now).unwrap_or(0) >= guard_bandwidth {
+                    flags.insert(Flag::Guard);
+                }
+            }
+            admin.apply(&entry.descriptor.identity, &mut flags);
+            assessed.push((entry, status, flags));
+        }
+        assessed
+    }
+
+    async fn vote(&self, valid_after: u64) -> Vote {
+        let now = unix_secs(SystemTime::now());
+        let nodes = self.nodes.read().await;
+        let listed = self
+            .assess(nodes.values(), now)
+            .await
+            .into_iter()
+            .filter(|(entry, ..)| self.approved(&entry.descriptor.identity))
+            .map(|(entry, status, flags)| {
+                ConsensusNode::new(
+                    &entry.signed,
+                    &entry.descriptor,
+                    flags,
+                    status.capacity(now),
+                )
+            })
+            .collect();
+        Vote {
+            authority: encode_key(&self.key.verifying_key()),
+            valid_after,
+            nodes: listed,
+        }
+    }
+
+    /// Forgets nodes whose last heartbeat is older than the TTL
+    async fn expire(&self) {
+        let now = unix_secs(SystemTime::now());
+        let nodes = &mut *self.nodes.write().await;
+        let mut expired = vec![];
+        for (addr, entry) in nodes.iter() {
+            if entry.status.lock().await.last_heartbeat + self.node_ttl.as_secs() < now {
+                expired.push(*addr);
+            }
+        }
+        for addr in expired {
+            info!(node = %addr, "Node stopped sending heartbeats");
+            nodes.remove(&addr);
+        }
+        self.metrics.registered_nodes.set(nodes.len() as i64);
+    }
+
+    /// Writes the nodes and the published consensus to the data directory, if there is one
+    async fn save(&self) {
+        let Some(state_file) = &self.state_file else {
+            return;
+        };
+        let _saving = self.saving.lock().await;
+        let mut stored = StoredState {
+            nodes: vec![],
+            published: self
+                .published
+                .read()
+                .expect("Consensus lock poisoned")
+                .clone(),
+            admin: self.admin.read().expect("Admin lock poisoned").clone(),
+        };
+        for entry in self.nodes.read().await.values() {
+            stored.nodes.push(StoredNode {
+                descriptor: entry.signed.clone(),
+                registered: entry.registered,
+                status: entry.status.lock().await.clone(),
+            });
+        }
+        if let Err(err) = state::write(state_file, &stored).await {
+            error!("Failed saving directory state: {:?}", err);
+        }
+    }
+}
+
+/// Nodes and consensus saved in `state_file`, descriptors that no longer verify are dropped
+fn restore(state_file: &Path) -> anyhow::Result<(BTreeMap<SocketAddr, NodeEntry>, StoredState)> {
+    let mut stored: StoredState = state::read(state_file)?;
+    let now = SystemTime::now();
+    let nodes = stored
+        .nodes
+        .drain(..)
+        .filter_map(|node| match node.descriptor.verify(now) {
+            Ok(descriptor) => Some((
+                descriptor.addr,
+                NodeEntry {
+                    signed: node.descriptor,
+                    descriptor,
+                    registered: node.registered,
+                    status: Mutex::new(node.status),
+                },
+            )),
+            Err(err) => {
+                warn!("Dropping stored descriptor: {}", err);
+                None
+            }
+        })
+        .collect();
+    Ok((nodes, stored))
+}
+
+/// How the nodes are probed
+#[derive(Clone, Copy)]
+struct Probing {
+    interval: Duration,
+    timeout: Duration,
+    concurrency: usize,
+    /// Where the nodes relay to when their bandwidth is measured
+    echo: SocketAddr,
+}
+
+async fn probe_loop(state: Arc<AuthorityState>, probing: Probing, stop: CancellationToken) {
+    let mut interval = tokio::time::interval(probing.interval);
+    let limit = Arc::new(Semaphore::new(probing.concurrency));
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+        state.expire().await;
+        // Probing without the lock keeps slow nodes from blocking registrations and votes
+        let now = unix_secs(SystemTime::now());
+        let mut nodes = vec![];
+        for (addr, entry) in state.nodes.read().await.iter() {
+            nodes.push((*addr, entry.status.lock().await.measurement_due(now)));
+        }
+        debug!(nodes = nodes.len(), "Probing nodes");
+        let mut probes = JoinSet::new();
+        for (node, measure) in nodes {
+            let limit = limit.clone();
+            let echo = measure.then_some(probing.echo);
+            probes.spawn(async move {
+                let _permit = limit.acquire_owned().await.expect("Never closed");
+                (node, probe(node, probing.timeout, echo).await)
+            });
+        }
+        let mut results = vec![];
+        loop {
+            tokio::select! {
+                // Dropping the probes aborts them
+                _ = stop.cancelled() => return,
+                result = probes.join_next() => match result {
+                    Some(Ok(result)) => results.push(result),
+                    Some(Err(err)) => error!("Probe panicked: {:?}", err),
+                    None => break,
+                },
+            }
+        }
+
+        let now = unix_secs(SystemTime::now());
+        let nodes = state.nodes.read().await;
+        let metrics = &state.metrics;
+        let mut valid_nodes = 0;
+        for (node, probe) in results {
+            // Removed while it was probed
+            let Some(entry) = nodes.get(&node) else {
+                continue;
+            };
+            let mut status = entry.status.lock().await;
+            let was_running = status.running();
+            status.record_probe(probe.map(|probe| probe.latency), now);
+            if let Some(bandwidth) = probe.and_then(|probe| probe.bandwidth) {
+                debug!(%node, bandwidth, "Measured bandwidth");
+                status.record_bandwidth(bandwidth, now);
+            }
+            match probe {
+                Some(Probe { latency, .. }) => {
+                    debug!(%node, ?latency, "Node is running");
+                    metrics.probes.with_label_values(&["valid"]).inc();
+                    metrics.probe_latency.observe(latency.as_secs_f64());
+                    valid_nodes += 1;
+                }
+                None => {
+                    if was_running {
+                        info!(%node, "Node stopped running");
+                    } else {
+                        debug!(%node, failures = status.failure_streak, "Node is down");
+                    }
+                    metrics.probes.with_label_values(&["invalid"]).inc();
+                }
+            }
+        }
+        metrics.valid_nodes.set(valid_nodes);
+        drop(nodes);
+        state.save().await;
+    }
+}
+
+/// A probe the node passed
+#[derive(Clone, Copy)]
+struct Probe {
+    /// How long the handshake took
+    latency: Duration,
+    /// Bytes per second the node relayed to the echo, when it was measured
+    bandwidth: Option<u64>,
+}
+
+/// Builds a one-hop circuit through the node, which proves that it relays rather than just
+/// accepts connections. The circuit then measures the node's bandwidth when given an `echo`
+async fn probe(
+    node: SocketAddr,
+    probe_timeout: Duration,
+    echo: Option<SocketAddr>,
+) -> Option<Probe> {
+    let started = Instant::now();
+    let timeouts = CircuitTimeouts {
+        handshake: probe_timeout,
+        build: probe_timeout,
+        ..Default::default()
+    };
+    let circuit = match prepare_circuit(vec![node], timeouts).await {
+        Ok(circuit) => circuit,
+        Err(err) => {
+            debug!(%node, "Probe failed: {}", err);
+            return None;
+        }
+    };
+    let latency = started.elapsed();
+    let bandwidth = match echo {
+        Some(echo) => match timeout(probe_timeout, measurement::measure(circuit, echo)).await {
+            Ok(Ok(bandwidth)) => Some(bandwidth),
+            Ok(Err(err)) => {
+                debug!(%node, "Measuring bandwidth failed: {}", err);
+                None
+            }
+            Err(_) => {
+                debug!(%node, "Measuring bandwidth timed out");
+                None
+            }
+        },
+        None => None,
+    };
+    Some(Probe { latency, bandwidth })
+}
+
+async fn voting_loop(state: Arc<AuthorityState>, interval: Duration, stop: CancellationToken) {
+    let interval = interval.as_secs().max(1);
+    loop {
+        let period = unix_secs(SystemTime::now()) / interval * interval;
+        tokio::select! {
+            _ = stop.cancelled() => break,
+            _ = run_round(&state, period, interval) => {}
+        }
+        let next_period = UNIX_EPOCH + Duration::from_secs(period + interval);
+        let wait = next_period
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        tokio::select! {
+            _ = stop.cancelled() => break,
+            _ = sleep(wait) => {}
+        }
+    }
+}
+
+/// Votes for the period starting at `period`, merges the peers' votes and collects their
+/// signatures over the result until the period ends
+async fn run_round(state: &AuthorityState, period: u64, interval: u64) {
+    let interval = Duration::from_secs(interval);
+    let start = UNIX_EPOCH + Duration::from_secs(period);
+    let poll = interval / 20;
+    let http = reqwest::Client::builder()
+        .timeout(interval / 4)
+        .build()
+        .expect("Client config is valid");
+
+    let vote = state.vote(period).await;
+    *state.vote.write().expect("Vote lock poisoned") = Some(vote.sign(&state.key));
+    let mut votes = BTreeMap::from([(vote.authority.clone(), vote)]);
+    loop {
+        for peer in &state.peers {
+            if votes.contains_key(&encode_key(&peer.key)) {
+                continue;
+            }
+            let vote = fetch::<SignedVote>(&http, format!("{}/vote", peer.url))
+                .await
+                .and_then(|signed| Ok(signed.verify(&[peer.key])?));
+            match vote {
+                Ok(vote) if vote.valid_after == period => {
+                    votes.insert(vote.authority.clone(), vote);
+                }
+                Ok(_) => debug!(peer = %peer.url, "Peer hasn't voted for the period yet"),
+                Err(err) => debug!(peer = %peer.url, "Failed fetching vote: {:?}", err),
+            }
+        }
+        if votes.len() == state.authorities() || SystemTime::now() >= start + interval / 2 {
+            break;
+        }
+        sleep(poll).await;
+    }
+    if votes.len() < state.majority() {
+        warn!(
+            votes = votes.len(),
+            "Too few authorities voted, keeping the previous consensus"
+        );
+        return;
+    }
+
+    let consensus = Consensus::from_votes(votes.values(), state.authorities(), period);
+    let mut signed = consensus.sign(&state.key);
+    *state.pending.write().expect("Consensus lock poisoned") = Some(signed.clone());
+    loop {
+        for peer in &state.peers {
+            let authority = encode_key(&peer.key);
+            if signed
+                .signatures
+                .iter()
+                .any(|signature| signature.authority == authority)
+            {
+                continue;
+            }
+            match fetch::<SignedConsensus>(&http, format!("{}/consensus/next", peer.url)).await {
+                Ok(theirs) if theirs.document == signed.document => {
+                    if let Err(err) = theirs.verify(&[peer.key], 1, SystemTime::now()) {
+                        warn!(peer = %peer.url, "Rejected consensus signature: {}", err);
+                        continue;
+                    }
+                    if let Some(signature) = theirs
+                        .signatures
+                        .into_iter()
+                        .find(|signature| signature.authority == authority)
+                    {
+                        signed.add_signature(signature);
+                    }
+                }
+                Ok(_) => debug!(peer = %peer.url, "Peer has a different consensus"),
+                Err(err) => debug!(peer = %peer.url, "Failed fetching signature: {:?}", err),
+            }
+        }
+        if signed.signatures.len() >= state.majority() {
+            state
+                .metrics
+                .consensus_signatures
+                .set(signed.signatures.len() as i64);
+            *state.published.write().expect("Consensus lock poisoned") = Some(signed.clone());
+        }
+        if signed.signatures.len() == state.authorities() || SystemTime::now() >= start + interval {
+            break;
+        }
+        sleep(poll).await;
+    }
+
+    if signed.signatures.len() >= state.majority() {
+        state.save().await;
+        info!(
+            nodes = consensus.nodes.len(),
+            votes = votes.len(),
+            signatures = signed.signatures.len(),
+            "Published consensus"
+        );
+    } else {
+        warn!(
+            signatures = signed.signatures.len(),
+            "Too few authorities signed, keeping the previous consensus"
+        );
+    }
+}
+
+async fn fetch<T: DeserializeOwned>(http: &reqwest::Client, url: String) -> anyhow::Result<T> {
+    Ok(http
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// A challenge the node signs with its identity key to register
+async fn get_challenge(data: web::Data<Arc<AuthorityState>>) -> impl Responder {
+    data.metrics.request("challenge");
+    let challenge = data
+        .challenges
+        .lock()
+        .expect("Challenges lock poisoned")
+        .issue(Instant::now());
+    HttpResponse::Ok().json(challenge)
+}
+
+async fn add_node(
+    data: web::Data<Arc<AuthorityState>>,
+    request: HttpRequest,
+    registration: web::Json<Registration>,
+) -> impl Responder {
+    data.metrics.request("add_node");
+    let source = request.peer_addr().map(|addr| addr.ip());
+    let descriptor = match registration.verify(SystemTime::now()) {
+        Ok(descriptor) => descriptor,
+        Err(err) => {
+            info!("Rejected registration: {}", err);
+            return HttpResponse::BadRequest().body(err.to_string());
+        }
+    };
+    let redeemed = data
+        .challenges
+        .lock()
+        .expect("Challenges lock poisoned")
+        .redeem(&registration.challenge, Instant::now());
+    if !redeemed {
+        return HttpResponse::Forbidden().body("Unknown or expired challenge");
+    }
+    let signed = registration.into_inner().descriptor;
+
+    {
+        let nodes = &mut *data.nodes.write().await;
+        // Checked with the nodes locked, bans drop the nodes after recording the ban
+        let refused = {
+            let admin = data.admin.read().expect("Admin lock poisoned");
+            admin.refused(&descriptor.identity, descriptor.addr.ip())
+                || source.is_some_and(|ip| admin.bans.addrs.contains(&ip))
+        };
+        if refused {
+            info!(identity = %descriptor.identity, source = ?source, "Refused banned node");
+            return HttpResponse::Forbidden().body("Node was rejected or banned");
+        }
+        let now = unix_secs(SystemTime::now());
+        if let Some(entry) = nodes.get(&descriptor.addr) {
+            let ttl = data.node_ttl.as_secs();
+            let live = entry.status.lock().await.last_heartbeat.saturating_add(ttl) >= now;
+            if entry.descriptor.identity != descriptor.identity && live {
+                info!(node = %descriptor.addr, identity = %descriptor.identity, "Address is taken");
+                return HttpResponse::Conflict().body("Address is registered by another node");
+            }
+        }
+        // Replayed or reordered uploads must not undo a newer descriptor, the same one is fine
+        let current = nodes
+            .values()
+            .find(|entry| entry.descriptor.identity == descriptor.identity);
+        if let Some(current) = current {
+            let newer =
+                descriptor.published > current.descriptor.published || current.signed == signed;
+            if !newer {
+                return HttpResponse::Conflict()
+                    .body("Descriptor isn't newer than the registered one");
+            }
+        }
+        if let Some(family) = &descriptor.family {
+            // Anyone can claim a family, only members that made it into the network count so
+            // unreachable or unapproved nodes can't lock the family's operator out
+            let mut members = 0;
+            for entry in nodes.values() {
+                if entry.descriptor.identity != descriptor.identity
+                    && entry.descriptor.family.as_ref() == Some(family)
+                    && data.approved(&entry.descriptor.identity)
+                    && entry.status.lock().await.running()
+                {
+                    members += 1;
+                }
+            }
+            if members >= data.max_family_size {
+                info!(%family, "Family is full");
+                return HttpResponse::Forbidden()
+                    .body(format!("Family {} has {} nodes already", family, members));
+            }
+        }
+        // Only nodes new to the directory count towards the limits, so junk uploads can't use
+        // them up and known nodes can replace their descriptors
+        let known = nodes.contains_key(&descriptor.addr)
+            || nodes
+                .values()
+                .any(|entry| entry.descriptor.identity == descriptor.identity);
+        let allowed = known
+            || source.is_none_or(|ip| {
+                data.rate_limiter
+                    .lock()
+                    .expect("Rate limiter lock poisoned")
+                    .allow(ip, Instant::now())
+            });
+        if !allowed {
+            info!(source = ?source, "Too many registrations");
+            return HttpResponse::TooManyRequests().body("Too many registrations from the address");
+        }
+        // A node that moved to a new address keeps a single entry
+        nodes.retain(|addr, entry| {
+            entry.descriptor.identity != descriptor.identity || *addr == descriptor.addr
+        });
+        let (registered, mut status) = match nodes.get(&descriptor.addr) {
+            Some(entry) if entry.descriptor.identity == descriptor.identity => {
+                (entry.registered, entry.status.lock().await.clone())
+            }
+            _ => (now, NodeStatus::default()),
+        };
+        status.last_heartbeat = now;
+        info!(node = %descriptor.addr, identity = %descriptor.identity, "Registered node");
+        if !data.approved(&descriptor.identity) {
+            info!(identity = %descriptor.identity, "Node is waiting for approval");
+        }
+        nodes.insert(
+            descriptor.addr,
+            NodeEntry {
+                signed,
+                descriptor,
+                registered,
+                status: Mutex::new(status),
+            },
+        );
+        data.metrics.registered_nodes.set(nodes.len() as i64);
+    }
+    data.save().await;
+
+    HttpResponse::Ok().body("Node added")
+}
+
+async fn heartbeat(
+    data: web::Data<Arc<AuthorityState>>,
+    signed: web::Json<SignedHeartbeat>,
+) -> impl Responder {
+    data.metrics.request("heartbeat");
+    let now = SystemTime::now();
+    let heartbeat = match signed.verify(now) {
+        Ok(heartbeat) => heartbeat,
+        Err(err) => {
+            info!("Rejected heartbeat: {}", err);
+            return HttpResponse::BadRequest().body(err.to_string());
+        }
+    };
+
+    let nodes = data.nodes.read().await;
+    match nodes.get(&heartbeat.addr) {
+        Some(entry) if entry.descriptor.identity == heartbeat.identity => {
+            let mut status = entry.status.lock().await;
+            if heartbeat.sent <= status.heartbeat_sent {
+                info!(node = %heartbeat.addr, sent = heartbeat.sent, "Replayed heartbeat");
+                return HttpResponse::Conflict().body("Heartbeat isn't newer than the last one");
+            }
+            status.heartbeat_sent = heartbeat.sent;
+            // A heartbeat only proves the node was up when it was sent
+            let sent = heartbeat.sent.min(unix_secs(now));
+            status.last_heartbeat = status.last_heartbeat.max(sent);
+            status.circuits = heartbeat.circuits;
+            status.bandwidth = Some(heartbeat.bandwidth);
+            debug!(
+                node = %heartbeat.addr,
+                circuits = heartbeat.circuits,
+                bandwidth = heartbeat.bandwidth,
+                "Heartbeat"
+            );
+            HttpResponse::Ok().body("Heartbeat received")
+        }
+        // Tells the node to register again
+        _ => HttpResponse::NotFound().body("Unknown node"),
+    }
+}
+
+async fn remove_node(
+    data: web::Data<Arc<AuthorityState>>,
+    deregistration: web::Json<Deregistration>,
+) -> impl Responder {
+    data.metrics.request("remove_node");
+    if let Err(err) = deregistration.verify() {
+        info!("Rejected deregistration: {}", err);
+        return HttpResponse::BadRequest().body(err.to_string());
+    }
+    {
+        let nodes = &mut *data.nodes.write().await;
+        match nodes.get(&deregistration.addr) {
+            Some(entry) if entry.descriptor.identity == deregistration.identity => {}
+            // Only the node registered at the address may remove it
+            Some(_) => return HttpResponse::Forbidden().body("Node has a different identity"),
+            None => return HttpResponse::NotFound().body("Unknown node"),
+        }
+        let redeemed = data
+            .challenges
+            .lock()
+            .expect("Challenges lock poisoned")
+            .redeem(&deregistration.challenge, Instant::now());
+        if !redeemed {
+            return HttpResponse::Forbidden().body("Unknown or expired challenge");
+        }
+        nodes.remove(&deregistration.addr);
+        data.metrics.registered_nodes.set(nodes.len() as i64);
+    }
+    info!(node = %deregistration.addr, "Removed node");
+    data.save().await;
+    HttpResponse::Ok().body("Node removed")
+}
+
+/// The published consensus, for clients that select nodes themselves
+async fn get_consensus(data: web::Data<Arc<AuthorityState>>) -> impl Responder {
+    data.metrics.request("consensus");
+    match &*data.published.read().expect("Consensus lock poisoned") {
+        Some(consensus) => HttpResponse::Ok().json(consensus),
+        None => HttpResponse::ServiceUnavailable().body("No consensus yet"),
+    }
+}
+
+/// Query of `/get_nodes`, flags and excluded identities are comma separated
+#[derive(Deserialize)]
+struct GetNodesQuery {
+    amount: Option<usize>,
+    flags: Option<String>,
+    port: Option<u16>,
+    exclude: Option<String>,
+}
+
+impl TryFrom<GetNodesQuery> for NodeQuery {
+    type Error = anyhow::Error;
+
+    fn try_from(query: GetNodesQuery) -> Result<Self, Self::Error> {
+        let list = |value: Option<String>| {
+            value
+                .into_iter()
+                .flat_map(|value| {
+                    value
+                        .split(',')
+                        .filter(|item| !item.trim().is_empty())
+                        .map(|item| item.trim().to_string())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<BTreeSet<_>>()
+        };
+        Ok(NodeQuery {
+            amount: query.amount.unwrap_or(DEFAULT_NODE_AMOUNT),
+            flags: list(query.flags)
+                .iter()
+                .map(|flag| flag.parse())
+                .collect::<anyhow::Result<_>>()?,
+            port: query.port,
+            exclude: list(query.exclude),
+        })
+    }
+}
+
+/// Nodes of the published consensus matching the query, picked weighted by bandwidth
+async fn get_nodes(
+    data: web::Data<Arc<AuthorityState>>,
+    query: web::Query<GetNodesQuery>,
+) -> impl Responder {
+    data.metrics.request("get_nodes");
+    let query = match NodeQuery::try_from(query.into_inner()) {
+        Ok(query) => query,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    let Some(consensus) = data
+        .published
+        .read()
+        .expect("Consensus lock poisoned")
+        .clone()
+    else {
+        return HttpResponse::ServiceUnavailable().body("No consensus yet");
+    };
+    let nodes = match serde_json::from_str::<Consensus>(&consensus.document) {
+        Ok(mut document) => {
+            // Banned nodes stay in the consensus until the next one, they're never picked
+            let admin = data.admin.read().expect("Admin lock poisoned");
+            document
+                .nodes
+                .retain(|node| !admin.refused(&node.identity, node.addr.ip()));
+            drop(admin);
+            document
+                .select(&query, &mut rand::thread_rng())
+                .into_iter()
+                .map(|node| node.identity.clone())
+                .collect()
+        }
+        Err(err) => {
+            error!("Published consensus is malformed: {}", err);
+            return HttpResponse::InternalServerError().body("Malformed consensus");
+        }
+    };
+    HttpResponse::Ok().json(NodeSelection { consensus, nodes })
+}
+
+async fn get_descriptor(
+    data: web::Data<Arc<AuthorityState>>,
+    identity: web::Path<String>,
+) -> impl Responder {
+    data.metrics.request("descriptor");
+    let nodes = &*data.nodes.read().await;
+    match nodes
+        .values()
+        .find(|entry| entry.descriptor.identity == *identity)
+    {
+        Some(entry) => HttpResponse::Ok().json(&entry.signed),
+        None => HttpResponse::NotFound().body("Unknown node"),
+    }
+}
+
+async fn get_descriptors(data: web::Data<Arc<AuthorityState>>) -> impl Responder {
+    data.metrics.request("descriptors");
+    let nodes = &*data.nodes.read().await;
+    let descriptors = nodes
+        .values()
+        .map(|entry| &entry.signed)
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(descriptors)
+}
+
+async fn get_vote(data: web::Data<Arc<AuthorityState>>) -> impl Responder {
+    data.metrics.request("vote");
+    match &*data.vote.read().expect("Vote lock poisoned") {
+        Some(vote) => HttpResponse::Ok().json(vote),
+        None => HttpResponse::ServiceUnavailable().body("No vote yet"),
+    }
+}
+
+async fn get_next_consensus(data: web::Data<Arc<AuthorityState>>) -> impl Responder {
+    data.metrics.request("next_consensus");
+    match &*data.pending.read().expect("Consensus lock poisoned") {
+        Some(consensus) => HttpResponse::Ok().json(consensus),
+        None => HttpResponse::ServiceUnavailable().body("No consensus yet"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_peers() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng).verifying_key();
+        let peer: AuthorityPeer = format!("http://10.0.0.2:30000={}", encode_key(&key))
+            .parse()
+            .unwrap();
+        assert_eq!(
+            peer,
+            AuthorityPeer {
+                url: "http://10.0.0.2:30000".to_string(),
+                key
+            }
+        );
+        assert!("http://10.0.0.2:30000".parse::<AuthorityPeer>().is_err());
+    }
+
+    #[test]
+    fn derives_running_and_stable_from_probes() {
+        let mut status = NodeStatus::default();
+        let passed = Some(Duration::from_millis(20));
+        assert!(!status.running());
+        for probe in 0..20 {
+            status.record_probe(if probe == 5 { None } else { passed }, probe);
+        }
+        assert!(status.running());
+        assert!((status.uptime() - 0.95).abs() < 0.001);
+        assert_eq!(status.latency_ms, Some(20));
+        // Not known for long enough yet
+        assert!(!status.stable(0, STABLE_AGE.as_secs() - 1));
+        assert!(status.stable(0, STABLE_AGE.as_secs()));
+
+        status.record_probe(None, 20);
+        status.record_probe(None, 21);
+        assert_eq!(status.failure_streak, 2);
+        assert_eq!(status.last_seen, Some(19));
+        assert!(!status.running());
+        assert!(!status.stable(0, STABLE_AGE.as_secs()));
+    }
+
+    #[test]
+    fn flapping_nodes_lose_stable() {
+        let mut status = NodeStatus::default();
+        let passed = Some(Duration::from_millis(20));
+        let mut now = 0;
+        while now < 30 * 24 * 60 * 60 {
+            status.record_probe(passed, now);
+            now += 60;
+        }
+        assert!(status.stable(0, now));
+
+        // Half an hour of failing every other probe outweighs a month of passed probes
+        for probe in 0..60 {
+            status.record_probe(if probe % 2 == 0 { None } else { passed }, now);
+            now += 30;
+        }
+        assert!(status.running());
+        assert!(status.passed_probes as f64 / status.probes as f64 > STABLE_UPTIME);
+        assert!(!status.stable(0, now));
+    }
+
+    #[test]
+    fn measured_peak_decays() {
+        let mut status = NodeStatus::default();
+        assert!(status.measurement_due(0));
+        status.record_bandwidth(1000, 0);
+        assert!(!status.measurement_due(MEASURE_INTERVAL.as_secs() - 1));
+        assert!(status.measurement_due(MEASURE_INTERVAL.as_secs()));
+
+        // A slower measurement doesn't lower the peak right away
+        let half_life = PEAK_HALF_LIFE.as_secs();
+        status.record_bandwidth(100, 1);
+        assert_eq!(status.capacity(1), Some(999));
+        assert_eq!(status.capacity(half_life + 1), Some(499));
+        status.record_bandwidth(100, 10 * half_life);
+        assert_eq!(status.capacity(10 * half_life), Some(100));
+
+        // Peaks restored from before the authority measured are clamped
+        status.peak_bandwidth = Some(u64::MAX);
+        assert_eq!(
+            status.capacity(10 * half_life),
+            Some(MAX_SELECTION_BANDWIDTH)
+        );
+    }
+}
@@ -0,0 +1,569 @@
+//! Authenticated `/admin` endpoints to approve, flag and ban nodes. Every change is recorded in
+//! an audit log kept with the directory state.
+//!
+//! Bans take effect right away: banned nodes are dropped, so their descriptors are no longer
+//! served and clients leave them out of the published consensus until the next one omits them.
+//! Flag changes apply from the next vote on. Each authority bans on its own, a node the other
+//! authorities still vote for stays in the consensus.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use super::{AuthorityState, NodeEntry, NodeStatus};
+use crate::tor::consensus::{unix_secs, Flag};
+
+/// Audit entries kept, older ones are dropped
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// What admins decided about nodes, kept across restarts
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(super) struct AdminState {
+    pub(super) approved: BTreeSet<String>,
+    pub(super) rejected: BTreeSet<String>,
+    /// Flags admins assigned or removed, by identity
+    pub(super) flags: BTreeMap<String, FlagOverrides>,
+    pub(super) bans: Bans,
+    pub(super) audit: VecDeque<AuditEntry>,
+}
+
+impl AdminState {
+    /// Whether the node may appear in the consensus. Without approvals required every node that
+    /// isn't rejected does
+    pub(super) fn approved(&self, identity: &str, require_approval: bool) -> bool {
+        !self.rejected.contains(identity) && (!require_approval || self.approved.contains(identity))
+    }
+
+    /// Whether the node is refused, because it was rejected or banned
+    pub(super) fn refused(&self, identity: &str, ip: IpAddr) -> bool {
+        self.rejected.contains(identity) || self.bans.banned(identity, ip)
+    }
+
+    /// Applies the admins' overrides to the flags the authority measured for the node
+    pub(super) fn apply(&self, identity: &str, flags: &mut BTreeSet<Flag>) {
+        if let Some(overrides) = self.flags.get(identity) {
+            flags.extend(&overrides.assigned);
+            flags.retain(|flag| !overrides.removed.contains(flag));
+        }
+        if flags.contains(&Flag::BadExit) {
+            flags.remove(&Flag::Exit);
+        }
+    }
+
+    fn record(&mut self, entry: AuditEntry) {
+        self.audit.push_back(entry);
+        while self.audit.len() > MAX_AUDIT_ENTRIES {
+            self.audit.pop_front();
+        }
+    }
+}
+
+/// Flags an admin forces on or off a node, whatever the authority measured
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(super) struct FlagOverrides {
+    pub(super) assigned: BTreeSet<Flag>,
+    pub(super) removed: BTreeSet<Flag>,
+}
+
+/// Banned node addresses, every port of the address, and identities
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(super) struct Bans {
+    pub(super) addrs: BTreeSet<IpAddr>,
+    pub(super) identities: BTreeSet<String>,
+}
+
+impl Bans {
+    pub(super) fn banned(&self, identity: &str, ip: IpAddr) -> bool {
+        self.identities.contains(identity) || self.addrs.contains(&ip)
+    }
+}
+
+/// Flags an admin can change, [`AdminFlag::Approved`] approves the node for the votes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum AdminFlag {
+    BadExit,
+    Guard,
+    Exit,
+    Approved,
+}
+
+impl AdminFlag {
+    fn consensus_flag(self) -> Option<Flag> {
+        match self {
+            AdminFlag::BadExit => Some(Flag::BadExit),
+            AdminFlag::Guard => Some(Flag::Guard),
+            AdminFlag::Exit => Some(Flag::Exit),
+            AdminFlag::Approved => None,
+        }
+    }
+}
+
+/// Body of `/admin/flags/{identity}`. Reset flags go back to what the authority measures, a reset
+/// approval makes the node wait for approval again
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FlagChange {
+    pub assign: BTreeSet<AdminFlag>,
+    pub remove: BTreeSet<AdminFlag>,
+    pub reset: BTreeSet<AdminFlag>,
+}
+
+/// What gets banned, written as `{"addr": "192.0.2.1"}` or `{"identity": "..."}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    Addr(IpAddr),
+    Identity(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum AdminAction {
+    Approve {
+        identity: String,
+    },
+    Reject {
+        identity: String,
+    },
+    Flags {
+        identity: String,
+        change: FlagChange,
+    },
+    Ban {
+        target: BanTarget,
+    },
+    Unban {
+        target: BanTarget,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct AuditEntry {
+    /// Unix seconds
+    time: u64,
+    /// Address the admin sent the request from
+    source: Option<IpAddr>,
+    action: AdminAction,
+}
+
+pub(super) fn routes(config: &mut web::ServiceConfig) {
+    config
+        .route("/admin/nodes", web::get().to(get_nodes))
+        .route("/admin/pending", web::get().to(get_pending))
+        .route("/admin/approve/{identity}", web::post().to(approve))
+        .route("/admin/reject/{identity}", web::post().to(reject))
+        .route("/admin/flags/{identity}", web::post().to(change_flags))
+        .route("/admin/bans", web::get().to(get_bans))
+        .route("/admin/ban", web::post().to(ban))
+        .route("/admin/unban", web::post().to(unban))
+        .route("/admin/audit", web::get().to(get_audit));
+}
+
+/// Refuses requests without the admin token
+fn authorize(data: &AuthorityState, request: &HttpRequest) -> Result<(), HttpResponse> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (data.admin_token, token) {
+        (Some(expected), Some(token)) if <[u8; 32]>::from(Sha256::digest(token)) == expected => {
+            Ok(())
+        }
+        (None, _) => Err(HttpResponse::Forbidden().body("Admin API is disabled")),
+        _ => {
+            warn!(source = ?request.peer_addr(), "Invalid admin token");
+            Err(HttpResponse::Unauthorized().body("Invalid admin token"))
+        }
+    }
+}
+
+/// Applies `change` to the admin state, logs it and saves the directory state
+async fn audited(
+    data: &AuthorityState,
+    request: &HttpRequest,
+    action: AdminAction,
+    change: impl FnOnce(&mut AdminState),
+) {
+    record(data, request, action, change);
+    data.save().await;
+}
+
+/// Changes the admin state and logs the action without saving
+fn record(
+    data: &AuthorityState,
+    request: &HttpRequest,
+    action: AdminAction,
+    change: impl FnOnce(&mut AdminState),
+) {
+    info!(source = ?request.peer_addr(), action = ?action, "Admin action");
+    let admin = &mut *data.admin.write().expect("Admin lock poisoned");
+    change(admin);
+    admin.record(AuditEntry {
+        time: unix_secs(SystemTime::now()),
+        source: request.peer_addr().map(|addr| addr.ip()),
+        action,
+    });
+}
+
+/// Forgets the registered nodes matching `dropped`
+async fn drop_nodes(data: &AuthorityState, dropped: impl Fn(&NodeEntry) -> bool) {
+    let nodes = &mut *data.nodes.write().await;
+    nodes.retain(|_, entry| !dropped(entry));
+    data.metrics.registered_nodes.set(nodes.len() as i64);
+}
+
+/// A registered node with everything the authority knows about it
+#[derive(Serialize)]
+struct NodeReport<'a> {
+    identity: &'a str,
+    addr: SocketAddr,
+    nickname: &'a str,
+    contact: &'a Option<String>,
+    family: &'a Option<String>,
+    registered: u64,
+    approved: bool,
+    /// Flags the authority would vote for now
+    flags: BTreeSet<Flag>,
+    overrides: Option<FlagOverrides>,
+    uptime: f64,
+    status: NodeStatus,
+}
+
+async fn get_nodes(data: web::Data<Arc<AuthorityState>>, request: HttpRequest) -> HttpResponse {
+    data.metrics.request("admin_nodes");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let nodes = data.nodes.read().await;
+    let assessed = data
+        .assess(nodes.values(), unix_secs(SystemTime::now()))
+        .await;
+    let admin = data.admin.read().expect("Admin lock poisoned");
+    let report = assessed
+        .into_iter()
+        .map(|(entry, status, flags)| NodeReport {
+            identity: &entry.descriptor.identity,
+            addr: entry.descriptor.addr,
+            nickname: &entry.descriptor.nickname,
+            contact: &entry.descriptor.contact,
+            family: &entry.descriptor.family,
+            registered: entry.registered,
+            approved: admin.approved(&entry.descriptor.identity, data.require_approval),
+            flags,
+            overrides: admin.flags.get(&entry.descriptor.identity).cloned(),
+            uptime: status.uptime(),
+            status,
+        })
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(report)
+}
+
+/// A registered node an admin hasn't approved yet
+#[derive(Serialize)]
+struct PendingNode<'a> {
+    identity: &'a str,
+    addr: SocketAddr,
+    nickname: &'a str,
+    contact: &'a Option<String>,
+    family: &'a Option<String>,
+    registered: u64,
+}
+
+async fn get_pending(data: web::Data<Arc<AuthorityState>>, request: HttpRequest) -> HttpResponse {
+    data.metrics.request("admin_pending");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let nodes = data.nodes.read().await;
+    let pending = nodes
+        .values()
+        .filter(|entry| !data.approved(&entry.descriptor.identity))
+        .map(|entry| PendingNode {
+            identity: &entry.descriptor.identity,
+            addr: entry.descriptor.addr,
+            nickname: &entry.descriptor.nickname,
+            contact: &entry.descriptor.contact,
+            family: &entry.descriptor.family,
+            registered: entry.registered,
+        })
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(pending)
+}
+
+async fn approve(
+    data: web::Data<Arc<AuthorityState>>,
+    request: HttpRequest,
+    identity: web::Path<String>,
+) -> HttpResponse {
+    data.metrics.request("admin_approve");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let identity = identity.into_inner();
+    audited(
+        &data,
+        &request,
+        AdminAction::Approve {
+            identity: identity.clone(),
+        },
+        |admin| {
+            admin.rejected.remove(&identity);
+            admin.approved.insert(identity.clone());
+        },
+    )
+    .await;
+    HttpResponse::Ok().body("Node approved")
+}
+
+/// Drops the node and refuses its registrations from now on
+async fn reject(
+    data: web::Data<Arc<AuthorityState>>,
+    request: HttpRequest,
+    identity: web::Path<String>,
+) -> HttpResponse {
+    data.metrics.request("admin_reject");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let identity = identity.into_inner();
+    // Refused before the node is dropped, so a registration in between can't bring it back
+    record(
+        &data,
+        &request,
+        AdminAction::Reject {
+            identity: identity.clone(),
+        },
+        |admin| {
+            admin.approved.remove(&identity);
+            admin.rejected.insert(identity.clone());
+        },
+    );
+    drop_nodes(&data, |entry| entry.descriptor.identity == identity).await;
+    data.save().await;
+    HttpResponse::Ok().body("Node rejected")
+}
+
+async fn change_flags(
+    data: web::Data<Arc<AuthorityState>>,
+    request: HttpRequest,
+    identity: web::Path<String>,
+    change: web::Json<FlagChange>,
+) -> HttpResponse {
+    data.metrics.request("admin_flags");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let identity = identity.into_inner();
+    let change = change.into_inner();
+    if let Some(flag) = change
+        .assign
+        .iter()
+        .find(|flag| change.remove.contains(flag) || change.reset.contains(flag))
+        .or_else(|| change.remove.intersection(&change.reset).next())
+    {
+        return HttpResponse::BadRequest().body(format!("{:?} is changed twice", flag));
+    }
+    let action = AdminAction::Flags {
+        identity: identity.clone(),
+        change: change.clone(),
+    };
+    audited(&data, &request, action, |admin| {
+        for flag in &change.assign {
+            match flag.consensus_flag() {
+                Some(flag) => {
+                    let overrides = admin.flags.entry(identity.clone()).or_default();
+                    overrides.removed.remove(&flag);
+                    overrides.assigned.insert(flag);
+                }
+                None => {
+                    admin.rejected.remove(&identity);
+                    admin.approved.insert(identity.clone());
+                }
+            }
+        }
+        for flag in &change.remove {
+            match flag.consensus_flag() {
+                Some(flag) => {
+                    let overrides = admin.flags.entry(identity.clone()).or_default();
+                    overrides.assigned.remove(&flag);
+                    overrides.removed.insert(flag);
+                }
+                None => {
+                    admin.approved.remove(&identity);
+                }
+            }
+        }
+        for flag in &change.reset {
+            match flag.consensus_flag() {
+                Some(flag) => {
+                    if let Some(overrides) = admin.flags.get_mut(&identity) {
+                        overrides.assigned.remove(&flag);
+                        overrides.removed.remove(&flag);
+                    }
+                }
+                None => {
+                    admin.approved.remove(&identity);
+                }
+            }
+        }
+        if admin.flags.get(&identity) == Some(&FlagOverrides::default()) {
+            admin.flags.remove(&identity);
+        }
+    })
+    .await;
+    HttpResponse::Ok().body("Flags changed")
+}
+
+async fn get_bans(data: web::Data<Arc<AuthorityState>>, request: HttpRequest) -> HttpResponse {
+    data.metrics.request("admin_bans");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let bans = data.admin.read().expect("Admin lock poisoned").bans.clone();
+    HttpResponse::Ok().json(bans)
+}
+
+/// Drops the matching nodes and refuses their registrations from now on
+async fn ban(
+    data: web::Data<Arc<AuthorityState>>,
+    request: HttpRequest,
+    target: web::Json<BanTarget>,
+) -> HttpResponse {
+    data.metrics.request("admin_ban");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let target = target.into_inner();
+    let action = AdminAction::Ban {
+        target: target.clone(),
+    };
+    // Banned before the nodes are dropped, so a registration in between can't bring them back
+    record(&data, &request, action, |admin| match &target {
+        BanTarget::Addr(ip) => {
+            admin.bans.addrs.insert(*ip);
+        }
+        BanTarget::Identity(identity) => {
+            admin.bans.identities.insert(identity.clone());
+        }
+    });
+    drop_nodes(&data, |entry| match &target {
+        BanTarget::Addr(ip) => entry.descriptor.addr.ip() == *ip,
+        BanTarget::Identity(identity) => entry.descriptor.identity == *identity,
+    })
+    .await;
+    data.save().await;
+    HttpResponse::Ok().body("Banned")
+}
+
+async fn unban(
+    data: web::Data<Arc<AuthorityState>>,
+    request: HttpRequest,
+    target: web::Json<BanTarget>,
+) -> HttpResponse {
+    data.metrics.request("admin_unban");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let target = target.into_inner();
+    let banned = {
+        let admin = data.admin.read().expect("Admin lock poisoned");
+        match &target {
+            BanTarget::Addr(ip) => admin.bans.addrs.contains(ip),
+            BanTarget::Identity(identity) => admin.bans.identities.contains(identity),
+        }
+    };
+    if !banned {
+        return HttpResponse::NotFound().body("Not banned");
+    }
+    let action = AdminAction::Unban {
+        target: target.clone(),
+    };
+    audited(&data, &request, action, |admin| match &target {
+        BanTarget::Addr(ip) => {
+            admin.bans.addrs.remove(ip);
+        }
+        BanTarget::Identity(identity) => {
+            admin.bans.identities.remove(identity);
+        }
+    })
+    .await;
+    HttpResponse::Ok().body("Unbanned")
+}
+
+/// Admin actions, oldest first
+async fn get_audit(data: web::Data<Arc<AuthorityState>>, request: HttpRequest) -> HttpResponse {
+    data.metrics.request("admin_audit");
+    if let Err(response) = authorize(&data, &request) {
+        return response;
+    }
+    let audit = data
+        .admin
+        .read()
+        .expect("Admin lock poisoned")
+        .audit
+        .clone();
+    HttpResponse::Ok().json(audit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_measured_flags() {
+        let mut admin = AdminState::default();
+        admin.flags.insert(
+            "node".to_string(),
+            FlagOverrides {
+                assigned: BTreeSet::from([Flag::BadExit]),
+                removed: BTreeSet::from([Flag::Guard]),
+            },
+        );
+        let mut flags = BTreeSet::from([Flag::Valid, Flag::Exit, Flag::Guard]);
+        admin.apply("node", &mut flags);
+        assert_eq!(flags, BTreeSet::from([Flag::Valid, Flag::BadExit]));
+
+        let mut flags = BTreeSet::from([Flag::Valid, Flag::Guard]);
+        admin.apply("other", &mut flags);
+        assert_eq!(flags, BTreeSet::from([Flag::Valid, Flag::Guard]));
+    }
+
+    #[test]
+    fn bans_every_port_of_an_address() {
+        let bans = Bans {
+            addrs: BTreeSet::from(["192.0.2.1".parse().unwrap()]),
+            identities: BTreeSet::from(["banned".to_string()]),
+        };
+        assert!(bans.banned("node", "192.0.2.1".parse().unwrap()));
+        assert!(bans.banned("banned", "192.0.2.2".parse().unwrap()));
+        assert!(!bans.banned("node", "192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn keeps_the_newest_audit_entries() {
+        let mut admin = AdminState::default();
+        for time in 0..MAX_AUDIT_ENTRIES as u64 + 5 {
+            admin.record(AuditEntry {
+                time,
+                source: None,
+                action: AdminAction::Approve {
+                    identity: "node".to_string(),
+                },
+            });
+        }
+        assert_eq!(admin.audit.len(), MAX_AUDIT_ENTRIES);
+        assert_eq!(admin.audit[0].time, 5);
+    }
+}
@@ -0,0 +1,75 @@
+//! Bandwidth measurements of the nodes. A node could claim any bandwidth, so the authority
+//! relays data through the node to its own echo and times it
+
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{
+    node_io::NodeIO,
+    tor::{
+        client::PendingCircuit, consensus::MAX_SELECTION_BANDWIDTH, error::TorError,
+        tor_message::TorMessage,
+    },
+};
+
+/// Size of the messages relayed to the echo
+const MESSAGE_SIZE: usize = 16 * 1024;
+/// Messages per measurement, the echo closes the connection after them
+const MESSAGES: usize = 8;
+/// Time a connection to the echo stays open at most
+const ECHO_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time between measurements of a node
+pub(super) const MEASURE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Time for a node's peak bandwidth to halve unless a measurement reaches it again
+pub(super) const PEAK_HALF_LIFE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Bytes per second the circuit's last node relays to `echo` and back
+pub(super) async fn measure(circuit: PendingCircuit, echo: SocketAddr) -> Result<u64, TorError> {
+    let elapsed = circuit.echo(echo, MESSAGE_SIZE, MESSAGES).await?;
+    let bytes = (MESSAGE_SIZE * MESSAGES) as f64;
+    Ok(((bytes / elapsed.as_secs_f64()) as u64).min(MAX_SELECTION_BANDWIDTH))
+}
+
+/// Sends the messages of the nodes being measured back through them
+pub(super) async fn serve_echo(listener: TcpListener, stop: CancellationToken) {
+    loop {
+        let stream = tokio::select! {
+            _ = stop.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Failed accepting echo connection: {:?}", err);
+                    continue;
+                }
+            },
+        };
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = stop.cancelled() => {}
+                result = timeout(ECHO_TIMEOUT, echo(stream)) => match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => debug!("Echo failed: {}", err),
+                    Err(_) => debug!("Echo timed out"),
+                },
+            }
+        });
+    }
+}
+
+async fn echo(stream: TcpStream) -> Result<(), TorError> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader: NodeIO<_, TorMessage, ()> = NodeIO::new(reader);
+    let mut writer: NodeIO<_, (), TorMessage> = NodeIO::new(writer);
+    for _ in 0..MESSAGES {
+        let message = reader.read().await?;
+        writer.node_write(message).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+//! Defenses against a single operator registering enough nodes to take over path selection
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Time a node has to answer a challenge
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+/// Issue time in milliseconds, random bytes and the MAC
+const CHALLENGE_LENGTH: usize = 8 + 16 + 32;
+
+/// Limits on how many nodes can register, counted by the address the registration came from.
+/// Only valid registrations of nodes the directory doesn't know yet count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistrationLimits {
+    /// New nodes accepted from one IP address per window
+    pub per_ip: usize,
+    /// New nodes accepted from one /24, or /48 for IPv6, per window
+    pub per_subnet: usize,
+    pub window: Duration,
+    /// Approved running nodes a family may have, further nodes can't register with it
+    pub max_family_size: usize,
+}
+
+impl Default for RegistrationLimits {
+    fn default() -> Self {
+        Self {
+            per_ip: 10,
+            per_subnet: 30,
+            window: Duration::from_secs(60 * 60),
+            max_family_size: 10,
+        }
+    }
+}
+
+/// Challenges handed out to registering nodes, each is accepted once before it expires.
+/// A challenge carries the time it was issued and the directory's MAC over it, so handing them
+/// out keeps no state and can't be exhausted. Only redeemed challenges are kept until they expire
+pub(super) struct Challenges {
+    key: [u8; 32],
+    /// Issue times are counted from here
+    started: Instant,
+    redeemed: HashMap<String, Instant>,
+}
+
+impl Default for Challenges {
+    fn default() -> Self {
+        Self {
+            key: rand::random(),
+            started: Instant::now(),
+            redeemed: HashMap::new(),
+        }
+    }
+}
+
+impl Challenges {
+    pub(super) fn issue(&self, now: Instant) -> String {
+        let issued = now.saturating_duration_since(self.started).as_millis() as u64;
+        let mut challenge = issued.to_be_bytes().to_vec();
+        challenge.extend_from_slice(&rand::random::<[u8; 16]>());
+        challenge.extend_from_slice(&self.mac(&challenge).finalize().into_bytes());
+        URL_SAFE_NO_PAD.encode(challenge)
+    }
+
+    pub(super) fn redeem(&mut self, challenge: &str, now: Instant) -> bool {
+        self.redeemed.retain(|_, expires| *expires > now);
+        let Some(expires) = self.expiry(challenge) else {
+            return false;
+        };
+        if expires <= now || self.redeemed.contains_key(challenge) {
+            return false;
+        }
+        self.redeemed.insert(challenge.to_string(), expires);
+        true
+    }
+
+    /// When a challenge this directory issued expires
+    fn expiry(&self, challenge: &str) -> Option<Instant> {
+        let challenge = URL_SAFE_NO_PAD.decode(challenge).ok()?;
+        if challenge.len() != CHALLENGE_LENGTH {
+            return None;
+        }
+        let (issued, tag) = challenge.split_at(CHALLENGE_LENGTH - 32);
+        self.mac(issued).verify_slice(tag).ok()?;
+        let issued = u64::from_be_bytes(issued[..8].try_into().expect("Has 8 bytes"));
+        Some(self.started + Duration::from_millis(issued) + CHALLENGE_TTL)
+    }
+
+    fn mac(&self, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("Any key length works");
+        mac.update(data);
+        mac
+    }
+}
+
+/// Registrations per address and subnet within a sliding window
+pub(super) struct RateLimiter {
+    limits: RegistrationLimits,
+    ips: HashMap<IpAddr, VecDeque<Instant>>,
+    subnets: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(limits: RegistrationLimits) -> Self {
+        Self {
+            limits,
+            ips: HashMap::new(),
+            subnets: HashMap::new(),
+        }
+    }
+
+    /// Counts a registration from `ip` unless the address or its subnet used up its registrations
+    pub(super) fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let window = self.limits.window;
+        for hits in [&mut self.ips, &mut self.subnets] {
+            hits.retain(|_, hits| {
+                while hits.front().is_some_and(|hit| *hit + window <= now) {
+                    hits.pop_front();
+                }
+                !hits.is_empty()
+            });
+        }
+        let subnet = subnet(ip);
+        let used = |hits: &HashMap<IpAddr, VecDeque<Instant>>, key| {
+            hits.get(&key).map_or(0, VecDeque::len)
+        };
+        if used(&self.ips, ip) >= self.limits.per_ip
+            || used(&self.subnets, subnet) >= self.limits.per_subnet
+        {
+            return false;
+        }
+        self.ips.entry(ip).or_default().push_back(now);
+        self.subnets.entry(subnet).or_default().push_back(now);
+        true
+    }
+}
+
+/// The /24 of an IPv4 address or the /48 of an IPv6 address, which one operator usually controls
+pub(super) fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) & 0xffff_ff00).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & !((1 << 80) - 1)).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_are_accepted_once() {
+        let mut challenges = Challenges::default();
+        let now = Instant::now();
+        let challenge = challenges.issue(now);
+        assert!(!challenges.redeem("forged", now));
+        assert!(challenges.redeem(&challenge, now));
+        assert!(!challenges.redeem(&challenge, now));
+
+        let expiring = challenges.issue(now);
+        assert!(!challenges.redeem(&expiring, now + CHALLENGE_TTL));
+
+        // Challenges of another directory or with a changed issue time are refused
+        let other = Challenges::default().issue(now);
+        assert!(!challenges.redeem(&other, now));
+        let mut backdated = URL_SAFE_NO_PAD.decode(challenges.issue(now)).unwrap();
+        backdated[7] ^= 1;
+        assert!(!challenges.redeem(&URL_SAFE_NO_PAD.encode(backdated), now));
+    }
+
+    #[test]
+    fn limits_registrations_per_ip_and_subnet() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(RegistrationLimits {
+            per_ip: 2,
+            per_subnet: 3,
+            window: Duration::from_secs(10),
+            ..Default::default()
+        });
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert!(limiter.allow(ip("10.0.0.1"), now));
+        assert!(limiter.allow(ip("10.0.0.1"), now));
+        assert!(!limiter.allow(ip("10.0.0.1"), now));
+        // Another address of the same /24 only gets what's left of the subnet's share
+        assert!(limiter.allow(ip("10.0.0.2"), now));
+        assert!(!limiter.allow(ip("10.0.0.3"), now));
+        assert!(limiter.allow(ip("10.0.1.1"), now));
+
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.allow(ip("10.0.0.1"), later));
+        assert!(limiter.allow(ip("10.0.0.3"), later));
+    }
+
+    #[test]
+    fn groups_addresses_by_subnet() {
+        let subnet = |ip: &str| subnet(ip.parse().unwrap()).to_string();
+        assert_eq!(subnet("192.168.7.42"), "192.168.7.0");
+        assert_eq!(subnet("2001:db8:1:2::1"), "2001:db8:1::");
+    }
+}
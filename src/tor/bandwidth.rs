@@ -0,0 +1,99 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthLimit {
+    /// Sustained rate in bytes per second
+    pub rate: u64,
+    /// Maximum bytes that can be sent at once after being idle
+    pub burst: u64,
+}
+
+struct Bucket {
+    limit: Option<BandwidthLimit>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket shared by every circuit of a node.
+/// Messages bigger than the available tokens put the bucket in debt and wait it out.
+pub struct BandwidthLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(limit: Option<BandwidthLimit>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                limit,
+                tokens: limit.map_or(0.0, |limit| limit.burst as f64),
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> Option<BandwidthLimit> {
+        self.bucket.lock().expect("Bandwidth lock poisoned").limit
+    }
+
+    pub fn set_limit(&self, limit: Option<BandwidthLimit>) {
+        let mut bucket = self.bucket.lock().expect("Bandwidth lock poisoned");
+        if let Some(limit) = limit {
+            bucket.tokens = bucket.tokens.min(limit.burst as f64);
+        }
+        bucket.limit = limit;
+    }
+
+    pub async fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("Bandwidth lock poisoned");
+            let Some(limit) = bucket.limit else {
+                return;
+            };
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.last_refill = now;
+            bucket.tokens = (bucket.tokens + elapsed * limit.rate as f64).min(limit.burst as f64);
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / limit.rate as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_rate() {
+        let limiter = BandwidthLimiter::new(Some(BandwidthLimit {
+            rate: 10_000,
+            burst: 1_000,
+        }));
+
+        let start = Instant::now();
+        // The burst goes through immediately, the rest at 10KB/s
+        limiter.consume(1_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.consume(2_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn unlimited() {
+        let limiter = BandwidthLimiter::new(None);
+        let start = Instant::now();
+        limiter.consume(usize::MAX).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
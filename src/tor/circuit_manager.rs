@@ -30,6 +30,9 @@ impl CircuitManager {
             Directional::Forward(TorMessage::NextNode { next_encrypted }) => {
                 self.connect(&next_encrypted[..])
             }
+            Directional::Forward(TorMessage::Destroy(_) | TorMessage::Connected) => {
+                anyhow::bail!("Received a backward only message from the back")
+            }
             Directional::Back(message) => self.push_response_back(message),
         }
     }
@@ -0,0 +1,245 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use super::tor_message::Next;
+
+pub type CircuitId = u64;
+
+const EVENTS_CAPACITY: usize = 256;
+
+/// Live state of a single circuit going through the node
+pub struct CircuitState {
+    id: CircuitId,
+    peer: SocketAddr,
+    opened_at: Instant,
+    next: Mutex<Option<Next>>,
+    bytes_forward: AtomicU64,
+    bytes_backward: AtomicU64,
+    queued_forward: AtomicUsize,
+    queued_backward: AtomicUsize,
+    cancellation: CancellationToken,
+}
+
+impl CircuitState {
+    pub fn id(&self) -> CircuitId {
+        self.id
+    }
+
+    pub fn next(&self) -> Option<Next> {
+        *self.next.lock().expect("Circuit lock poisoned")
+    }
+
+    pub(crate) fn set_next(&self, next: Next) {
+        *self.next.lock().expect("Circuit lock poisoned") = Some(next);
+    }
+
+    pub(crate) fn add_forward(&self, bytes: usize) {
+        self.bytes_forward
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_backward(&self, bytes: usize) {
+        self.bytes_backward
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Messages waiting to be relayed in each direction, returns the previous total
+    pub(crate) fn set_queued(&self, forward: usize, backward: usize) -> usize {
+        self.queued_forward.swap(forward, Ordering::Relaxed)
+            + self.queued_backward.swap(backward, Ordering::Relaxed)
+    }
+
+    pub fn age(&self) -> Duration {
+        self.opened_at.elapsed()
+    }
+
+    pub fn info(&self) -> CircuitInfo {
+        CircuitInfo {
+            id: self.id,
+            peer: self.peer,
+            next: self.next(),
+            age: self.opened_at.elapsed(),
+            bytes_forward: self.bytes_forward.load(Ordering::Relaxed),
+            bytes_backward: self.bytes_backward.load(Ordering::Relaxed),
+            queued_forward: self.queued_forward.load(Ordering::Relaxed),
+            queued_backward: self.queued_backward.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitInfo {
+    pub id: CircuitId,
+    /// The previous hop, or the client for the first node
+    pub peer: SocketAddr,
+    pub next: Option<Next>,
+    pub age: Duration,
+    pub bytes_forward: u64,
+    pub bytes_backward: u64,
+    pub queued_forward: usize,
+    pub queued_backward: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitEvent {
+    Opened {
+        id: CircuitId,
+        peer: SocketAddr,
+    },
+    Closed {
+        id: CircuitId,
+        bytes_forward: u64,
+        bytes_backward: u64,
+    },
+    Failed {
+        id: CircuitId,
+        reason: String,
+    },
+}
+
+/// Every circuit currently going through the node
+pub struct CircuitRegistry {
+    next_id: AtomicU64,
+    circuits: Mutex<BTreeMap<CircuitId, Arc<CircuitState>>>,
+    events: broadcast::Sender<CircuitEvent>,
+}
+
+impl Default for CircuitRegistry {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            circuits: Mutex::default(),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+}
+
+impl CircuitRegistry {
+    pub(crate) fn open(
+        &self,
+        peer: SocketAddr,
+        cancellation: CancellationToken,
+    ) -> Arc<CircuitState> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let circuit = Arc::new(CircuitState {
+            id,
+            peer,
+            opened_at: Instant::now(),
+            next: Mutex::new(None),
+            bytes_forward: AtomicU64::new(0),
+            bytes_backward: AtomicU64::new(0),
+            queued_forward: AtomicUsize::new(0),
+            queued_backward: AtomicUsize::new(0),
+            cancellation,
+        });
+        self.lock().insert(id, circuit.clone());
+        let _ = self.events.send(CircuitEvent::Opened { id, peer });
+        circuit
+    }
+
+    pub(crate) fn close(&self, id: CircuitId, error: Option<&anyhow::Error>) {
+        let Some(circuit) = self.lock().remove(&id) else {
+            return;
+        };
+        let event = match error {
+            Some(error) => CircuitEvent::Failed {
+                id,
+                reason: error.to_string(),
+            },
+            None => {
+                let info = circuit.info();
+                CircuitEvent::Closed {
+                    id,
+                    bytes_forward: info.bytes_forward,
+                    bytes_backward: info.bytes_backward,
+                }
+            }
+        };
+        let _ = self.events.send(event);
+    }
+
+    pub fn circuits(&self) -> Vec<CircuitInfo> {
+        self.lock().values().map(|circuit| circuit.info()).collect()
+    }
+
+    /// Tears down a circuit, returns false if there is no such circuit
+    pub fn destroy(&self, id: CircuitId) -> bool {
+        match self.lock().get(&id) {
+            Some(circuit) => {
+                circuit.cancellation.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CircuitEvent> {
+        self.events.subscribe()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<CircuitId, Arc<CircuitState>>> {
+        self.circuits
+            .lock()
+            .expect("Circuit registry lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_and_close() -> anyhow::Result<()> {
+        let registry = CircuitRegistry::default();
+        let mut events = registry.subscribe();
+        let peer = "127.0.0.1:1234".parse()?;
+
+        let circuit = registry.open(peer, CancellationToken::new());
+        circuit.add_forward(10);
+        circuit.add_backward(20);
+        assert_eq!(registry.circuits().len(), 1);
+
+        registry.close(circuit.id(), None);
+        assert!(registry.circuits().is_empty());
+
+        assert_eq!(
+            events.try_recv()?,
+            CircuitEvent::Opened {
+                id: circuit.id(),
+                peer
+            }
+        );
+        assert_eq!(
+            events.try_recv()?,
+            CircuitEvent::Closed {
+                id: circuit.id(),
+                bytes_forward: 10,
+                bytes_backward: 20
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn destroy_cancels_circuit() -> anyhow::Result<()> {
+        let registry = CircuitRegistry::default();
+        let cancellation = CancellationToken::new();
+        let circuit = registry.open("127.0.0.1:1234".parse()?, cancellation.clone());
+
+        assert!(!registry.destroy(circuit.id() + 1));
+        assert!(registry.destroy(circuit.id()));
+        assert!(cancellation.is_cancelled());
+        Ok(())
+    }
+}
@@ -1,101 +1,375 @@
-use log::info;
-use std::{iter, net::SocketAddr};
+use std::{
+    iter,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{debug, info, info_span, Instrument, Span};
 
 use crate::tor::onion::onion_wrap_connect_to;
 use crate::{
     encryption::{Encryptor, KeyPair},
     node_io::NodeIO,
-    tor::onion::{decrypt_onion_layers, onion_wrap_handshake},
+    tor::onion::{decrypt_onion_layers, onion_wrap_handshake, onion_wrap_tor_message},
 };
 
 use super::{
+    error::TorError,
     onion::onion_wrap_packet,
-    tor_message::{Next, TorMessage},
+    tor_message::{DestroyReason, Next, TorMessage},
 };
 type NetworkIO<T> = NodeIO<T, TorMessage, TorMessage>;
+
+/// Time limits for building a circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitTimeouts {
+    /// Time a hop gets to answer a handshake
+    pub handshake: Duration,
+    /// Time the exit gets to connect to the server
+    pub exit_connect: Duration,
+    /// Time every hop together gets to complete its handshake, excluding the exit's connect
+    pub build: Duration,
+}
+
+impl Default for CircuitTimeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(10),
+            // Longer than the default node connect timeout so the exit can report failures
+            exit_connect: Duration::from_secs(15),
+            build: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Client side circuit ids, only meaningful within this process
+static NEXT_CIRCUIT_ID: AtomicU64 = AtomicU64::new(1);
+
 pub struct TorClient<T> {
     nodes: Vec<(Encryptor, Next)>,
 
     stream: NetworkIO<T>,
+    span: Span,
 }
 
 pub async fn nodes_handshake(
-    mut nodes: Vec<SocketAddr>,
+    nodes: Vec<SocketAddr>,
+    server: SocketAddr,
+) -> Result<
+    (
+        TorClient<ReadHalf<TcpStream>>,
+        TorClient<WriteHalf<TcpStream>>,
+    ),
+    TorError,
+> {
+    nodes_handshake_with(nodes, server, CircuitTimeouts::default()).await
+}
+
+pub async fn nodes_handshake_with(
+    nodes: Vec<SocketAddr>,
     server: SocketAddr,
-) -> anyhow::Result<(
-    TorClient<ReadHalf<TcpStream>>,
-    TorClient<WriteHalf<TcpStream>>,
-)> {
-    assert!(!nodes.is_empty(), "Can't run a request on zero nodes");
-    let stream = TcpStream::connect(nodes[0]).await?;
+    timeouts: CircuitTimeouts,
+) -> Result<
+    (
+        TorClient<ReadHalf<TcpStream>>,
+        TorClient<WriteHalf<TcpStream>>,
+    ),
+    TorError,
+> {
+    prepare_circuit(nodes, timeouts)
+        .await?
+        .connect(server, timeouts)
+        .await
+}
+
+/// A circuit whose hops all completed their handshakes, the exit isn't connected yet
+pub struct PendingCircuit {
+    nodes: Vec<(Option<Encryptor>, Next)>,
+    reader: NetworkIO<ReadHalf<TcpStream>>,
+    writer: NetworkIO<WriteHalf<TcpStream>>,
+    span: Span,
+    created: Instant,
+    /// Address the circuit connects to the entry node from
+    local: SocketAddr,
+    path: Vec<SocketAddr>,
+}
+
+/// Handshakes with every node, leaving the choice of server for later
+pub async fn prepare_circuit(
+    nodes: Vec<SocketAddr>,
+    timeouts: CircuitTimeouts,
+) -> Result<PendingCircuit, TorError> {
+    if nodes.is_empty() {
+        return Err(TorError::NotEnoughNodes {
+            needed: 1,
+            available: 0,
+        });
+    }
+    let span = info_span!(
+        "circuit",
+        id = NEXT_CIRCUIT_ID.fetch_add(1, Ordering::Relaxed),
+        hops = nodes.len(),
+        local = tracing::field::Empty
+    );
+    // The hop being built when the deadline passes
+    let progress = AtomicUsize::new(1);
+    timeout(
+        timeouts.build,
+        build_circuit(nodes, timeouts, &progress, span.clone()).instrument(span.clone()),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        let hop = progress.load(Ordering::Relaxed);
+        span.in_scope(|| info!(hop, "Circuit build timed out"));
+        Err(TorError::BuildTimeout { hop })
+    })
+}
+
+async fn build_circuit(
+    addrs: Vec<SocketAddr>,
+    timeouts: CircuitTimeouts,
+    progress: &AtomicUsize,
+    span: Span,
+) -> Result<PendingCircuit, TorError> {
+    let created = Instant::now();
+    let path = addrs.clone();
+    let entry = addrs[0];
+    let stream = timeout(timeouts.handshake, TcpStream::connect(entry))
+        .await
+        .map_err(|_| TorError::HandshakeTimeout { hop: 1 })?
+        .map_err(|_| TorError::HopUnreachable { hop: 1 })?;
+    // Matches the `peer` of the entry node's circuit span
+    let local = stream.local_addr()?;
+    span.record("local", tracing::field::display(local));
     let (reader, writer) = tokio::io::split(stream);
 
     let mut reader: NetworkIO<_> = NodeIO::new(reader);
     let mut writer: NetworkIO<_> = NodeIO::new(writer);
 
-    nodes.remove(0);
-    let mut nodes = iter::repeat(None)
-        .zip(nodes.into_iter().map(Next::Node))
+    // The exit's next is a placeholder until the server is known, handshakes never read it
+    let exit = *addrs.last().expect("Isn't empty");
+    let mut nodes = addrs
+        .iter()
+        .skip(1)
+        .chain(iter::once(&exit))
+        .map(|addr| (None, Next::Node(*addr)))
         .collect::<Vec<_>>();
-    nodes.push((None, Next::Server(server)));
 
-    for i in 0..nodes.len() {
-        let my_pubkey = KeyPair::default();
+    for (i, peer) in addrs.into_iter().enumerate() {
+        let hop = i + 1;
+        progress.store(hop, Ordering::Relaxed);
+        async {
+            hop_handshake(i, &mut nodes, timeouts, &mut reader, &mut writer).await?;
+            if hop < nodes.len() {
+                writer
+                    .node_write(onion_wrap_connect_to(&nodes).unwrap())
+                    .await?;
+            }
+            Ok::<_, TorError>(())
+        }
+        .instrument(info_span!("hop", position = hop, %peer))
+        .await?;
+    }
 
-        writer
-            .node_write(
-                onion_wrap_handshake(&nodes[..], my_pubkey.initial_public_message()).unwrap(),
-            )
-            .await?;
+    Ok(PendingCircuit {
+        nodes,
+        reader,
+        writer,
+        span,
+        created,
+        local,
+        path,
+    })
+}
 
-        let encrypted_nodes = nodes
-            .iter()
-            .map(|(encryptor, _)| encryptor.as_ref())
-            .take_while(|a| a.is_some())
-            .map(|a| a.unwrap())
-            .collect::<Vec<_>>();
+/// Handshakes with hop `i` through the already established hops
+async fn hop_handshake(
+    i: usize,
+    nodes: &mut [(Option<Encryptor>, Next)],
+    timeouts: CircuitTimeouts,
+    reader: &mut NetworkIO<ReadHalf<TcpStream>>,
+    writer: &mut NetworkIO<WriteHalf<TcpStream>>,
+) -> Result<(), TorError> {
+    let hop = i + 1;
+    let my_pubkey = KeyPair::default();
 
-        info!("Waiting for handshake");
-        let TorMessage::HandShake(other_pubkey) =
-            decrypt_onion_layers(&encrypted_nodes[..], reader.read().await?)?
-        else {
-            anyhow::bail!("Expected handshake");
-        };
+    writer
+        .node_write(onion_wrap_handshake(nodes, my_pubkey.initial_public_message()).unwrap())
+        .await?;
+
+    debug!("Waiting for handshake");
+    let other_pubkey = match read_reply(nodes, reader, timeouts.handshake, hop).await? {
+        TorMessage::HandShake(other_pubkey) => other_pubkey,
+        // The previous hop failed extending the circuit to this one
+        TorMessage::Destroy(DestroyReason::RelayUnreachable) => {
+            return Err(TorError::HopUnreachable { hop })
+        }
+        TorMessage::Destroy(reason) => return Err(TorError::CircuitDestroyed(reason)),
+        _ => return Err(TorError::UnexpectedMessage("expected handshake")),
+    };
+    info!("Handshake completed");
+
+    nodes[i].0 = Some(my_pubkey.handshake(other_pubkey));
+    Ok(())
+}
 
-        let (encryptor, _) = &mut nodes[i];
+impl PendingCircuit {
+    /// Time since the circuit started building
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Nodes of the circuit, from the entry to the exit
+    pub fn path(&self) -> &[SocketAddr] {
+        &self.path
+    }
+
+    /// Has the exit connect to `server`, the circuit is then ready for data
+    pub async fn connect(
+        mut self,
+        server: SocketAddr,
+        timeouts: CircuitTimeouts,
+    ) -> Result<
+        (
+            TorClient<ReadHalf<TcpStream>>,
+            TorClient<WriteHalf<TcpStream>>,
+        ),
+        TorError,
+    > {
+        let span = self.span.clone();
+        let hop = self.nodes.len();
+        // Still the placeholder set while building
+        let (Next::Node(exit) | Next::Server(exit)) = self.nodes[hop - 1].1;
+        async {
+            self.nodes[hop - 1].1 = Next::Server(server);
+            self.writer
+                .node_write(onion_wrap_connect_to(&self.nodes).unwrap())
+                .await?;
+            match read_reply(&self.nodes, &mut self.reader, timeouts.exit_connect, hop).await? {
+                TorMessage::Connected => info!("Exit connected"),
+                TorMessage::Destroy(reason) => return Err(TorError::destroyed(reason, server)),
+                _ => return Err(TorError::UnexpectedMessage("expected connected")),
+            }
+            Ok(())
+        }
+        .instrument(info_span!(parent: &span, "hop", position = hop, peer = %exit))
+        .await?;
+
+        let reader_nodes = self
+            .nodes
+            .into_iter()
+            .map(|(encryptor, next)| (encryptor.unwrap(), next))
+            .collect::<Vec<_>>();
+        let writer_nodes = reader_nodes.clone();
 
-        *encryptor = Some(my_pubkey.handshake(other_pubkey));
+        span.in_scope(|| info!("Circuit built"));
+        Ok((
+            TorClient {
+                nodes: reader_nodes,
+                stream: self.reader,
+                span: span.clone(),
+            },
+            TorClient {
+                nodes: writer_nodes,
+                stream: self.writer,
+                span,
+            },
+        ))
+    }
 
-        writer
-            .node_write(onion_wrap_connect_to(&nodes[..]).unwrap())
+    /// Extends the circuit to `echo`, a peer that sends every message it reads back, and
+    /// returns how long `rounds` messages of `size` bytes take there and back. Directories
+    /// measure the bandwidth of nodes with it. An unspecified ip is taken to be the address
+    /// the circuit connects from
+    pub(crate) async fn echo(
+        mut self,
+        mut echo: SocketAddr,
+        size: usize,
+        rounds: usize,
+    ) -> Result<Duration, TorError> {
+        if echo.ip().is_unspecified() {
+            echo.set_ip(self.local.ip());
+        }
+        let hop = self.nodes.len();
+        self.nodes[hop - 1].1 = Next::Node(echo);
+        self.writer
+            .node_write(onion_wrap_connect_to(&self.nodes).unwrap())
             .await?;
+
+        // The echo reads the message once every hop removed its layer
+        let mut layers = self
+            .nodes
+            .iter()
+            .map(|(encryptor, next)| (encryptor.as_ref(), *next))
+            .collect::<Vec<_>>();
+        layers.push((None, Next::Node(echo)));
+        let encryptors = self
+            .nodes
+            .iter()
+            .map(|(encryptor, _)| encryptor.as_ref().expect("Every hop is handshaken"))
+            .collect::<Vec<_>>();
+
+        let started = Instant::now();
+        let send = async {
+            for _ in 0..rounds {
+                let wrapped = onion_wrap_tor_message(&layers, |_, _| TorMessage::NotForYou {
+                    data: vec![0; size],
+                });
+                self.writer.node_write(wrapped.unwrap()).await?;
+            }
+            Ok::<_, TorError>(())
+        };
+        let receive = async {
+            for _ in 0..rounds {
+                match decrypt_onion_layers(&encryptors, self.reader.read().await?)? {
+                    TorMessage::NotForYou { data } if data.len() == size => {}
+                    TorMessage::Destroy(DestroyReason::RelayUnreachable) => {
+                        return Err(TorError::HopUnreachable { hop: hop + 1 })
+                    }
+                    TorMessage::Destroy(reason) => return Err(TorError::CircuitDestroyed(reason)),
+                    _ => return Err(TorError::UnexpectedMessage("expected the echo")),
+                }
+            }
+            Ok(())
+        };
+        tokio::try_join!(send, receive)?;
+        Ok(started.elapsed())
     }
+}
 
-    let reader_nodes = nodes
-        .into_iter()
-        .map(|(encryptor, next)| (encryptor.unwrap(), next))
+/// Reads the next message and removes the layers of the hops handshaken so far
+async fn read_reply(
+    nodes: &[(Option<Encryptor>, Next)],
+    reader: &mut NetworkIO<ReadHalf<TcpStream>>,
+    duration: Duration,
+    hop: usize,
+) -> Result<TorMessage, TorError> {
+    let encrypted_nodes = nodes
+        .iter()
+        .map_while(|(encryptor, _)| encryptor.as_ref())
         .collect::<Vec<_>>();
 
-    let writer_nodes = reader_nodes.clone();
-
-    Ok((
-        TorClient {
-            nodes: reader_nodes,
-            stream: reader,
-        },
-        TorClient {
-            nodes: writer_nodes,
-            stream: writer,
-        },
-    ))
+    let message = timeout(duration, reader.read())
+        .await
+        .map_err(|_| TorError::HandshakeTimeout { hop })??;
+    decrypt_onion_layers(&encrypted_nodes[..], message)
 }
+
 impl<T> TorClient<T>
 where
     T: AsyncWrite + Unpin,
 {
-    pub async fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+    /// Shuts the connection to the entry node down, which closes the circuit
+    pub async fn shutdown(&mut self) -> Result<(), TorError> {
+        self.stream.shutdown().await
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), TorError> {
+        self.span
+            .in_scope(|| debug!(bytes = data.len(), "Writing to circuit"));
         self.stream
             .node_write(onion_wrap_packet(&self.nodes[..], data).expect("Isn't empty"))
             .await?;
@@ -107,7 +381,8 @@ impl<T> TorClient<T>
 where
     T: AsyncRead + Unpin,
 {
-    pub async fn read(&mut self) -> anyhow::Result<Vec<u8>> {
+    /// Reads the next message, an empty message means the server closed the connection
+    pub async fn read(&mut self) -> Result<Vec<u8>, TorError> {
         let message = self.stream.read().await?;
 
         let encryptors = self
@@ -116,11 +391,30 @@ where
             .map(|(encryptor, _)| encryptor)
             .collect::<Vec<_>>();
 
-        let TorMessage::NotForYou { data: message } =
-            decrypt_onion_layers(&encryptors[..], message)?
-        else {
-            anyhow::bail!("Invalid response")
-        };
-        Ok(message)
+        match decrypt_onion_layers(&encryptors[..], message)? {
+            TorMessage::NotForYou { data: message } => {
+                self.span
+                    .in_scope(|| debug!(bytes = message.len(), "Read from circuit"));
+                Ok(message)
+            }
+            TorMessage::Destroy(DestroyReason::Finished) => {
+                self.span.in_scope(|| info!("Server closed the connection"));
+                Ok(Vec::new())
+            }
+            TorMessage::Destroy(reason) => {
+                self.span
+                    .in_scope(|| info!(%reason, "Circuit was destroyed by a node"));
+                Err(TorError::destroyed(reason, self.server()))
+            }
+            _ => Err(TorError::UnexpectedMessage("invalid response")),
+        }
+    }
+}
+
+impl<T> TorClient<T> {
+    fn server(&self) -> SocketAddr {
+        match self.nodes.last().expect("Circuit has nodes").1 {
+            Next::Node(addr) | Next::Server(addr) => addr,
+        }
     }
 }
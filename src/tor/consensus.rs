@@ -0,0 +1,757 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    descriptor::{NodeDescriptor, SignedDescriptor},
+    keys::encode_key,
+};
+
+/// Time a consensus stays valid after it was signed
+pub const CONSENSUS_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How far the directory's clock may be ahead of the client's
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// Bandwidth nodes measured below it are selected with, so new nodes get some traffic
+pub const MIN_SELECTION_BANDWIDTH: u64 = 16 * 1024;
+/// Bandwidth nodes measured above it are selected with, so no node draws all the traffic
+pub const MAX_SELECTION_BANDWIDTH: u64 = 1 << 30;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConsensusError {
+    #[error("Consensus is signed by {signed} known authorities, {threshold} are required")]
+    TooFewSignatures { signed: usize, threshold: usize },
+    #[error("{authority} isn't a known authority")]
+    UnknownAuthority { authority: String },
+    #[error("Invalid signature by {authority}")]
+    BadSignature { authority: String },
+    #[error("Malformed consensus")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Consensus is only valid after {valid_after}")]
+    NotYetValid { valid_after: u64 },
+    #[error("Consensus expired at {valid_until}")]
+    Expired { valid_until: u64 },
+    #[error("Directory picked {identity}, which isn't a matching node of the consensus")]
+    BadSelection { identity: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Flag {
+    /// Usable by clients
+    Valid,
+    /// Completed a handshake in the directory's last probe
+    Running,
+    /// Exits to common web ports
+    Exit,
+    /// Known and reachable for long enough to carry long lived circuits
+    Stable,
+    /// Stable and fast enough to be an entry guard
+    Guard,
+    /// Flagged by an admin for misbehaving as an exit, clients never exit through it
+    BadExit,
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for Flag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Flag::Valid,
+            Flag::Running,
+            Flag::Exit,
+            Flag::Stable,
+            Flag::Guard,
+            Flag::BadExit,
+        ]
+        .into_iter()
+        .find(|flag| flag.to_string().eq_ignore_ascii_case(s.trim()))
+        .ok_or_else(|| anyhow::anyhow!("Unknown flag {:?}", s))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusNode {
+    pub addr: SocketAddr,
+    pub identity: String,
+    /// Digest of the node's signed descriptor, see [`SignedDescriptor::digest`]
+    pub descriptor: String,
+    pub flags: BTreeSet<Flag>,
+    /// Ports the node exits to for any address, as in its descriptor
+    #[serde(default)]
+    pub exit_ports: Vec<RangeInclusive<u16>>,
+    /// Bytes per second the authorities measured relaying through the node, the lower median of
+    /// their votes
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+}
+
+impl ConsensusNode {
+    pub fn new(
+        descriptor: &SignedDescriptor,
+        node: &NodeDescriptor,
+        flags: BTreeSet<Flag>,
+        bandwidth: Option<u64>,
+    ) -> Self {
+        Self {
+            addr: node.addr,
+            identity: node.identity.clone(),
+            descriptor: descriptor.digest(),
+            flags,
+            exit_ports: node.exit_ports.clone(),
+            bandwidth,
+        }
+    }
+
+    /// Whether clients may exit to `port` through the node, never for a [`Flag::BadExit`]
+    pub fn allows_exit_port(&self, port: u16) -> bool {
+        !self.flags.contains(&Flag::BadExit)
+            && self.exit_ports.iter().any(|ports| ports.contains(&port))
+    }
+}
+
+/// Nodes a client asks the directory for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeQuery {
+    pub amount: usize,
+    /// Flags every node needs on top of [`Flag::Valid`]
+    pub flags: BTreeSet<Flag>,
+    /// Port every node has to exit to
+    pub port: Option<u16>,
+    /// Identities never picked
+    pub exclude: BTreeSet<String>,
+}
+
+impl NodeQuery {
+    pub fn matches(&self, node: &ConsensusNode) -> bool {
+        node.flags.contains(&Flag::Valid)
+            && self.flags.is_subset(&node.flags)
+            && self.port.is_none_or(|port| node.allows_exit_port(port))
+            && !self.exclude.contains(&node.identity)
+    }
+}
+
+/// Nodes the directory picked for a query, they have to be listed in the consensus
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeSelection {
+    pub consensus: SignedConsensus,
+    /// Identities of the picked nodes
+    pub nodes: Vec<String>,
+}
+
+/// The directory's view of the network, times are unix seconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Consensus {
+    pub valid_after: u64,
+    pub valid_until: u64,
+    pub nodes: Vec<ConsensusNode>,
+}
+
+impl Consensus {
+    /// A consensus valid for [`CONSENSUS_LIFETIME`] from `now`
+    pub fn new(nodes: Vec<ConsensusNode>, now: SystemTime) -> Self {
+        Self::valid_after(nodes, unix_secs(now))
+    }
+
+    fn valid_after(nodes: Vec<ConsensusNode>, valid_after: u64) -> Self {
+        Self {
+            valid_after,
+            valid_until: valid_after + CONSENSUS_LIFETIME.as_secs(),
+            nodes,
+        }
+    }
+
+    /// Merges the votes for the period starting at `valid_after`, every authority computes the
+    /// same document from the same votes. A node is listed when a majority of all `authorities`
+    /// lists it, with the address and descriptor most of them agree on, and gets the flags a
+    /// majority of the authorities listing it assigned. `votes` must be of distinct authorities.
+    pub fn from_votes<'a>(
+        votes: impl IntoIterator<Item = &'a Vote>,
+        authorities: usize,
+        valid_after: u64,
+    ) -> Self {
+        let majority = authorities / 2 + 1;
+        let mut listings = BTreeMap::<&str, Vec<&ConsensusNode>>::new();
+        for vote in votes {
+            if vote.valid_after != valid_after {
+                continue;
+            }
+            // A vote listing a node more than once still counts once
+            let mut listed = BTreeSet::new();
+            for node in &vote.nodes {
+                if listed.insert(&node.identity) {
+                    listings.entry(&node.identity).or_default().push(node);
+                }
+            }
+        }
+
+        let mut nodes = listings
+            .into_iter()
+            .filter(|(_, listed)| listed.len() >= majority)
+            .map(|(identity, listed)| {
+                let mut variants = BTreeMap::<(SocketAddr, &str), (usize, &ConsensusNode)>::new();
+                let mut flags = BTreeMap::<Flag, usize>::new();
+                for node in &listed {
+                    variants
+                        .entry((node.addr, &node.descriptor))
+                        .or_insert((0, node))
+                        .0 += 1;
+                    for flag in &node.flags {
+                        *flags.entry(*flag).or_default() += 1;
+                    }
+                }
+                let mut bandwidths = listed
+                    .iter()
+                    .filter_map(|node| node.bandwidth)
+                    .collect::<Vec<_>>();
+                bandwidths.sort();
+                // Ties go to the lowest variant so every authority picks the same
+                let ((addr, descriptor), (_, variant)) = variants
+                    .into_iter()
+                    .max_by_key(|(variant, (count, _))| (*count, Reverse(*variant)))
+                    .expect("Listed nodes have a variant");
+                ConsensusNode {
+                    addr,
+                    identity: identity.to_string(),
+                    descriptor: descriptor.to_string(),
+                    flags: flags
+                        .into_iter()
+                        .filter(|(_, count)| count * 2 > listed.len())
+                        .map(|(flag, _)| flag)
+                        .collect(),
+                    exit_ports: variant.exit_ports.clone(),
+                    // The lower median, so a single authority can't inflate it
+                    bandwidth: bandwidths
+                        .get(bandwidths.len().saturating_sub(1) / 2)
+                        .copied(),
+                }
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by(|a, b| (a.addr, &a.identity).cmp(&(b.addr, &b.identity)));
+        Self::valid_after(nodes, valid_after)
+    }
+
+    pub fn sign(&self, key: &SigningKey) -> SignedConsensus {
+        let document = serde_json::to_string(self).expect("Consensus is serializable");
+        SignedConsensus {
+            signatures: vec![sign(key, &document)],
+            document,
+        }
+    }
+
+    /// Up to `query.amount` distinct nodes matching the query, picked at random with
+    /// probabilities proportional to their bandwidth
+    pub fn select<R: Rng + ?Sized>(&self, query: &NodeQuery, rng: &mut R) -> Vec<&ConsensusNode> {
+        let candidates = self
+            .nodes
+            .iter()
+            .filter(|node| query.matches(node))
+            .collect::<Vec<_>>();
+        candidates
+            .choose_multiple_weighted(rng, query.amount, |node| selection_weight(node.bandwidth))
+            .expect("Weights are positive")
+            .copied()
+            .collect()
+    }
+
+    pub fn valid_nodes(&self) -> impl Iterator<Item = &ConsensusNode> {
+        self.nodes
+            .iter()
+            .filter(|node| node.flags.contains(&Flag::Valid))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthoritySignature {
+    /// Encoded ed25519 key of the authority
+    pub authority: String,
+    /// Encoded ed25519 signature over the document
+    pub signature: String,
+}
+
+/// A consensus as served by the directory, `document` holds the exact bytes that were signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedConsensus {
+    pub document: String,
+    pub signatures: Vec<AuthoritySignature>,
+}
+
+impl SignedConsensus {
+    /// Accepts the consensus if at least `threshold` of `authorities` signed it and it's valid at
+    /// `now`
+    pub fn verify(
+        &self,
+        authorities: &[VerifyingKey],
+        threshold: usize,
+        now: SystemTime,
+    ) -> Result<Consensus, ConsensusError> {
+        let mut signed = BTreeSet::new();
+        for signature in &self.signatures {
+            let Some(authority) = authorities
+                .iter()
+                .find(|authority| encode_key(authority) == signature.authority)
+            else {
+                continue;
+            };
+            verify(authority, &self.document, signature)?;
+            signed.insert(&signature.authority);
+        }
+        let threshold = threshold.max(1);
+        if signed.len() < threshold {
+            return Err(ConsensusError::TooFewSignatures {
+                signed: signed.len(),
+                threshold,
+            });
+        }
+
+        let consensus: Consensus = serde_json::from_str(&self.document)?;
+        let now = unix_secs(now);
+        if consensus.valid_after > now + CLOCK_SKEW.as_secs() {
+            return Err(ConsensusError::NotYetValid {
+                valid_after: consensus.valid_after,
+            });
+        }
+        if consensus.valid_until <= now {
+            return Err(ConsensusError::Expired {
+                valid_until: consensus.valid_until,
+            });
+        }
+        Ok(consensus)
+    }
+
+    /// Adds another authority's signature over the same document, it has to be verified already
+    pub fn add_signature(&mut self, signature: AuthoritySignature) {
+        if !self
+            .signatures
+            .iter()
+            .any(|existing| existing.authority == signature.authority)
+        {
+            self.signatures.push(signature);
+        }
+    }
+}
+
+/// One authority's view of the network for the period starting at `valid_after`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vote {
+    /// Encoded ed25519 key of the authority
+    pub authority: String,
+    pub valid_after: u64,
+    pub nodes: Vec<ConsensusNode>,
+}
+
+impl Vote {
+    pub fn sign(&self, key: &SigningKey) -> SignedVote {
+        let document = serde_json::to_string(self).expect("Vote is serializable");
+        SignedVote {
+            signature: sign(key, &document).signature,
+            document,
+        }
+    }
+}
+
+/// A vote as exchanged between authorities, `document` holds the exact bytes that were signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedVote {
+    pub document: String,
+    pub signature: String,
+}
+
+impl SignedVote {
+    /// Accepts the vote if the authority it names is one of `authorities` and signed it
+    pub fn verify(&self, authorities: &[VerifyingKey]) -> Result<Vote, ConsensusError> {
+        let vote: Vote = serde_json::from_str(&self.document)?;
+        let authority = authorities
+            .iter()
+            .find(|authority| encode_key(authority) == vote.authority)
+            .ok_or_else(|| ConsensusError::UnknownAuthority {
+                authority: vote.authority.clone(),
+            })?;
+        verify(
+            authority,
+            &self.document,
+            &AuthoritySignature {
+                authority: vote.authority.clone(),
+                signature: self.signature.clone(),
+            },
+        )?;
+        Ok(vote)
+    }
+}
+
+fn sign(key: &SigningKey, document: &str) -> AuthoritySignature {
+    AuthoritySignature {
+        authority: encode_key(&key.verifying_key()),
+        signature: URL_SAFE_NO_PAD.encode(key.sign(document.as_bytes()).to_bytes()),
+    }
+}
+
+fn verify(
+    authority: &VerifyingKey,
+    document: &str,
+    signature: &AuthoritySignature,
+) -> Result<(), ConsensusError> {
+    let bad_signature = || ConsensusError::BadSignature {
+        authority: signature.authority.clone(),
+    };
+    let bytes = URL_SAFE_NO_PAD
+        .decode(&signature.signature)
+        .map_err(|_| bad_signature())?;
+    let parsed = Signature::from_slice(&bytes).map_err(|_| bad_signature())?;
+    authority
+        .verify_strict(document.as_bytes(), &parsed)
+        .map_err(|_| bad_signature())
+}
+
+/// Weight of a node with the measured `bandwidth` when picking nodes at random
+pub fn selection_weight(bandwidth: Option<u64>) -> f64 {
+    bandwidth
+        .unwrap_or(0)
+        .clamp(MIN_SELECTION_BANDWIDTH, MAX_SELECTION_BANDWIDTH) as f64
+}
+
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .expect("Clock is after the epoch")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn node(addr: &str, flags: BTreeSet<Flag>) -> ConsensusNode {
+        ConsensusNode {
+            addr: addr.parse().unwrap(),
+            identity: addr.to_string(),
+            descriptor: String::new(),
+            flags,
+            exit_ports: vec![],
+            bandwidth: None,
+        }
+    }
+
+    fn consensus(now: SystemTime) -> Consensus {
+        Consensus::new(
+            vec![
+                node("10.0.0.1:9001", BTreeSet::from([Flag::Valid])),
+                node("10.1.0.1:9001", BTreeSet::new()),
+            ],
+            now,
+        )
+    }
+
+    #[test]
+    fn accepts_consensus_of_pinned_authority() {
+        let key = SigningKey::generate(&mut OsRng);
+        let now = SystemTime::now();
+        let consensus = consensus(now);
+        let signed = consensus.sign(&key);
+
+        let verified = signed.verify(&[key.verifying_key()], 1, now).unwrap();
+        assert_eq!(verified, consensus);
+        assert_eq!(
+            verified
+                .valid_nodes()
+                .map(|node| node.addr)
+                .collect::<Vec<_>>(),
+            vec!["10.0.0.1:9001".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn rejects_forged_consensus() {
+        let key = SigningKey::generate(&mut OsRng);
+        let attacker = SigningKey::generate(&mut OsRng);
+        let now = SystemTime::now();
+
+        let signed = consensus(now).sign(&attacker);
+        assert!(matches!(
+            signed.verify(&[key.verifying_key()], 1, now),
+            Err(ConsensusError::TooFewSignatures { signed: 0, .. })
+        ));
+
+        // A valid signature over a different document
+        let mut tampered = consensus(now).sign(&key);
+        tampered.document = tampered.document.replace("10.0.0.1", "10.6.6.6");
+        assert!(matches!(
+            tampered.verify(&[key.verifying_key()], 1, now),
+            Err(ConsensusError::BadSignature { .. })
+        ));
+
+        // The attacker claims to be the authority
+        let mut impersonated = consensus(now).sign(&attacker);
+        impersonated.signatures[0].authority = encode_key(&key.verifying_key());
+        assert!(matches!(
+            impersonated.verify(&[key.verifying_key()], 1, now),
+            Err(ConsensusError::BadSignature { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_stale_consensus() {
+        let key = SigningKey::generate(&mut OsRng);
+        let now = SystemTime::now();
+        let signed = consensus(now).sign(&key);
+        let authorities = [key.verifying_key()];
+
+        assert!(signed
+            .verify(
+                &authorities,
+                1,
+                now + CONSENSUS_LIFETIME - Duration::from_secs(1)
+            )
+            .is_ok());
+        assert!(matches!(
+            signed.verify(&authorities, 1, now + CONSENSUS_LIFETIME),
+            Err(ConsensusError::Expired { .. })
+        ));
+        assert!(signed.verify(&authorities, 1, now - CLOCK_SKEW).is_ok());
+        assert!(matches!(
+            signed.verify(&authorities, 1, now - CLOCK_SKEW - Duration::from_secs(1)),
+            Err(ConsensusError::NotYetValid { .. })
+        ));
+    }
+
+    #[test]
+    fn requires_threshold_of_authorities() {
+        let keys = [(); 3].map(|_| SigningKey::generate(&mut OsRng));
+        let authorities = keys.each_ref().map(SigningKey::verifying_key);
+        let now = SystemTime::now();
+        let mut signed = consensus(now).sign(&keys[0]);
+
+        assert!(matches!(
+            signed.verify(&authorities, 2, now),
+            Err(ConsensusError::TooFewSignatures {
+                signed: 1,
+                threshold: 2
+            })
+        ));
+        // Repeated signatures of one authority count once
+        signed.signatures.push(signed.signatures[0].clone());
+        assert!(signed.verify(&authorities, 2, now).is_err());
+
+        let other = consensus(now).sign(&keys[1]);
+        signed.add_signature(other.signatures[0].clone());
+        assert!(signed.verify(&authorities, 2, now).is_ok());
+    }
+
+    #[test]
+    fn merges_votes_by_majority() {
+        let keys = [(); 3].map(|_| SigningKey::generate(&mut OsRng));
+        let authorities = keys.each_ref().map(SigningKey::verifying_key);
+        let valid = || BTreeSet::from([Flag::Valid]);
+        let vote = |key: &SigningKey, nodes| {
+            Vote {
+                authority: encode_key(&key.verifying_key()),
+                valid_after: 1000,
+                nodes,
+            }
+            .sign(key)
+        };
+        let mut moved = node("10.3.0.1:9001", valid());
+        moved.identity = "10.2.0.1:9001".to_string();
+        let votes = [
+            vote(
+                &keys[0],
+                vec![
+                    node("10.0.0.1:9001", valid()),
+                    node("10.1.0.1:9001", valid()),
+                    node("10.2.0.1:9001", valid()),
+                ],
+            ),
+            vote(
+                &keys[1],
+                vec![
+                    node("10.0.0.1:9001", BTreeSet::new()),
+                    node("10.1.0.1:9001", valid()),
+                    moved.clone(),
+                ],
+            ),
+            vote(&keys[2], vec![node("10.0.0.1:9001", valid()), moved]),
+        ]
+        .map(|signed| signed.verify(&authorities).unwrap());
+
+        let consensus = Consensus::from_votes(&votes, 3, 1000);
+        assert_eq!(
+            consensus.nodes,
+            vec![
+                node("10.0.0.1:9001", valid()),
+                node("10.1.0.1:9001", valid()),
+                // Two of three saw the node at its new address
+                ConsensusNode {
+                    identity: "10.2.0.1:9001".to_string(),
+                    ..node("10.3.0.1:9001", valid())
+                },
+            ]
+        );
+        // Any order of the votes gives the same document
+        let mut reversed = votes.clone();
+        reversed.reverse();
+        assert_eq!(Consensus::from_votes(&reversed, 3, 1000), consensus);
+
+        // Two authorities don't list the node without a majority of all three
+        let consensus = Consensus::from_votes(&votes[1..], 3, 1000);
+        assert_eq!(consensus.nodes.len(), 2);
+        assert!(consensus.nodes[0].flags.is_empty());
+
+        let forged = vote(&SigningKey::generate(&mut OsRng), vec![]);
+        assert!(matches!(
+            forged.verify(&authorities),
+            Err(ConsensusError::UnknownAuthority { .. })
+        ));
+    }
+
+    #[test]
+    fn merges_bandwidth_to_lower_median() {
+        let votes = [Some(1000), Some(u64::MAX), None].map(|bandwidth| Vote {
+            authority: String::new(),
+            valid_after: 1000,
+            nodes: vec![ConsensusNode {
+                bandwidth,
+                ..node("10.0.0.1:9001", BTreeSet::from([Flag::Valid]))
+            }],
+        });
+
+        let consensus = Consensus::from_votes(&votes, 3, 1000);
+        // One authority can't inflate the bandwidth
+        assert_eq!(consensus.nodes[0].bandwidth, Some(1000));
+    }
+
+    #[test]
+    fn counts_one_listing_per_vote() {
+        let listed = || node("10.0.0.1:9001", BTreeSet::from([Flag::Valid, Flag::Exit]));
+        let votes = [
+            vec![listed(), listed(), listed()],
+            vec![node("10.1.0.1:9001", BTreeSet::new())],
+            vec![],
+        ]
+        .map(|nodes| Vote {
+            authority: String::new(),
+            valid_after: 1000,
+            nodes,
+        });
+
+        // One authority listing the node three times isn't a majority of three
+        let consensus = Consensus::from_votes(&votes, 3, 1000);
+        assert!(consensus.nodes.is_empty());
+
+        // nor does it outvote the flags the other authority assigned
+        let mut votes = votes;
+        votes[1].nodes.push(node("10.0.0.1:9001", BTreeSet::new()));
+        let consensus = Consensus::from_votes(&votes, 3, 1000);
+        assert_eq!(consensus.nodes.len(), 1);
+        assert!(consensus.nodes[0].flags.is_empty());
+    }
+
+    #[test]
+    fn selects_matching_nodes() {
+        let exit = |addr| ConsensusNode {
+            exit_ports: vec![443..=443],
+            ..node(addr, BTreeSet::from([Flag::Valid, Flag::Exit, Flag::Guard]))
+        };
+        let consensus = Consensus::new(
+            vec![
+                exit("10.0.0.1:9001"),
+                exit("10.1.0.1:9001"),
+                node("10.2.0.1:9001", BTreeSet::from([Flag::Valid, Flag::Guard])),
+                node("10.3.0.1:9001", BTreeSet::from([Flag::Exit])),
+            ],
+            SystemTime::now(),
+        );
+        let select = |query: NodeQuery| {
+            let mut picked = consensus
+                .select(&query, &mut OsRng)
+                .into_iter()
+                .map(|node| node.identity.as_str())
+                .collect::<Vec<_>>();
+            picked.sort();
+            picked
+        };
+
+        assert_eq!(
+            select(NodeQuery {
+                amount: 10,
+                ..Default::default()
+            }),
+            ["10.0.0.1:9001", "10.1.0.1:9001", "10.2.0.1:9001"]
+        );
+        assert_eq!(
+            select(NodeQuery {
+                amount: 10,
+                flags: BTreeSet::from([Flag::Exit]),
+                port: Some(443),
+                exclude: BTreeSet::from(["10.1.0.1:9001".to_string()]),
+            }),
+            ["10.0.0.1:9001"]
+        );
+        assert!(select(NodeQuery {
+            amount: 10,
+            port: Some(80),
+            ..Default::default()
+        })
+        .is_empty());
+        assert_eq!(
+            select(NodeQuery {
+                amount: 2,
+                flags: BTreeSet::from([Flag::Guard]),
+                ..Default::default()
+            })
+            .len(),
+            2
+        );
+
+        let mut bad_exit = exit("10.4.0.1:9001");
+        bad_exit.flags.insert(Flag::BadExit);
+        assert!(!bad_exit.allows_exit_port(443));
+    }
+
+    #[test]
+    fn selects_proportionally_to_bandwidth() {
+        let nodes = (1..=4)
+            .map(|i| ConsensusNode {
+                bandwidth: Some(i * MIN_SELECTION_BANDWIDTH),
+                ..node(&format!("10.0.0.{}:9001", i), BTreeSet::from([Flag::Valid]))
+            })
+            .collect();
+        let consensus = Consensus::new(nodes, SystemTime::now());
+        let query = NodeQuery {
+            amount: 1,
+            ..Default::default()
+        };
+
+        let draws = 20_000;
+        let mut picks = BTreeMap::<&str, usize>::new();
+        for _ in 0..draws {
+            let picked = consensus.select(&query, &mut OsRng);
+            *picks.entry(&picked[0].identity).or_default() += 1;
+        }
+        for (i, node) in consensus.nodes.iter().enumerate() {
+            let share = picks[node.identity.as_str()] as f64 / draws as f64;
+            let expected = (i + 1) as f64 / 10.0;
+            assert!(
+                (share - expected).abs() < 0.02,
+                "{} picked {:.3} of the time, expected {:.1}",
+                node.identity,
+                share,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Line based control protocol for inspecting and managing a running node.
+//!
+//! Every connection has to start with `AUTHENTICATE <password>`. Replies follow the
+//! tor control port conventions: `250 OK` on success, `250-` prefixed lines for multi
+//! line replies, `5xx` for errors and `650` for asynchronous events.
+//!
+//! Commands:
+//! - `CIRCUITS` lists every circuit with its age, byte counts and queued messages
+//! - `CLOSE <id>` tears down a circuit
+//! - `STATS` shows node wide counters
+//! - `LOGLEVEL <level>` changes the maximum log level
+//! - `SUBSCRIBE` starts sending circuit opened, closed and failed events
+//! - `QUIT` closes the connection
+
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, level_filters::LevelFilter};
+
+use crate::logging;
+
+use super::{
+    circuit_registry::{CircuitEvent, CircuitRegistry},
+    node_server::NodeStats,
+};
+
+#[derive(Debug, Clone)]
+pub struct ControlConfig {
+    pub address: SocketAddr,
+    pub password: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct ControlContext {
+    pub(crate) password: Arc<str>,
+    pub(crate) circuits: Arc<CircuitRegistry>,
+    pub(crate) stats: Arc<NodeStats>,
+}
+
+pub(crate) async fn serve(
+    listener: TcpListener,
+    context: ControlContext,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Failed accepting control connection: {:?}", err);
+                    continue;
+                }
+            }
+        };
+        info!("New control connection from {}", addr);
+
+        let context = context.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = shutdown.cancelled() => {},
+                result = handle_control_connection(stream, context) => {
+                    if let Err(err) = result {
+                        error!("Control connection failed: {:?}", err);
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn passwords_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn handle_control_connection(
+    stream: TcpStream,
+    context: ControlContext,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    // Replies and events are interleaved through a single writer
+    let (replies, mut replies_receiver) = mpsc::channel::<String>(64);
+    let writer_task = tokio::spawn(async move {
+        while let Some(reply) = replies_receiver.recv().await {
+            writer.write_all(reply.as_bytes()).await?;
+            writer.write_all(b"\r\n").await?;
+        }
+        Ok::<_, std::io::Error>(())
+    });
+
+    let mut authenticated = false;
+    let mut subscription = None;
+
+    while let Some(line) = lines.next_line().await? {
+        let (command, argument) = line
+            .trim()
+            .split_once(' ')
+            .map_or((line.trim(), ""), |(command, argument)| {
+                (command, argument.trim())
+            });
+        let command = command.to_ascii_uppercase();
+
+        if !authenticated && command != "AUTHENTICATE" && command != "QUIT" {
+            replies
+                .send("514 Authentication required".to_string())
+                .await?;
+            continue;
+        }
+
+        let reply = match command.as_str() {
+            "AUTHENTICATE" => {
+                if passwords_match(argument, &context.password) {
+                    authenticated = true;
+                    vec!["250 OK".to_string()]
+                } else {
+                    vec!["515 Authentication failed".to_string()]
+                }
+            }
+            "CIRCUITS" => {
+                let mut reply = context
+                    .circuits
+                    .circuits()
+                    .into_iter()
+                    .map(|circuit| {
+                        format!(
+                            "250-{} peer={} next={} age={} forward={} backward={} queued_forward={} queued_backward={}",
+                            circuit.id,
+                            circuit.peer,
+                            circuit
+                                .next
+                                .map_or("none".to_string(), |next| format!("{:?}", next)),
+                            circuit.age.as_secs(),
+                            circuit.bytes_forward,
+                            circuit.bytes_backward,
+                            circuit.queued_forward,
+                            circuit.queued_backward,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                reply.push("250 OK".to_string());
+                reply
+            }
+            "CLOSE" => match argument.parse() {
+                Ok(id) if context.circuits.destroy(id) => vec!["250 OK".to_string()],
+                Ok(id) => vec![format!("552 Unknown circuit {}", id)],
+                Err(_) => vec![format!("512 Invalid circuit id {:?}", argument)],
+            },
+            "STATS" => {
+                let stats = context.stats.snapshot();
+                vec![
+                    format!("250-circuits_opened={}", stats.circuits_opened),
+                    format!("250-circuits_failed={}", stats.circuits_failed),
+                    format!("250-circuits_active={}", stats.circuits_active),
+                    format!("250-bytes_forward={}", stats.bytes_forward),
+                    format!("250-bytes_backward={}", stats.bytes_backward),
+                    format!("250-decrypt_failures={}", stats.decrypt_failures),
+                    format!("250-queued_messages={}", stats.queued_messages),
+                    "250 OK".to_string(),
+                ]
+            }
+            "LOGLEVEL" => match argument.parse::<LevelFilter>() {
+                Ok(level) => match logging::set_level(level) {
+                    Ok(()) => vec!["250 OK".to_string()],
+                    Err(err) => vec![format!("551 {}", err)],
+                },
+                Err(_) => vec![format!("512 Invalid log level {:?}", argument)],
+            },
+            "SUBSCRIBE" => {
+                if subscription.is_none() {
+                    subscription = Some(tokio::spawn(forward_events(
+                        context.circuits.subscribe(),
+                        replies.clone(),
+                    )));
+                }
+                vec!["250 OK".to_string()]
+            }
+            "QUIT" => {
+                replies.send("250 closing connection".to_string()).await?;
+                break;
+            }
+            _ => vec![format!("510 Unrecognized command {:?}", command)],
+        };
+
+        for line in reply {
+            replies.send(line).await?;
+        }
+    }
+
+    if let Some(subscription) = subscription {
+        subscription.abort();
+    }
+    drop(replies);
+    writer_task.await??;
+    Ok(())
+}
+
+async fn forward_events(
+    mut events: broadcast::Receiver<CircuitEvent>,
+    replies: mpsc::Sender<String>,
+) {
+    loop {
+        let line = match events.recv().await {
+            Ok(CircuitEvent::Opened { id, peer }) => {
+                format!("650 CIRCUIT {} OPENED peer={}", id, peer)
+            }
+            Ok(CircuitEvent::Closed {
+                id,
+                bytes_forward,
+                bytes_backward,
+            }) => format!(
+                "650 CIRCUIT {} CLOSED forward={} backward={}",
+                id, bytes_forward, bytes_backward
+            ),
+            Ok(CircuitEvent::Failed { id, reason }) => {
+                format!("650 CIRCUIT {} FAILED reason={:?}", id, reason)
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                format!("650 LAGGED missed={}", missed)
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if replies.send(line).await.is_err() {
+            break;
+        }
+    }
+}
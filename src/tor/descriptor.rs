@@ -0,0 +1,528 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{
+    bandwidth::BandwidthLimit,
+    consensus::unix_secs,
+    exit_policy::ExitPolicy,
+    keys::{self, encode_key, parse_key},
+};
+
+/// Version of the relay protocol this build speaks
+pub const PROTOCOL_VERSION: u16 = 1;
+pub(crate) const MAX_NICKNAME_LENGTH: usize = 19;
+/// How far the node's clock may be ahead of the directory's
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum DescriptorError {
+    #[error("Malformed descriptor")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Invalid identity key")]
+    BadIdentity,
+    #[error("Descriptor isn't signed by its identity key")]
+    BadSignature,
+    #[error("Nickname {0:?} must be 1-19 ascii letters or digits")]
+    BadNickname(String),
+    #[error("Descriptor was published in the future at {0}")]
+    FromTheFuture(u64),
+    #[error("Heartbeat sent at {0} is too old")]
+    Stale(u64),
+    #[error("Registration isn't signed by the descriptor's identity key")]
+    BadRegistration,
+    #[error("Deregistration isn't signed by the node's identity key")]
+    BadDeregistration,
+}
+
+pub(crate) fn valid_nickname(nickname: &str) -> bool {
+    !nickname.is_empty()
+        && nickname.len() <= MAX_NICKNAME_LENGTH
+        && nickname.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Long term keys of a node, the identity signs its descriptors
+#[derive(Clone)]
+pub struct NodeKeys {
+    identity: SigningKey,
+    onion: StaticSecret,
+}
+
+impl fmt::Debug for NodeKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeKeys")
+            .field("identity", &self.identity())
+            .finish_non_exhaustive()
+    }
+}
+
+impl NodeKeys {
+    pub fn generate() -> Self {
+        Self {
+            identity: SigningKey::generate(&mut OsRng),
+            onion: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// Keys kept in `dir` across restarts, generated on first use
+    pub fn load_or_generate(dir: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            identity: keys::load_or_generate_signing_key(&dir.join("identity.key"))?,
+            onion: StaticSecret::from(keys::load_or_generate_secret(&dir.join("onion.key"))?),
+        })
+    }
+
+    pub fn identity(&self) -> String {
+        encode_key(&self.identity.verifying_key())
+    }
+}
+
+/// What a node publishes about itself besides its address and settings
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub keys: NodeKeys,
+    pub nickname: String,
+    pub contact: Option<String>,
+    pub family: Option<String>,
+}
+
+impl Default for NodeInfo {
+    fn default() -> Self {
+        Self {
+            keys: NodeKeys::generate(),
+            nickname: "Unnamed".to_string(),
+            contact: None,
+            family: None,
+        }
+    }
+}
+
+impl NodeInfo {
+    pub fn descriptor(
+        &self,
+        addr: SocketAddr,
+        exit_policy: &ExitPolicy,
+        bandwidth: Option<BandwidthLimit>,
+        now: SystemTime,
+    ) -> SignedDescriptor {
+        NodeDescriptor {
+            addr,
+            identity: self.keys.identity(),
+            onion_key: URL_SAFE_NO_PAD.encode(PublicKey::from(&self.keys.onion).as_bytes()),
+            nickname: self.nickname.clone(),
+            contact: self.contact.clone(),
+            bandwidth: bandwidth.map(|bandwidth| bandwidth.rate),
+            exit_ports: exit_policy.accepted_ports(),
+            protocols: vec![PROTOCOL_VERSION],
+            family: self.family.clone(),
+            published: unix_secs(now),
+        }
+        .sign(&self.keys.identity)
+    }
+
+    /// Proves to a directory that the node holds the descriptor's identity key by signing the
+    /// `challenge` the directory issued
+    pub fn register(&self, descriptor: SignedDescriptor, challenge: &str) -> Registration {
+        let (_, signature) = sign(
+            &self.keys.identity,
+            &registration_document(challenge, &descriptor),
+        );
+        Registration {
+            descriptor,
+            challenge: challenge.to_string(),
+            signature,
+        }
+    }
+
+    /// Proves to a directory that the node at `addr` asks to be removed by signing the
+    /// `challenge` the directory issued
+    pub fn deregister(&self, addr: SocketAddr, challenge: &str) -> Deregistration {
+        let (_, signature) = sign(&self.keys.identity, &("deregistration", challenge, addr));
+        Deregistration {
+            addr,
+            identity: self.keys.identity(),
+            challenge: challenge.to_string(),
+            signature,
+        }
+    }
+
+    /// A heartbeat reporting the node's current load
+    pub fn heartbeat(
+        &self,
+        addr: SocketAddr,
+        circuits: u64,
+        bandwidth: u64,
+        now: SystemTime,
+    ) -> SignedHeartbeat {
+        let heartbeat = Heartbeat {
+            addr,
+            identity: self.keys.identity(),
+            circuits,
+            bandwidth,
+            sent: unix_secs(now),
+        };
+        let (heartbeat, signature) = sign(&self.keys.identity, &heartbeat);
+        SignedHeartbeat {
+            heartbeat,
+            signature,
+        }
+    }
+}
+
+/// The document serialized and the encoded signature over it
+fn sign<T: Serialize>(key: &SigningKey, document: &T) -> (String, String) {
+    let document = serde_json::to_string(document).expect("Document is serializable");
+    let signature = key.sign(document.as_bytes());
+    (document, URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+}
+
+/// Checks that `identity` signed `document`
+fn verify(identity: &str, document: &str, signature: &str) -> Result<(), DescriptorError> {
+    let identity = parse_key(identity).map_err(|_| DescriptorError::BadIdentity)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(DescriptorError::BadSignature)?;
+    identity
+        .verify_strict(document.as_bytes(), &signature)
+        .map_err(|_| DescriptorError::BadSignature)
+}
+
+/// What a registration's signature covers, binding the challenge to the descriptor
+fn registration_document<'a>(
+    challenge: &'a str,
+    descriptor: &SignedDescriptor,
+) -> (&'static str, &'a str, String) {
+    ("registration", challenge, descriptor.digest())
+}
+
+/// A descriptor uploaded in answer to a directory's challenge
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    pub descriptor: SignedDescriptor,
+    pub challenge: String,
+    /// Identity key's signature over the challenge and the descriptor's digest
+    pub signature: String,
+}
+
+impl Registration {
+    /// Checks the descriptor and that its identity key signed the challenge, whether the
+    /// directory issued the challenge is up to the directory
+    pub fn verify(&self, now: SystemTime) -> Result<NodeDescriptor, DescriptorError> {
+        let descriptor = self.descriptor.verify(now)?;
+        let document =
+            serde_json::to_string(&registration_document(&self.challenge, &self.descriptor))?;
+        verify(&descriptor.identity, &document, &self.signature)
+            .map_err(|_| DescriptorError::BadRegistration)?;
+        Ok(descriptor)
+    }
+}
+
+/// A request to remove the node at `addr`, answering a directory's challenge
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Deregistration {
+    pub addr: SocketAddr,
+    pub identity: String,
+    pub challenge: String,
+    /// Identity key's signature over the challenge and the address
+    pub signature: String,
+}
+
+impl Deregistration {
+    /// Checks that `identity` signed the challenge, whether it's the identity registered at
+    /// `addr` and the directory issued the challenge is up to the directory
+    pub fn verify(&self) -> Result<(), DescriptorError> {
+        let document = serde_json::to_string(&("deregistration", &self.challenge, self.addr))?;
+        verify(&self.identity, &document, &self.signature)
+            .map_err(|_| DescriptorError::BadDeregistration)
+    }
+}
+
+/// A node as described by itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeDescriptor {
+    pub addr: SocketAddr,
+    /// Encoded ed25519 key the descriptor is signed with
+    pub identity: String,
+    /// Encoded x25519 onion key, circuit handshakes are still unauthenticated and don't use it yet
+    pub onion_key: String,
+    pub nickname: String,
+    pub contact: Option<String>,
+    /// Bytes per second the node relays at most, unlimited when missing
+    pub bandwidth: Option<u64>,
+    /// Ports the exit policy accepts for any address
+    pub exit_ports: Vec<RangeInclusive<u16>>,
+    /// Relay protocol versions the node speaks
+    pub protocols: Vec<u16>,
+    /// Nodes of one operator share a family
+    pub family: Option<String>,
+    /// Unix seconds
+    pub published: u64,
+}
+
+impl NodeDescriptor {
+    fn sign(&self, key: &SigningKey) -> SignedDescriptor {
+        let (descriptor, signature) = sign(key, self);
+        SignedDescriptor {
+            descriptor,
+            signature,
+        }
+    }
+
+    pub fn allows_exit_port(&self, port: u16) -> bool {
+        self.exit_ports.iter().any(|ports| ports.contains(&port))
+    }
+}
+
+/// A descriptor as uploaded to the directory, `descriptor` holds the exact bytes that were signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedDescriptor {
+    pub descriptor: String,
+    pub signature: String,
+}
+
+impl SignedDescriptor {
+    /// Checks the signature by the identity in the descriptor and its fields
+    pub fn verify(&self, now: SystemTime) -> Result<NodeDescriptor, DescriptorError> {
+        let descriptor: NodeDescriptor = serde_json::from_str(&self.descriptor)?;
+        verify(&descriptor.identity, &self.descriptor, &self.signature)?;
+
+        if !valid_nickname(&descriptor.nickname) {
+            return Err(DescriptorError::BadNickname(descriptor.nickname));
+        }
+        if descriptor.published > unix_secs(now) + CLOCK_SKEW.as_secs() {
+            return Err(DescriptorError::FromTheFuture(descriptor.published));
+        }
+        Ok(descriptor)
+    }
+
+    /// Hash the consensus refers to the descriptor by
+    pub fn digest(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.descriptor.as_bytes()))
+    }
+}
+
+/// Proof that a node is still up, sent to the directories between descriptor uploads
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub addr: SocketAddr,
+    pub identity: String,
+    /// Circuits open when the heartbeat was sent
+    pub circuits: u64,
+    /// Bytes per second relayed since the previous heartbeat
+    pub bandwidth: u64,
+    /// Unix seconds
+    pub sent: u64,
+}
+
+/// A heartbeat signed by the node's identity key, `heartbeat` holds the exact bytes that were
+/// signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedHeartbeat {
+    pub heartbeat: String,
+    pub signature: String,
+}
+
+impl SignedHeartbeat {
+    /// Checks the signature and that the heartbeat was sent around `now`. Directories also
+    /// refuse heartbeats that aren't newer than the node's last one, and count a node as heard
+    /// from when its heartbeat was sent, so replays can't keep a dead node listed
+    pub fn verify(&self, now: SystemTime) -> Result<Heartbeat, DescriptorError> {
+        let heartbeat: Heartbeat = serde_json::from_str(&self.heartbeat)?;
+        verify(&heartbeat.identity, &self.heartbeat, &self.signature)?;
+
+        let now = unix_secs(now);
+        if heartbeat.sent > now + CLOCK_SKEW.as_secs() {
+            return Err(DescriptorError::FromTheFuture(heartbeat.sent));
+        }
+        if heartbeat.sent + CLOCK_SKEW.as_secs() < now {
+            return Err(DescriptorError::Stale(heartbeat.sent));
+        }
+        Ok(heartbeat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(info: &NodeInfo, now: SystemTime) -> SignedDescriptor {
+        info.descriptor(
+            "10.0.0.1:9001".parse().unwrap(),
+            &"accept *:443, reject *:*".parse().unwrap(),
+            Some(BandwidthLimit {
+                rate: 1000,
+                burst: 2000,
+            }),
+            now,
+        )
+    }
+
+    #[test]
+    fn verifies_self_signed_descriptor() {
+        let info = NodeInfo {
+            nickname: "relay1".to_string(),
+            family: Some("alice".to_string()),
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+        let descriptor = descriptor(&info, now).verify(now).unwrap();
+
+        assert_eq!(descriptor.identity, info.keys.identity());
+        assert_eq!(descriptor.bandwidth, Some(1000));
+        assert_eq!(descriptor.protocols, vec![PROTOCOL_VERSION]);
+        assert!(descriptor.allows_exit_port(443));
+        assert!(!descriptor.allows_exit_port(80));
+    }
+
+    #[test]
+    fn rejects_tampered_descriptor() {
+        let info = NodeInfo::default();
+        let now = SystemTime::now();
+
+        let mut tampered = descriptor(&info, now);
+        tampered.descriptor = tampered.descriptor.replace("10.0.0.1", "10.6.6.6");
+        assert!(matches!(
+            tampered.verify(now),
+            Err(DescriptorError::BadSignature)
+        ));
+
+        // Signed by a different key than the identity it claims
+        let other = NodeInfo::default();
+        let mut impersonated = descriptor(&other, now);
+        impersonated.descriptor = impersonated
+            .descriptor
+            .replace(&other.keys.identity(), &info.keys.identity());
+        assert!(matches!(
+            impersonated.verify(now),
+            Err(DescriptorError::BadSignature)
+        ));
+        assert_ne!(impersonated.digest(), descriptor(&other, now).digest());
+    }
+
+    #[test]
+    fn validates_fields() {
+        let now = SystemTime::now();
+        let unnamed = NodeInfo {
+            nickname: "not a nickname".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            descriptor(&unnamed, now).verify(now),
+            Err(DescriptorError::BadNickname(_))
+        ));
+
+        let later = now + CLOCK_SKEW + Duration::from_secs(1);
+        assert!(matches!(
+            descriptor(&NodeInfo::default(), later).verify(now),
+            Err(DescriptorError::FromTheFuture(_))
+        ));
+    }
+
+    #[test]
+    fn verifies_fresh_heartbeats() {
+        let info = NodeInfo::default();
+        let addr = "10.0.0.1:9001".parse().unwrap();
+        let now = SystemTime::now();
+        let heartbeat = info.heartbeat(addr, 3, 1000, now).verify(now).unwrap();
+        assert_eq!(heartbeat.identity, info.keys.identity());
+        assert_eq!((heartbeat.circuits, heartbeat.bandwidth), (3, 1000));
+
+        let mut forged = info.heartbeat(addr, 3, 1000, now);
+        forged.heartbeat = forged.heartbeat.replace("\"circuits\":3", "\"circuits\":0");
+        assert!(matches!(
+            forged.verify(now),
+            Err(DescriptorError::BadSignature)
+        ));
+
+        let replayed = info.heartbeat(addr, 3, 1000, now - CLOCK_SKEW - Duration::from_secs(1));
+        assert!(matches!(
+            replayed.verify(now),
+            Err(DescriptorError::Stale(_))
+        ));
+    }
+
+    #[test]
+    fn keys_survive_restarts() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("rustor-node-keys-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir)?;
+        let keys = NodeKeys::load_or_generate(&dir)?;
+        let reloaded = NodeKeys::load_or_generate(&dir)?;
+        assert_eq!(reloaded.identity(), keys.identity());
+        assert_eq!(
+            PublicKey::from(&reloaded.onion),
+            PublicKey::from(&keys.onion)
+        );
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn registration_proves_key_possession() {
+        let info = NodeInfo::default();
+        let now = SystemTime::now();
+        let registration = info.register(descriptor(&info, now), "challenge");
+        assert_eq!(
+            registration.verify(now).unwrap().identity,
+            info.keys.identity()
+        );
+
+        // A signature over one challenge can't answer another
+        let replayed = Registration {
+            challenge: "other".to_string(),
+            ..registration.clone()
+        };
+        assert!(matches!(
+            replayed.verify(now),
+            Err(DescriptorError::BadRegistration)
+        ));
+
+        // Someone else's descriptor can't be registered without its key
+        let other = NodeInfo::default();
+        let stolen = Registration {
+            descriptor: descriptor(&other, now),
+            ..registration
+        };
+        assert!(matches!(
+            stolen.verify(now),
+            Err(DescriptorError::BadRegistration)
+        ));
+    }
+
+    #[test]
+    fn deregistration_proves_key_possession() {
+        let info = NodeInfo::default();
+        let deregistration = info.deregister("10.0.0.1:9001".parse().unwrap(), "challenge");
+        assert!(deregistration.verify().is_ok());
+
+        // The signature covers the address
+        let moved = Deregistration {
+            addr: "10.0.0.2:9001".parse().unwrap(),
+            ..deregistration.clone()
+        };
+        assert!(matches!(
+            moved.verify(),
+            Err(DescriptorError::BadDeregistration)
+        ));
+        let impersonated = Deregistration {
+            identity: NodeInfo::default().keys.identity(),
+            ..deregistration
+        };
+        assert!(matches!(
+            impersonated.verify(),
+            Err(DescriptorError::BadDeregistration)
+        ));
+    }
+}
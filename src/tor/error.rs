@@ -0,0 +1,93 @@
+use std::{io, net::SocketAddr};
+
+use super::{consensus::ConsensusError, tor_message::DestroyReason};
+
+/// Errors returned by the client side of the tor library
+#[derive(Debug, thiserror::Error)]
+pub enum TorError {
+    #[error("Directory unavailable")]
+    DirectoryUnavailable(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Directory sent an untrusted consensus")]
+    ConsensusRejected(#[from] ConsensusError),
+    #[error("Failed resolving {host}")]
+    ResolveFailed {
+        host: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Not enough nodes, needed {needed} but only {available} are available")]
+    NotEnoughNodes { needed: usize, available: usize },
+    #[error("No relay satisfies the path constraints for hop {hop}")]
+    NoEligibleRelay { hop: usize },
+    #[error("Timed out waiting for hop {hop}")]
+    HandshakeTimeout { hop: usize },
+    #[error("Hop {hop} is unreachable")]
+    HopUnreachable { hop: usize },
+    #[error("Circuit took too long to build, stopped at hop {hop}")]
+    BuildTimeout { hop: usize },
+    #[error("Failed decrypting the layer of hop {hop}")]
+    HopDecryptFailed { hop: usize },
+    #[error("Exit connection to {0} was refused")]
+    ExitConnectRefused(SocketAddr),
+    #[error("Exit can't reach {0}")]
+    HostUnreachable(SocketAddr),
+    #[error("Exit policy rejects connecting to {0}")]
+    PolicyRejected(SocketAddr),
+    #[error("Circuit was destroyed by a node: {0}")]
+    CircuitDestroyed(DestroyReason),
+    #[error("Unexpected message: {0}")]
+    UnexpectedMessage(&'static str),
+    #[error("Invalid message")]
+    Serialization(#[from] bincode::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl TorError {
+    /// Error for a circuit destroyed by a node, `server` is the circuit's destination
+    pub fn destroyed(reason: DestroyReason, server: SocketAddr) -> Self {
+        match reason {
+            DestroyReason::ExitPolicy => TorError::PolicyRejected(server),
+            DestroyReason::ConnectRefused => TorError::ExitConnectRefused(server),
+            DestroyReason::HostUnreachable | DestroyReason::ConnectTimeout => {
+                TorError::HostUnreachable(server)
+            }
+            DestroyReason::Teardown | DestroyReason::RelayUnreachable | DestroyReason::Finished => {
+                TorError::CircuitDestroyed(reason)
+            }
+        }
+    }
+
+    /// The hop responsible for the failure, counting from 1 at the entry
+    pub fn failed_hop(&self) -> Option<usize> {
+        match self {
+            TorError::HandshakeTimeout { hop }
+            | TorError::HopUnreachable { hop }
+            | TorError::BuildTimeout { hop }
+            | TorError::HopDecryptFailed { hop } => Some(*hop),
+            _ => None,
+        }
+    }
+
+    /// Short name of the variant, used as a metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TorError::DirectoryUnavailable(_) => "directory_unavailable",
+            TorError::ConsensusRejected(_) => "consensus_rejected",
+            TorError::ResolveFailed { .. } => "resolve_failed",
+            TorError::NotEnoughNodes { .. } => "not_enough_nodes",
+            TorError::NoEligibleRelay { .. } => "no_eligible_relay",
+            TorError::HandshakeTimeout { .. } => "handshake_timeout",
+            TorError::HopUnreachable { .. } => "hop_unreachable",
+            TorError::BuildTimeout { .. } => "build_timeout",
+            TorError::HopDecryptFailed { .. } => "hop_decrypt_failed",
+            TorError::ExitConnectRefused(_) => "exit_connect_refused",
+            TorError::HostUnreachable(_) => "host_unreachable",
+            TorError::PolicyRejected(_) => "policy_rejected",
+            TorError::CircuitDestroyed(_) => "circuit_destroyed",
+            TorError::UnexpectedMessage(_) => "unexpected_message",
+            TorError::Serialization(_) => "serialization",
+            TorError::Io(_) => "io",
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    Accept,
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrPattern {
+    Any,
+    Network { addr: IpAddr, prefix: u8 },
+}
+
+impl AddrPattern {
+    fn matches(&self, ip: IpAddr) -> bool {
+        match (self, ip) {
+            (AddrPattern::Any, _) => true,
+            (
+                AddrPattern::Network {
+                    addr: IpAddr::V4(net),
+                    prefix,
+                },
+                IpAddr::V4(ip),
+            ) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(ip) & mask
+            }
+            (
+                AddrPattern::Network {
+                    addr: IpAddr::V6(net),
+                    prefix,
+                },
+                IpAddr::V6(ip),
+            ) => {
+                let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A single `accept|reject <addr>[/<prefix>]:<port>[-<port>]` line, `*` matches anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitRule {
+    pub action: ExitAction,
+    pub addr: AddrPattern,
+    pub ports: RangeInclusive<u16>,
+}
+
+impl ExitRule {
+    fn matches(&self, target: &SocketAddr) -> bool {
+        self.addr.matches(target.ip()) && self.ports.contains(&target.port())
+    }
+}
+
+impl FromStr for ExitRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (action, target) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .with_context(|| format!("Exit rule {:?} must look like \"accept *:80\"", s))?;
+
+        let action = match action {
+            "accept" => ExitAction::Accept,
+            "reject" => ExitAction::Reject,
+            other => anyhow::bail!("Unknown exit rule action {:?}", other),
+        };
+
+        let (addr, ports) = target
+            .trim()
+            .rsplit_once(':')
+            .with_context(|| format!("Exit rule {:?} is missing a port", s))?;
+
+        let addr = match addr.replace(['[', ']'], "").as_str() {
+            "*" => AddrPattern::Any,
+            addr => {
+                let (ip, prefix) = match addr.split_once('/') {
+                    Some((ip, prefix)) => (ip, Some(prefix)),
+                    None => (addr, None),
+                };
+                let ip: IpAddr = ip
+                    .parse()
+                    .with_context(|| format!("Invalid address in exit rule {:?}", s))?;
+                let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|prefix| *prefix <= max_prefix)
+                        .with_context(|| format!("Invalid prefix in exit rule {:?}", s))?,
+                    None => max_prefix,
+                };
+                AddrPattern::Network { addr: ip, prefix }
+            }
+        };
+
+        let ports = match ports {
+            "*" => 0..=u16::MAX,
+            ports => {
+                let parse = |port: &str| {
+                    port.parse::<u16>()
+                        .with_context(|| format!("Invalid port in exit rule {:?}", s))
+                };
+                match ports.split_once('-') {
+                    Some((start, end)) => parse(start)?..=parse(end)?,
+                    None => parse(ports)?..=parse(ports)?,
+                }
+            }
+        };
+
+        Ok(ExitRule {
+            action,
+            addr,
+            ports,
+        })
+    }
+}
+
+impl fmt::Display for ExitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            ExitAction::Accept => "accept",
+            ExitAction::Reject => "reject",
+        };
+        write!(f, "{} ", action)?;
+        match self.addr {
+            AddrPattern::Any => write!(f, "*")?,
+            AddrPattern::Network {
+                addr: IpAddr::V4(addr),
+                prefix,
+            } => write!(f, "{}/{}", addr, prefix)?,
+            AddrPattern::Network {
+                addr: IpAddr::V6(addr),
+                prefix,
+            } => write!(f, "[{}]/{}", addr, prefix)?,
+        }
+        if self.ports == (0..=u16::MAX) {
+            write!(f, ":*")
+        } else if self.ports.start() == self.ports.end() {
+            write!(f, ":{}", self.ports.start())
+        } else {
+            write!(f, ":{}-{}", self.ports.start(), self.ports.end())
+        }
+    }
+}
+
+/// Ordered list of exit rules, the first matching rule wins.
+/// Targets that match no rule are accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct ExitPolicy {
+    rules: Vec<ExitRule>,
+}
+
+impl ExitPolicy {
+    pub fn new(rules: Vec<ExitRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn reject_all() -> Self {
+        Self::new(vec![ExitRule {
+            action: ExitAction::Reject,
+            addr: AddrPattern::Any,
+            ports: 0..=u16::MAX,
+        }])
+    }
+
+    pub fn rules(&self) -> &[ExitRule] {
+        &self.rules
+    }
+
+    pub fn allows(&self, target: &SocketAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(target))
+            .is_none_or(|rule| rule.action == ExitAction::Accept)
+    }
+
+    /// Ports accepted for any address, the summary published in the node's descriptor.
+    /// Rules for specific networks are left out, so a port can still be rejected for some hosts.
+    pub fn accepted_ports(&self) -> Vec<RangeInclusive<u16>> {
+        let general = self
+            .rules
+            .iter()
+            .filter(|rule| rule.addr == AddrPattern::Any)
+            .collect::<Vec<_>>();
+        // Every port range between two boundaries is decided by the same rule
+        let mut boundaries = general
+            .iter()
+            .flat_map(|rule| {
+                [
+                    u32::from(*rule.ports.start()),
+                    u32::from(*rule.ports.end()) + 1,
+                ]
+            })
+            .chain([0, u32::from(u16::MAX) + 1])
+            .collect::<Vec<_>>();
+        boundaries.sort();
+        boundaries.dedup();
+
+        let mut accepted: Vec<RangeInclusive<u16>> = vec![];
+        for range in boundaries.windows(2) {
+            let (start, end) = (range[0] as u16, (range[1] - 1) as u16);
+            let accepts = general
+                .iter()
+                .find(|rule| rule.ports.contains(&start))
+                .is_none_or(|rule| rule.action == ExitAction::Accept);
+            if !accepts {
+                continue;
+            }
+            match accepted.last_mut() {
+                Some(last) if u32::from(*last.end()) + 1 == u32::from(start) => {
+                    *last = *last.start()..=end
+                }
+                _ => accepted.push(start..=end),
+            }
+        }
+        accepted
+    }
+}
+
+impl FromStr for ExitPolicy {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of rules
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s
+            .split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(str::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(rules))
+    }
+}
+
+impl TryFrom<Vec<String>> for ExitPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(rules: Vec<String>) -> Result<Self, Self::Error> {
+        let rules = rules
+            .iter()
+            .map(|rule| rule.parse())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(rules))
+    }
+}
+
+impl From<ExitPolicy> for Vec<String> {
+    fn from(policy: ExitPolicy) -> Self {
+        policy.rules.iter().map(ToString::to_string).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_wins() -> anyhow::Result<()> {
+        let policy: ExitPolicy = "accept 10.0.0.1:80, reject 10.0.0.0/8:*, accept *:443".parse()?;
+
+        assert!(policy.allows(&"10.0.0.1:80".parse()?));
+        assert!(!policy.allows(&"10.0.0.1:81".parse()?));
+        assert!(!policy.allows(&"10.1.2.3:443".parse()?));
+        assert!(policy.allows(&"1.1.1.1:443".parse()?));
+        assert!(policy.allows(&"1.1.1.1:22".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn port_ranges_and_ipv6() -> anyhow::Result<()> {
+        let policy: ExitPolicy = "reject [::1]/128:1-1024, reject *:6660-6669".parse()?;
+
+        assert!(!policy.allows(&"[::1]:22".parse()?));
+        assert!(policy.allows(&"[::1]:8080".parse()?));
+        assert!(!policy.allows(&"1.1.1.1:6667".parse()?));
+        assert!(policy.allows(&"1.1.1.1:6670".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn summarizes_accepted_ports() -> anyhow::Result<()> {
+        let policy: ExitPolicy =
+            "reject 10.0.0.0/8:*, accept *:80, accept *:443-444, accept *:445, reject *:*"
+                .parse()?;
+        assert_eq!(policy.accepted_ports(), vec![80..=80, 443..=445]);
+
+        let policy: ExitPolicy = "reject *:25, accept 10.0.0.1:22, reject *:1-1023".parse()?;
+        assert_eq!(policy.accepted_ports(), vec![0..=0, 1024..=u16::MAX]);
+
+        assert_eq!(ExitPolicy::default().accepted_ports(), vec![0..=u16::MAX]);
+        assert!(ExitPolicy::reject_all().accepted_ports().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn display_roundtrip() -> anyhow::Result<()> {
+        for rule in [
+            "accept *:*",
+            "reject 10.0.0.0/8:22",
+            "accept [::1]/128:80-90",
+        ] {
+            let parsed: ExitRule = rule.parse()?;
+            assert_eq!(parsed.to_string(), rule);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rule in [
+            "allow *:*",
+            "accept *",
+            "accept 10.0.0.0/33:1",
+            "accept *:99999",
+        ] {
+            assert!(rule.parse::<ExitRule>().is_err(), "{}", rule);
+        }
+    }
+}
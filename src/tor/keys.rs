@@ -0,0 +1,81 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::Context;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+
+/// Url safe base64 of an ed25519 public key, also used as the node's identity
+pub fn encode_key(key: &VerifyingKey) -> String {
+    URL_SAFE_NO_PAD.encode(key.as_bytes())
+}
+
+/// Parses a key written by [`encode_key`]
+pub fn parse_key(key: &str) -> anyhow::Result<VerifyingKey> {
+    Ok(VerifyingKey::from_bytes(&decode_32(key)?)?)
+}
+
+/// Decodes url safe base64, or the padded standard base64 keys used to be written in
+pub(crate) fn decode_32(encoded: &str) -> anyhow::Result<[u8; 32]> {
+    let encoded = encoded.trim();
+    URL_SAFE_NO_PAD
+        .decode(encoded)
+        .or_else(|err| STANDARD.decode(encoded).map_err(|_| err))?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Key isn't 32 bytes long"))
+}
+
+/// Reads the secret key at `path`, generating and saving one only the owner can read when it
+/// doesn't exist
+pub fn load_or_generate_secret(path: &Path) -> anyhow::Result<[u8; 32]> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            decode_32(&content).with_context(|| format!("Invalid key in {}", path.display()))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let mut secret = [0u8; 32];
+            OsRng.fill_bytes(&mut secret);
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(path)
+                .and_then(|mut file| file.write_all(URL_SAFE_NO_PAD.encode(secret).as_bytes()))
+                .with_context(|| format!("Failed creating {}", path.display()))?;
+            Ok(secret)
+        }
+        Err(err) => Err(err).context(format!("Failed reading {}", path.display())),
+    }
+}
+
+pub fn load_or_generate_signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&load_or_generate_secret(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_kept() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("rustor-key-{}", rand::random::<u64>()));
+        let key = load_or_generate_signing_key(&path)?;
+        assert_eq!(load_or_generate_signing_key(&path)?, key);
+        assert_eq!(
+            parse_key(&encode_key(&key.verifying_key()))?,
+            key.verifying_key()
+        );
+        assert!(parse_key("not a key").is_err());
+        // Keys written before the switch to url safe base64 still load
+        let standard = STANDARD.encode(key.verifying_key().as_bytes());
+        assert_eq!(parse_key(&standard)?, key.verifying_key());
+        fs::write(&path, STANDARD.encode(key.as_bytes()))?;
+        assert_eq!(load_or_generate_signing_key(&path)?, key);
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
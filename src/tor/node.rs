@@ -1,11 +1,14 @@
-use log::{error, info};
+use std::{io, sync::Arc};
+
 use serde::de::DeserializeOwned;
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite},
     net::TcpStream,
     sync::mpsc,
+    time::{error::Elapsed, timeout},
 };
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, Instrument, Span};
 
 use crate::{
     node_io::NodeIO,
@@ -13,59 +16,123 @@ use crate::{
 };
 
 use super::{
+    bandwidth::BandwidthLimiter,
     circuit_manager::CircuitManager,
-    tor_message::{Next, TorMessage},
+    circuit_registry::CircuitState,
+    node_server::{NodeSettings, NodeStats},
+    tor_message::{DestroyReason, Next, TorMessage},
 };
 
-pub async fn handle_connection(stream: TcpStream) -> anyhow::Result<()> {
-    let cancellation_token = CancellationToken::new();
+/// Everything a circuit shares with the rest of the node
+pub(crate) struct CircuitContext {
+    pub(crate) settings: Arc<NodeSettings>,
+    pub(crate) stats: Arc<NodeStats>,
+    pub(crate) bandwidth: Arc<BandwidthLimiter>,
+    pub(crate) circuit: Arc<CircuitState>,
+}
+
+impl CircuitContext {
+    async fn relay_forward(&self, bytes: usize) {
+        self.bandwidth.consume(bytes).await;
+        self.circuit.add_forward(bytes);
+        self.stats.bytes_forward.inc_by(bytes as u64);
+    }
+
+    async fn relay_backward(&self, bytes: usize) {
+        self.bandwidth.consume(bytes).await;
+        self.circuit.add_backward(bytes);
+        self.stats.bytes_backward.inc_by(bytes as u64);
+    }
+
+    fn set_queued(&self, forward: usize, backward: usize) {
+        let previous = self.circuit.set_queued(forward, backward);
+        self.stats
+            .queued_messages
+            .add((forward + backward) as i64 - previous as i64);
+    }
+}
 
+pub(crate) async fn handle_connection(
+    stream: TcpStream,
+    context: CircuitContext,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
     let (back_read, back_write) = tokio::io::split(stream);
     let back_write: NodeIO<_, (), TorMessage> = NodeIO::new(back_write);
 
     let back_node_reader: NodeIO<_, TorMessage, ()> = NodeIO::new(back_read);
     let (back_sender, back_receiver) = mpsc::channel(10);
 
-    tokio::spawn(reader_task(
-        back_node_reader,
-        cancellation_token.clone(),
-        back_sender,
-    ));
+    tokio::spawn(
+        reader_task(back_node_reader, cancellation_token.clone(), back_sender).in_current_span(),
+    );
 
-    tor_node(cancellation_token, back_write, back_receiver).await?;
+    tor_node(cancellation_token, &context, back_write, back_receiver).await?;
 
     Ok(())
 }
 
 async fn start_forward_connection(
     next: Next,
+    context: &CircuitContext,
     cancellation_token: &CancellationToken,
 ) -> anyhow::Result<ForwardStream> {
-    let (front_read, forward_write) = tokio::io::split(match next {
-        Next::Node(n) => TcpStream::connect(n).await?,
-        Next::Server(n) => TcpStream::connect(n).await?,
-    });
+    let addr = match next {
+        Next::Node(n) => n,
+        Next::Server(n) => {
+            if !context.settings.exit_policy.allows(&n) {
+                return Err(anyhow::Error::new(DestroyReason::ExitPolicy)
+                    .context(format!("Exit policy rejects connecting to {}", n)));
+            }
+            n
+        }
+    };
+    let stream = timeout(
+        context.settings.limits.connect_timeout,
+        TcpStream::connect(addr),
+    )
+    .await??;
+    // Lets the next hop's `peer` be matched with this circuit
+    Span::current().record(
+        "forward_local",
+        tracing::field::display(stream.local_addr()?),
+    );
+    let (front_read, forward_write) = tokio::io::split(stream);
 
     let front_write: NodeIO<_, (), TorMessage> = NodeIO::new(forward_write);
 
     let (front_sender, front_receiver) = mpsc::channel(10);
 
     if next.is_server() {
-        tokio::spawn(server_reader_task(
-            front_read,
-            cancellation_token.clone(),
-            front_sender,
-        ));
+        tokio::spawn(
+            server_reader_task(front_read, cancellation_token.clone(), front_sender)
+                .in_current_span(),
+        );
     } else {
         let front_read: NodeIO<_, TorMessage, ()> = NodeIO::new(front_read);
-        tokio::spawn(reader_task(
-            front_read,
-            cancellation_token.clone(),
-            front_sender,
-        ));
+        tokio::spawn(
+            reader_task(front_read, cancellation_token.clone(), front_sender).in_current_span(),
+        );
     }
     Ok((front_write, front_receiver))
 }
+/// Reason sent back to the client when connecting forward failed
+fn connect_failure(next: Next, err: &anyhow::Error) -> DestroyReason {
+    if !next.is_server() {
+        return DestroyReason::RelayUnreachable;
+    }
+    if let Some(reason) = err.downcast_ref::<DestroyReason>() {
+        return *reason;
+    }
+    if err.is::<Elapsed>() {
+        return DestroyReason::ConnectTimeout;
+    }
+    match err.downcast_ref::<io::Error>().map(io::Error::kind) {
+        Some(io::ErrorKind::ConnectionRefused) => DestroyReason::ConnectRefused,
+        _ => DestroyReason::HostUnreachable,
+    }
+}
+
 type ForwardStream = (
     NodeIO<tokio::io::WriteHalf<tokio::net::TcpStream>, (), TorMessage>,
     mpsc::Receiver<TorMessage>,
@@ -73,6 +140,7 @@ type ForwardStream = (
 
 async fn tor_node(
     cancellation: CancellationToken,
+    context: &CircuitContext,
     mut back_write: NodeIO<impl AsyncWrite + Unpin, (), TorMessage>,
     mut back_receiver: mpsc::Receiver<TorMessage>,
 ) -> anyhow::Result<()> {
@@ -84,39 +152,77 @@ async fn tor_node(
         message: Directional<TorMessage, TorMessage>,
         forward_stream: &mut Option<ForwardStream>,
         back_write: &mut NodeIO<impl AsyncWrite + Unpin, (), TorMessage>,
+        context: &CircuitContext,
         cancellation_token: &CancellationToken,
     ) -> anyhow::Result<()> {
-        let a = circuit_manager.message(message)?;
+        let a = circuit_manager.message(message).inspect_err(|err| {
+            if err.downcast_ref::<aes_gcm::Error>().is_some() {
+                context.stats.decrypt_failures.inc();
+            }
+        })?;
         match a {
             Directional::Back(m @ TorMessage::NotForYou { .. }) => {
-                info!("Writing backward: TorMessage");
-                back_write.node_write(m).await
+                if let TorMessage::NotForYou { data } = &m {
+                    debug!(bytes = data.len(), "Relaying backward");
+                    context.relay_backward(data.len()).await;
+                }
+                Ok(back_write.node_write(m).await?)
             }
             Directional::Back(m @ TorMessage::HandShake { .. }) => {
-                info!("Writing backward: Handshake");
-                back_write.node_write(m).await
+                info!("Handshake completed");
+                context
+                    .stats
+                    .handshake_latency
+                    .observe(context.circuit.age().as_secs_f64());
+                Ok(back_write.node_write(m).await?)
             }
-            Directional::Back(TorMessage::NextNode { .. }) => {
+            Directional::Back(
+                TorMessage::NextNode { .. } | TorMessage::Destroy(_) | TorMessage::Connected,
+            ) => {
                 unreachable!()
             }
             Directional::Forward(NetworkMessage::ConnectTo(next)) => {
-                info!("Received connect to, connection to: {:?}", next);
-                let new_forward_stream = start_forward_connection(next, cancellation_token).await?;
+                let span = Span::current();
+                span.record("role", if next.is_server() { "exit" } else { "relay" });
+                info!(?next, "Extending circuit");
+                context.circuit.set_next(next);
+                let new_forward_stream =
+                    match start_forward_connection(next, context, cancellation_token).await {
+                        Ok(new_forward_stream) => new_forward_stream,
+                        Err(err) => {
+                            let reason = connect_failure(next, &err);
+                            let _ = back_write.node_write(TorMessage::Destroy(reason)).await;
+                            return Err(err);
+                        }
+                    };
                 *forward_stream = Some(new_forward_stream);
+
+                if next.is_server() {
+                    // Lets the client report connect failures before it starts sending data
+                    if let Directional::Back(connected) =
+                        circuit_manager.message(Directional::Back(TorMessage::Connected))?
+                    {
+                        back_write.node_write(connected).await?;
+                    }
+                }
                 Ok(())
             }
             Directional::Forward(NetworkMessage::TorMessage(m)) => {
-                info!("Writing forward: TorMessage");
+                if let TorMessage::NotForYou { data } = &m {
+                    debug!(bytes = data.len(), "Relaying forward");
+                    context.relay_forward(data.len()).await;
+                }
                 if let Some((forward_write, _)) = forward_stream {
-                    forward_write.node_write(m).await
+                    Ok(forward_write.node_write(m).await?)
                 } else {
                     anyhow::bail!("Not connected forward and received message forward")
                 }
             }
             Directional::Forward(NetworkMessage::ServerMessage(data)) => {
-                info!("Writing to server: ");
+                debug!(bytes = data.len(), "Writing to server");
+                context.relay_forward(data.len()).await;
                 if let Some((forward_write, _)) = forward_stream {
-                    forward_write.write_raw(&data).await
+                    Ok(forward_write.write_raw(&data).await?)
                 } else {
                     anyhow::bail!("Not connected forward and received message forward")
                 }
@@ -124,48 +230,74 @@ async fn tor_node(
         }
     }
 
+    let mut result = Ok(());
     loop {
+        context.set_queued(
+            back_receiver.len(),
+            forward.as_ref().map_or(0, |(_, receiver)| receiver.len()),
+        );
         if let Some((_, ref mut forward_receiver)) = &mut forward {
             tokio::select! {
-                Some(forward_msg) = forward_receiver.recv() => {
-                    info!("Received message from front");
+                biased;
+                forward_msg = forward_receiver.recv() => {
+                    let Some(forward_msg) = forward_msg else {
+                        // The server closing its connection ends the circuit normally
+                        let exit = context.circuit.next().is_some_and(|next| next.is_server());
+                        let reason = if exit && !cancellation.is_cancelled() {
+                            DestroyReason::Finished
+                        } else {
+                            DestroyReason::Teardown
+                        };
+                        info!(%reason, "Forward connection closed");
+                        let _ = back_write.node_write(TorMessage::Destroy(reason)).await;
+                        break;
+                    };
                     // Read from the front: direction is backward
-                        if let Err(err) = handle_message(&mut circuit_manager, Directional::Back(forward_msg), &mut forward, &mut back_write, &cancellation).await {
-                            error!("{:?}",err);
-                            break
+                    if let Err(err) = handle_message(&mut circuit_manager, Directional::Back(forward_msg), &mut forward, &mut back_write, context, &cancellation).await {
+                        result = Err(err);
+                        break
                     }
                 },
                 Some(back_msg) = back_receiver.recv() => {
-                    info!("Received message from back");
                     // Read from the back: direction is forward
-                    if let Err(err) = handle_message(&mut circuit_manager, Directional::Forward(back_msg), &mut forward, &mut back_write, &cancellation).await {
-                        error!("Failed sending message: {:?}",err);
+                    if let Err(err) = handle_message(&mut circuit_manager, Directional::Forward(back_msg), &mut forward, &mut back_write, context, &cancellation).await {
+                        result = Err(err);
                         break
                     }
                 },
+                _ = cancellation.cancelled() => {
+                    info!("Tearing down circuit");
+                    let _ = back_write.node_write(TorMessage::Destroy(DestroyReason::Teardown)).await;
+                    break;
+                },
                 else => {
                     break;
                 }
             }
         } else {
             tokio::select! {
-
+                biased;
                 Some(back_msg) = back_receiver.recv() => {
-                    info!("Received message from back");
                     // Read from the back: direction is forward
-                    if let Err(err) = handle_message(&mut circuit_manager, Directional::Forward(back_msg), &mut forward, &mut back_write, &cancellation).await {
-                        error!("Failed sending message: {:?}",err);
+                    if let Err(err) = handle_message(&mut circuit_manager, Directional::Forward(back_msg), &mut forward, &mut back_write, context, &cancellation).await {
+                        result = Err(err);
                         break
                     }
                 },
+                _ = cancellation.cancelled() => {
+                    info!("Tearing down circuit");
+                    let _ = back_write.node_write(TorMessage::Destroy(DestroyReason::Teardown)).await;
+                    break;
+                },
                 else => {
                     break;
                 }
             }
         }
     }
+    context.set_queued(0, 0);
     cancellation.cancel();
-    Ok(())
+    result
 }
 
 async fn server_reader_task(
@@ -177,7 +309,7 @@ async fn server_reader_task(
     loop {
         tokio::select! {
             _ = cancellation.cancelled() => {
-                info!("Cancellation requested, shutting down server_reader_task.");
+                debug!("Cancellation requested, shutting down server reader");
                 break;
             }
             Ok(len) = reader.read(&mut buf) => {
@@ -193,7 +325,7 @@ async fn server_reader_task(
                 }
             }
             else => {
-                info!("Failed reading from server closing");
+                info!("Server closed the connection");
                 cancellation.cancel();
                 break;
             }
@@ -211,7 +343,7 @@ async fn reader_task<V, G>(
     loop {
         tokio::select! {
             _ = cancellation.cancelled() => {
-                info!("Cancellation requested, shutting down reader_task.");
+                debug!("Cancellation requested, shutting down reader");
                 break;
             },
             result = reader.read() => {
@@ -233,5 +365,6 @@ async fn reader_task<V, G>(
     }
 }
 
+#[cfg(test)]
 mod tests;
 // Example
@@ -1,103 +1,1747 @@
-#![allow(dead_code, unused_imports)]
-
-use log::info;
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    time::Duration,
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
 use tokio::{
-    process::{Child, Command},
-    time::sleep,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
 };
 
+use tracing::info;
+
 use crate::{
-    encryption::KeyPair,
-    node_io::NodeIO,
+    proxy::{self, ProxyMetrics},
     tor::{
-        client::{nodes_handshake, TorClient},
-        onion::{decrypt_onion_layers, onion_wrap_handshake, onion_wrap_packet},
-        tor_message::{Next, TorMessage},
+        authority::{Authority, AuthorityHandle, AuthorityPeer, RegistrationLimits},
+        client::{nodes_handshake, prepare_circuit, CircuitTimeouts},
+        consensus::{Consensus, ConsensusError, ConsensusNode, Flag, SignedConsensus},
+        control::ControlConfig,
+        descriptor::{NodeInfo, SignedDescriptor},
+        error::TorError,
+        exit_policy::ExitPolicy,
+        node_directory,
+        node_server::{NodeHandle, NodeServer, NodeSettings},
+        path::PathSelector,
+        stream::{TorStream, MAX_PAYLOAD},
+        tor_message::DestroyReason,
     },
+    Client, ClientBuilder,
 };
 
-const NODE1_PORT: u16 = 10000;
-const NODE2_PORT: u16 = 10001;
-const NODE3_PORT: u16 = 10002;
-const NODE4_PORT: u16 = 10003;
+fn init_tracing() {
+    let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+}
+
+async fn start_nodes(amount: usize, exit_policy: ExitPolicy) -> anyhow::Result<Vec<NodeHandle>> {
+    let mut nodes = vec![];
+    for _ in 0..amount {
+        nodes.push(
+            NodeServer::new()
+                .exit_policy(exit_policy.clone())
+                .start()
+                .await?,
+        );
+    }
+    Ok(nodes)
+}
+
+async fn start_echo_server() -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = tokio::spawn(async move {
+        let Ok((mut client, _)) = listener.accept().await else {
+            return;
+        };
+        let mut buf = vec![0u8; 1024];
+        while let Ok(n @ 1..) = client.read(&mut buf).await {
+            if client.write_all(&buf[..n]).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok((addr, server))
+}
+
+#[tokio::test]
+async fn test_end_to_end() -> anyhow::Result<()> {
+    init_tracing();
 
-const FAKE_SERVER_PORT: u16 = 12345;
-const NODE1: SocketAddr =
-    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), NODE1_PORT));
+    let nodes = start_nodes(4, ExitPolicy::default()).await?;
+    let (server, _server_task) = start_echo_server().await?;
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect();
 
-const NODE2: SocketAddr =
-    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), NODE2_PORT));
+    let (mut reader, mut writer) = nodes_handshake(addrs, server).await?;
+    info!("Finished handshake");
 
-const NODE3: SocketAddr =
-    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), NODE3_PORT));
+    let message = "Hello";
+    writer.write(message.as_bytes()).await?;
 
-const NODE4: SocketAddr =
-    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), NODE4_PORT));
+    let response = reader.read().await?;
+    assert_eq!(String::from_utf8(response)?, message);
 
-const FAKE_SERVER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(
-    Ipv4Addr::new(127, 0, 0, 1),
-    FAKE_SERVER_PORT,
-));
+    for node in &nodes {
+        let stats = node.stats();
+        assert_eq!(stats.circuits_opened, 1);
+        assert!(stats.bytes_forward > 0 && stats.bytes_backward > 0);
+    }
 
-async fn start_node(port: u16) -> anyhow::Result<Child> {
-    let proc = Command::new("cargo")
-        .arg("run")
-        .arg("--bin")
-        .arg("node")
-        .arg("-q")
-        .arg("--")
-        .arg("-p")
-        .arg(port.to_string())
-        .spawn()?;
-    Ok(proc)
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
 }
 
-async fn start_fake_server(port: u16) -> anyhow::Result<Child> {
-    let server = Command::new("python3")
-        .arg("./src/tor/node/test_server.py")
-        .arg(port.to_string())
-        .spawn()
-        .expect("Hello");
-    Ok(server)
+#[tokio::test]
+async fn exit_policy_rejects_server() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(2, ExitPolicy::reject_all()).await?;
+    let (server, _server_task) = start_echo_server().await?;
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect();
+
+    let result = nodes_handshake(addrs, server).await;
+    assert!(matches!(result, Err(TorError::PolicyRejected(addr)) if addr == server));
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
 }
 
-async fn end_to_end() -> anyhow::Result<()> {
-    info!("Connected to node!");
+#[tokio::test]
+async fn exit_reports_refused_connection() -> anyhow::Result<()> {
+    init_tracing();
 
-    let (mut reader, mut writer) =
-        nodes_handshake(vec![NODE1, NODE2, NODE3, NODE4], FAKE_SERVER).await?;
+    let nodes = start_nodes(2, ExitPolicy::default()).await?;
+    // Nothing listens on the port once the listener is dropped
+    let server = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect();
 
-    info!("Finised handshake with node2!");
+    let result = nodes_handshake(addrs, server).await;
+    assert!(matches!(result, Err(TorError::ExitConnectRefused(addr)) if addr == server));
 
-    let message = "Hello";
-    writer.write(message.as_bytes()).await?;
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_closes_circuits() -> anyhow::Result<()> {
+    init_tracing();
+
+    let mut nodes = start_nodes(3, ExitPolicy::default()).await?;
+    let (server, _server_task) = start_echo_server().await?;
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect();
 
-    let message = reader.read().await?;
-    let message = String::from_utf8(message)?;
-    info!("Reponse is: {}", message);
+    let (mut reader, _writer) = nodes_handshake(addrs, server).await?;
 
+    nodes.remove(1).shutdown().await;
+
+    assert!(timeout(Duration::from_secs(5), reader.read())
+        .await?
+        .is_err());
+    for node in nodes {
+        node.shutdown().await;
+    }
     Ok(())
 }
 
 #[tokio::test]
-async fn test_end_to_end() -> anyhow::Result<()> {
-    env_logger::init();
-
-    let mut node_1_proc = start_node(NODE1_PORT).await?;
-    let mut node_2_proc = start_node(NODE2_PORT).await?;
-    let mut node_3_proc = start_node(NODE3_PORT).await?;
-    let mut node_4_proc = start_node(NODE4_PORT).await?;
-    let mut server = start_fake_server(FAKE_SERVER_PORT).await?;
-    sleep(Duration::from_secs_f32(1.5)).await;
-    let result = end_to_end().await;
-    node_1_proc.kill().await?;
-    node_2_proc.kill().await?;
-    node_3_proc.kill().await?;
-    node_4_proc.kill().await?;
-    server.kill().await?;
-    result
+async fn shutdown_drains_circuits() -> anyhow::Result<()> {
+    init_tracing();
+
+    let mut nodes = start_nodes(2, ExitPolicy::default()).await?;
+    let draining = NodeServer::new()
+        .drain_period(Duration::from_millis(500))
+        .start()
+        .await?;
+    let (server, _server_task) = start_echo_server().await?;
+    let addrs = vec![
+        nodes[0].local_addr(),
+        draining.local_addr(),
+        nodes[1].local_addr(),
+    ];
+
+    let (mut reader, mut writer) = nodes_handshake(addrs, server).await?;
+    let shutdown = tokio::spawn(draining.shutdown());
+
+    // Existing circuits keep working during the drain period
+    writer.write(b"Still here").await?;
+    assert_eq!(reader.read().await?, b"Still here");
+
+    let destroyed = timeout(Duration::from_secs(5), reader.read()).await?;
+    assert!(matches!(
+        destroyed,
+        Err(TorError::CircuitDestroyed(DestroyReason::Teardown))
+    ));
+    shutdown.await?;
+
+    for node in nodes.drain(..) {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn reloaded_settings_only_affect_new_circuits() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(2, ExitPolicy::default()).await?;
+    let (server, _server_task) = start_echo_server().await?;
+    let (second_server, _second_server_task) = start_echo_server().await?;
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect::<Vec<_>>();
+
+    let (mut reader, mut writer) = nodes_handshake(addrs.clone(), server).await?;
+
+    let exit = &nodes[1];
+    exit.update_settings(NodeSettings {
+        exit_policy: ExitPolicy::reject_all(),
+        ..(*exit.settings()).clone()
+    });
+
+    writer.write(b"Existing circuit").await?;
+    assert_eq!(reader.read().await?, b"Existing circuit");
+
+    let result = nodes_handshake(addrs, second_server).await;
+    assert!(matches!(result, Err(TorError::PolicyRejected(_))));
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+async fn control_reply(
+    lines: &mut Lines<BufReader<impl AsyncRead + Unpin>>,
+) -> anyhow::Result<String> {
+    let line = timeout(Duration::from_secs(5), lines.next_line()).await??;
+    Ok(line.unwrap_or_default())
+}
+
+#[tokio::test]
+async fn control_port() -> anyhow::Result<()> {
+    init_tracing();
+
+    let mut nodes = start_nodes(2, ExitPolicy::default()).await?;
+    let controlled = NodeServer::new()
+        .control(ControlConfig {
+            address: "127.0.0.1:0".parse()?,
+            password: "secret".to_string(),
+        })
+        .start()
+        .await?;
+    let (server, _server_task) = start_echo_server().await?;
+
+    let control = TcpStream::connect(controlled.control_addr().unwrap()).await?;
+    let (control_reader, mut control) = control.into_split();
+    let mut control_reader = BufReader::new(control_reader).lines();
+
+    control.write_all(b"CIRCUITS\r\n").await?;
+    assert_eq!(
+        control_reply(&mut control_reader).await?,
+        "514 Authentication required"
+    );
+    control.write_all(b"AUTHENTICATE wrong\r\n").await?;
+    assert_eq!(
+        control_reply(&mut control_reader).await?,
+        "515 Authentication failed"
+    );
+    control.write_all(b"AUTHENTICATE secret\r\n").await?;
+    assert_eq!(control_reply(&mut control_reader).await?, "250 OK");
+    control.write_all(b"SUBSCRIBE\r\n").await?;
+    assert_eq!(control_reply(&mut control_reader).await?, "250 OK");
+
+    let addrs = vec![
+        nodes[0].local_addr(),
+        controlled.local_addr(),
+        nodes[1].local_addr(),
+    ];
+    let (mut reader, mut writer) = nodes_handshake(addrs, server).await?;
+    writer.write(b"Hello").await?;
+    assert_eq!(reader.read().await?, b"Hello");
+
+    let opened = control_reply(&mut control_reader).await?;
+    assert!(opened.starts_with("650 CIRCUIT 1 OPENED"), "{}", opened);
+
+    control.write_all(b"CIRCUITS\r\n").await?;
+    let circuit = control_reply(&mut control_reader).await?;
+    assert!(circuit.starts_with("250-1 peer="), "{}", circuit);
+    assert!(circuit.contains(&format!("next=Node({})", nodes[1].local_addr())));
+    assert_eq!(control_reply(&mut control_reader).await?, "250 OK");
+
+    control.write_all(b"CLOSE 1\r\n").await?;
+    assert_eq!(control_reply(&mut control_reader).await?, "250 OK");
+    assert!(reader.read().await.is_err());
+    assert!(control_reply(&mut control_reader)
+        .await?
+        .starts_with("650 CIRCUIT 1 CLOSED"));
+
+    control.write_all(b"CLOSE 1\r\n").await?;
+    assert_eq!(
+        control_reply(&mut control_reader).await?,
+        "552 Unknown circuit 1"
+    );
+
+    controlled.shutdown().await;
+    for node in nodes.drain(..) {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_endpoint() -> anyhow::Result<()> {
+    init_tracing();
+
+    let mut nodes = start_nodes(2, ExitPolicy::default()).await?;
+    let measured = NodeServer::new()
+        .metrics("127.0.0.1:0".parse()?)
+        .start()
+        .await?;
+    let (server, _server_task) = start_echo_server().await?;
+
+    let addrs = [measured.local_addr()]
+        .into_iter()
+        .chain(nodes.iter().map(NodeHandle::local_addr))
+        .collect();
+    let (mut reader, mut writer) = nodes_handshake(addrs, server).await?;
+    writer.write(b"Hello").await?;
+    reader.read().await?;
+
+    let mut metrics = TcpStream::connect(measured.metrics_addr().unwrap()).await?;
+    metrics
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    metrics.read_to_string(&mut response).await?;
+
+    assert!(response.contains("rustor_node_circuits_opened_total 1"));
+    assert!(response.contains("rustor_node_circuits_active 1"));
+    assert!(response.contains("rustor_node_handshake_latency_seconds_count 1"));
+    assert!(response.contains("rustor_node_decrypt_failures_total 0"));
+
+    nodes.push(measured);
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn json_logs_carry_circuit_spans() -> anyhow::Result<()> {
+    let buffer = SharedBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_span_list(true)
+        .with_max_level(tracing::Level::TRACE)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let nodes = start_nodes(2, ExitPolicy::default()).await?;
+    let (server, _server_task) = start_echo_server().await?;
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect();
+
+    let (mut reader, mut writer) = nodes_handshake(addrs, server).await?;
+    writer.write(b"secret payload").await?;
+    reader.read().await?;
+    for node in nodes {
+        node.shutdown().await;
+    }
+
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+    let lines = logs
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    let spans = |line: &serde_json::Value| line["spans"].as_array().cloned().unwrap_or_default();
+
+    // Both the client and the exit node log inside a circuit span
+    assert!(lines.iter().any(|line| spans(line)
+        .iter()
+        .any(|span| span["name"] == "hop" && span["position"] == 2)));
+    assert!(lines.iter().any(|line| spans(line)
+        .iter()
+        .any(|span| span["name"] == "circuit" && span["role"] == "exit")));
+    assert!(!logs.contains("secret payload"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn tor_stream_round_trips_large_payload() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(3, ExitPolicy::default()).await?;
+    let (server, _server_task) = start_echo_server().await?;
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect();
+
+    let stream = TorStream::connect(addrs, server).await?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // Larger than a single message, read back in small pieces
+    let payload = (0..3 * MAX_PAYLOAD + 17)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let sent = payload.clone();
+    let writing = tokio::spawn(async move {
+        writer.write_all(&sent).await?;
+        writer.flush().await?;
+        Ok::<_, std::io::Error>(writer)
+    });
+
+    let mut received = vec![0u8; payload.len()];
+    for chunk in received.chunks_mut(100) {
+        timeout(Duration::from_secs(5), reader.read_exact(chunk)).await??;
+    }
+    assert_eq!(received, payload);
+    writing.await??;
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn tor_stream_ends_when_server_closes() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(2, ExitPolicy::default()).await?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server = listener.local_addr()?;
+    let _server_task = tokio::spawn(async move {
+        let (mut client, _) = listener.accept().await?;
+        client.write_all(b"Goodbye").await?;
+        Ok::<_, std::io::Error>(())
+    });
+    let addrs = nodes.iter().map(NodeHandle::local_addr).collect();
+
+    let mut stream = TorStream::connect(addrs, server).await?;
+    let mut response = vec![];
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await??;
+    assert_eq!(response, b"Goodbye");
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Every test node listens on localhost
+fn local_path() -> PathSelector {
+    PathSelector::default().distinct_subnets(false)
+}
+
+/// Stub of the directory, counting the requests it got
+struct TestDirectory {
+    url: String,
+    key: SigningKey,
+    requests: Arc<AtomicUsize>,
+}
+
+impl TestDirectory {
+    /// A client trusting the directory
+    fn client(&self) -> ClientBuilder {
+        Client::builder()
+            .directory(self.url.clone())
+            .authority_key(self.key.verifying_key())
+    }
+}
+
+/// Directory of `nodes` described by fresh identities that exit to every port
+async fn start_directory(nodes: Vec<SocketAddr>) -> anyhow::Result<TestDirectory> {
+    let descriptors = nodes
+        .into_iter()
+        .map(|addr| {
+            NodeInfo::default().descriptor(addr, &ExitPolicy::default(), None, SystemTime::now())
+        })
+        .collect();
+    serve_directory(descriptors).await
+}
+
+/// Serves the descriptors and a consensus of them signed by a fresh key like the directory's
+/// /descriptors and /consensus, counting the consensus requests
+async fn serve_directory(descriptors: Vec<SignedDescriptor>) -> anyhow::Result<TestDirectory> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let nodes = descriptors
+        .iter()
+        .map(|signed| {
+            let descriptor = signed.verify(SystemTime::now())?;
+            Ok(ConsensusNode::new(
+                signed,
+                &descriptor,
+                BTreeSet::from([Flag::Valid]),
+                None,
+            ))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let consensus = serde_json::to_string(&Consensus::new(nodes, SystemTime::now()).sign(&key))?;
+    let descriptors = serde_json::to_string(&descriptors)?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![0u8; 1024];
+            let _ = stream.read(&mut request).await;
+            let body = if request.starts_with(b"GET /descriptors") {
+                &descriptors
+            } else {
+                counter.fetch_add(1, Ordering::SeqCst);
+                &consensus
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    Ok(TestDirectory { url, key, requests })
+}
+
+#[tokio::test]
+async fn client_connects_through_directory_nodes() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(4, ExitPolicy::default()).await?;
+    let directory = start_directory(nodes.iter().map(NodeHandle::local_addr).collect()).await?;
+
+    let client = directory.client().path_selector(local_path()).build();
+    for _ in 0..2 {
+        let (server, _server_task) = start_echo_server().await?;
+        let mut stream = client.connect("127.0.0.1", server.port()).await?;
+        stream.write_all(b"Hello").await?;
+        stream.flush().await?;
+        let mut response = [0u8; 5];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+        assert_eq!(&response, b"Hello");
+    }
+    // The consensus is cached between circuits
+    assert_eq!(directory.requests.load(Ordering::SeqCst), 1);
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_needs_enough_nodes_for_hops() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(2, ExitPolicy::default()).await?;
+    let directory = start_directory(nodes.iter().map(NodeHandle::local_addr).collect()).await?;
+
+    let client = directory.client().hops(3).build();
+    let result = client.connect("127.0.0.1", 80).await;
+    assert!(matches!(
+        result,
+        Err(TorError::NotEnoughNodes {
+            needed: 3,
+            available: 2
+        })
+    ));
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_uses_pooled_circuits() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(3, ExitPolicy::default()).await?;
+    let directory = start_directory(nodes.iter().map(NodeHandle::local_addr).collect()).await?;
+    let client = directory
+        .client()
+        .path_selector(local_path())
+        .pool_size(2)
+        .build();
+    let registry = prometheus::Registry::new();
+    client.metrics().register(&registry)?;
+    let idle = || client.metrics().idle_circuits.get();
+
+    timeout(Duration::from_secs(5), async {
+        while idle() < 2 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+
+    let (server, _server_task) = start_echo_server().await?;
+    let mut stream = client.connect_addr(server).await?;
+    stream.write_all(b"Hello").await?;
+    stream.flush().await?;
+    let mut response = [0u8; 5];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+    assert_eq!(&response, b"Hello");
+
+    let setup = client.metrics().circuit_setup.clone();
+    assert_eq!(setup.with_label_values(&["pool"]).get_sample_count(), 1);
+    assert_eq!(setup.with_label_values(&["fresh"]).get_sample_count(), 0);
+
+    // The used circuit is replaced in the background
+    timeout(Duration::from_secs(5), async {
+        while idle() < 2 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_reuses_guards_across_restarts() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(4, ExitPolicy::default()).await?;
+    let directory = start_directory(nodes.iter().map(NodeHandle::local_addr).collect()).await?;
+    let state_dir = std::env::temp_dir().join(format!("rustor-client-{}", rand::random::<u64>()));
+    let client = || {
+        directory
+            .client()
+            .path_selector(local_path())
+            .guards(2)
+            .state_dir(&state_dir)
+            .build()
+    };
+
+    let first = client();
+    for _ in 0..3 {
+        let (server, _server_task) = start_echo_server().await?;
+        first.connect_addr(server).await?;
+    }
+    let guards = first.guards().await;
+    assert_eq!(guards.len(), 2);
+    assert!(guards.iter().any(|guard| guard.last_success.is_some()));
+
+    // A new client, like a restarted proxy, keeps the guards
+    let second = client();
+    let (server, _server_task) = start_echo_server().await?;
+    second.connect_addr(server).await?;
+    let addrs = |guards: Vec<crate::client::Guard>| {
+        guards
+            .into_iter()
+            .map(|guard| guard.addr)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(addrs(second.guards().await), addrs(guards));
+    // and the consensus, so it didn't ask the directory again
+    assert_eq!(directory.requests.load(Ordering::SeqCst), 1);
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    std::fs::remove_dir_all(state_dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_retries_without_black_holed_relay() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(3, ExitPolicy::default()).await?;
+    // Accepts connections but never answers a handshake
+    let black_hole = TcpListener::bind("127.0.0.1:0").await?;
+    let black_hole_addr = black_hole.local_addr()?;
+    let _black_hole_task = tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((connection, _)) = black_hole.accept().await {
+            connections.push(connection);
+        }
+    });
+    let mut relays = nodes.iter().map(NodeHandle::local_addr).collect::<Vec<_>>();
+    relays.push(black_hole_addr);
+    let directory = start_directory(relays).await?;
+
+    let client = directory
+        .client()
+        .path_selector(local_path().exit_nodes(vec![black_hole_addr, nodes[2].local_addr()]))
+        .guards(0)
+        .handshake_timeout(Duration::from_millis(300))
+        .retries(1)
+        .build();
+    for _ in 0..5 {
+        let (server, _server_task) = start_echo_server().await?;
+        let mut stream = timeout(Duration::from_secs(5), client.connect_addr(server)).await??;
+        stream.write_all(b"Hello").await?;
+        stream.flush().await?;
+        let mut response = [0u8; 5];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+    }
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn circuit_build_deadline() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(1, ExitPolicy::default()).await?;
+    let black_hole = TcpListener::bind("127.0.0.1:0").await?;
+    let black_hole_addr = black_hole.local_addr()?;
+    let _black_hole_task = tokio::spawn(async move {
+        let mut connections = vec![];
+        while let Ok((connection, _)) = black_hole.accept().await {
+            connections.push(connection);
+        }
+    });
+
+    let timeouts = CircuitTimeouts {
+        build: Duration::from_millis(300),
+        ..CircuitTimeouts::default()
+    };
+    let result = prepare_circuit(vec![nodes[0].local_addr(), black_hole_addr], timeouts).await;
+    assert!(matches!(result, Err(TorError::BuildTimeout { hop: 2 })));
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Sends a SOCKS5 CONNECT to `server`, authenticating with `user` when given
+async fn socks_connect(
+    proxy: SocketAddr,
+    user: Option<(&str, &str)>,
+    server: SocketAddr,
+) -> anyhow::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    let method = if user.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    assert_eq!(reply, [0x05, method]);
+
+    if let Some((username, password)) = user {
+        let mut request = vec![0x01, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        assert_eq!(reply, [0x01, 0x00]);
+    }
+
+    let SocketAddr::V4(server) = server else {
+        anyhow::bail!("Only IPv4 servers are supported");
+    };
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&server.ip().octets());
+    request.extend_from_slice(&server.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 10];
+    timeout(Duration::from_secs(10), stream.read_exact(&mut reply)).await??;
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    Ok(stream)
+}
+
+#[tokio::test]
+async fn proxy_accepts_isolation_credentials() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(3, ExitPolicy::default()).await?;
+    let directory = start_directory(nodes.iter().map(NodeHandle::local_addr).collect()).await?;
+    let client = directory.client().path_selector(local_path()).build();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?;
+    let config = format!("{proxy_addr}=auth,dest-port").parse()?;
+    let _proxy = tokio::spawn(proxy::serve(
+        listener,
+        config,
+        client,
+        std::sync::Arc::new(ProxyMetrics::default()),
+    ));
+
+    for user in [Some(("alice", "secret")), Some(("bob", "secret")), None] {
+        let (server, _server_task) = start_echo_server().await?;
+        let mut stream = socks_connect(proxy_addr, user, server).await?;
+        stream.write_all(b"Hello").await?;
+        let mut response = [0u8; 5];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+        assert_eq!(&response, b"Hello");
+    }
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn isolation_keys_never_share_circuits() -> anyhow::Result<()> {
+    init_tracing();
+
+    let nodes = start_nodes(6, ExitPolicy::default()).await?;
+    let directory = start_directory(nodes.iter().map(NodeHandle::local_addr).collect()).await?;
+    let client = directory
+        .client()
+        .path_selector(local_path())
+        .guards(0)
+        .build();
+    let listener: proxy::ListenerConfig = "127.0.0.1:1080=auth".parse()?;
+    // Nodes that opened a circuit for the stream
+    let connect = |username: &'static str| {
+        let client = client.clone();
+        let credentials = proxy::SocksCredentials {
+            username: username.to_string(),
+            password: "secret".to_string(),
+        };
+        let isolation = proxy::IsolationKey::new(
+            &listener,
+            "127.0.0.1:5000".parse().expect("Valid address"),
+            Some(&credentials),
+            "127.0.0.1",
+            80,
+        );
+        let nodes = &nodes;
+        async move {
+            let opened = || {
+                nodes
+                    .iter()
+                    .map(|node| node.stats().circuits_opened)
+                    .collect::<Vec<_>>()
+            };
+            let (server, server_task) = start_echo_server().await?;
+            let before = opened();
+            let mut stream = client
+                .connect_isolated("127.0.0.1", server.port(), &isolation)
+                .await?;
+            stream.write_all(b"Hello").await?;
+            stream.flush().await?;
+            let mut response = [0u8; 5];
+            timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+            let used = opened()
+                .into_iter()
+                .zip(before)
+                .map(|(after, before)| after - before)
+                .collect::<Vec<_>>();
+            anyhow::Ok(((stream, server_task), used))
+        }
+    };
+
+    let (_alice, alice_path) = connect("alice").await?;
+    let (_bob, bob_path) = connect("bob").await?;
+    let (_alice_again, alice_again_path) = connect("alice").await?;
+    // Every stream got a circuit of its own through three nodes
+    for path in [&alice_path, &bob_path, &alice_again_path] {
+        assert_eq!(path.iter().sum::<u64>(), 3);
+    }
+    // and a key's streams take the path of its first circuit
+    assert_eq!(alice_again_path, alice_path);
+    let setup = client.metrics().circuit_setup.clone();
+    assert_eq!(setup.with_label_values(&["isolated"]).get_sample_count(), 1);
+    assert_eq!(setup.with_label_values(&["fresh"]).get_sample_count(), 2);
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_rejects_untrusted_directory() -> anyhow::Result<()> {
+    init_tracing();
+
+    let directory = start_directory(vec!["127.0.0.1:1".parse()?; 3]).await?;
+    let attacker = SigningKey::generate(&mut rand::rngs::OsRng);
+    let client = Client::builder()
+        .directory(directory.url.clone())
+        .authority_key(attacker.verifying_key())
+        .build();
+    let result = client.connect("127.0.0.1", 80).await;
+    assert!(matches!(result, Err(TorError::ConsensusRejected(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_picks_exits_accepting_the_port() -> anyhow::Result<()> {
+    init_tracing();
+
+    let mut nodes = start_nodes(3, ExitPolicy::default()).await?;
+    nodes.extend(start_nodes(2, ExitPolicy::reject_all()).await?);
+    let directory = serve_directory(nodes.iter().map(NodeHandle::descriptor).collect()).await?;
+
+    let client = directory
+        .client()
+        .path_selector(local_path())
+        .guards(0)
+        .retries(0)
+        .build();
+    for _ in 0..10 {
+        let (server, _server_task) = start_echo_server().await?;
+        let mut stream = client.connect_addr(server).await?;
+        stream.write_all(b"Hello").await?;
+        stream.flush().await?;
+        let mut response = [0u8; 5];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+    }
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Authorities that know each other, voting every second
+fn start_authorities(amount: usize) -> anyhow::Result<Vec<AuthorityHandle>> {
+    let keys = (0..amount)
+        .map(|_| SigningKey::generate(&mut rand::rngs::OsRng))
+        .collect::<Vec<_>>();
+    let listeners = (0..amount)
+        .map(|_| std::net::TcpListener::bind("127.0.0.1:0"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let peers = keys
+        .iter()
+        .zip(&listeners)
+        .map(|(key, listener)| {
+            Ok(AuthorityPeer {
+                url: format!("http://{}", listener.local_addr()?),
+                key: key.verifying_key(),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    keys.into_iter()
+        .zip(listeners)
+        .enumerate()
+        .map(|(i, (key, listener))| {
+            peers
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| i != *j)
+                .fold(Authority::new(key), |authority, (_, peer)| {
+                    authority.peer(peer.clone())
+                })
+                .probe_interval(Duration::from_millis(100))
+                .voting_interval(Duration::from_secs(1))
+                .listen(listener)
+        })
+        .collect()
+}
+
+/// Waits for a consensus of the authority that `signatures` authorities signed after `after`
+async fn wait_for_consensus(
+    authority: &AuthorityHandle,
+    keys: &[VerifyingKey],
+    signatures: usize,
+    after: u64,
+) -> anyhow::Result<Consensus> {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    loop {
+        let response = reqwest::get(format!("{}/consensus", authority.url())).await?;
+        if response.status().is_success() {
+            let signed: SignedConsensus = response.json().await?;
+            let consensus = signed.verify(keys, signatures, SystemTime::now());
+            if let Ok(consensus) = consensus {
+                if consensus.valid_after > after && consensus.valid_nodes().count() == 3 {
+                    return Ok(consensus);
+                }
+            }
+        }
+        anyhow::ensure!(
+            tokio::time::Instant::now() < deadline,
+            "No consensus signed by {} authorities",
+            signatures
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn authorities_agree_on_a_consensus() -> anyhow::Result<()> {
+    init_tracing();
+
+    let mut authorities = start_authorities(3)?;
+    let keys = authorities
+        .iter()
+        .map(AuthorityHandle::key)
+        .collect::<Vec<_>>();
+    let mut nodes = vec![];
+    for _ in 0..3 {
+        nodes.push(
+            authorities
+                .iter()
+                .fold(NodeServer::new(), |node, authority| {
+                    node.directory(authority.url())
+                })
+                .start()
+                .await?,
+        );
+    }
+
+    let consensus = wait_for_consensus(&authorities[0], &keys, 3, 0).await?;
+    for authority in &authorities[1..] {
+        let theirs = wait_for_consensus(authority, &keys, 3, consensus.valid_after - 1).await?;
+        if theirs.valid_after == consensus.valid_after {
+            assert_eq!(theirs, consensus);
+        }
+    }
+
+    // Two of three authorities are still a majority, the client falls back to the second
+    let stopped = authorities.remove(0);
+    let stopped_url = stopped.url();
+    stopped.shutdown().await;
+    wait_for_consensus(&authorities[0], &keys, 2, consensus.valid_after).await?;
+
+    let client = keys
+        .iter()
+        .fold(Client::builder(), |builder, key| {
+            builder.authority_key(*key)
+        })
+        .directory(stopped_url)
+        .directory(authorities[0].url())
+        .path_selector(local_path())
+        .build();
+    let (server, _server_task) = start_echo_server().await?;
+    let mut stream = client.connect_addr(server).await?;
+    stream.write_all(b"Hello").await?;
+    stream.flush().await?;
+    let mut response = [0u8; 5];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut response)).await??;
+    assert_eq!(&response, b"Hello");
+
+    // A client asking for all three signatures rejects the consensus of the remaining two
+    let strict = keys
+        .iter()
+        .fold(Client::builder(), |builder, key| {
+            builder.authority_key(*key)
+        })
+        .authority_threshold(3)
+        .directory(authorities[0].url())
+        .build();
+    let result = strict.connect_addr(server).await;
+    assert!(matches!(
+        result,
+        Err(TorError::ConsensusRejected(
+            ConsensusError::TooFewSignatures {
+                signed: 2,
+                threshold: 3
+            }
+        ))
+    ));
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    for authority in authorities {
+        authority.shutdown().await;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn authority_restores_nodes_after_restart() -> anyhow::Result<()> {
+    init_tracing();
+
+    let data_dir = std::env::temp_dir().join(format!("rustor-authority-{}", rand::random::<u64>()));
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let keys = [key.verifying_key()];
+    let authority = Authority::new(key)
+        .data_dir(&data_dir)
+        .probe_interval(Duration::from_millis(100))
+        .voting_interval(Duration::from_secs(1));
+    let first = authority.clone().start().await?;
+    let mut nodes = vec![];
+    for _ in 0..3 {
+        nodes.push(NodeServer::new().directory(first.url()).start().await?);
+    }
+    let consensus = wait_for_consensus(&first, &keys, 1, 0).await?;
+    first.shutdown().await;
+
+    // The nodes never register at the restarted authority
+    let restarted = authority.start().await?;
+    let restored: SignedConsensus = reqwest::get(format!("{}/consensus", restarted.url()))
+        .await?
+        .json()
+        .await?;
+    let restored = restored.verify(&keys, 1, SystemTime::now())?;
+    assert!(restored.valid_after >= consensus.valid_after);
+    assert_eq!(restored.valid_nodes().count(), 3);
+    let descriptors: Vec<SignedDescriptor> =
+        reqwest::get(format!("{}/descriptors", restarted.url()))
+            .await?
+            .json()
+            .await?;
+    assert_eq!(descriptors.len(), 3);
+    // and it keeps probing them
+    wait_for_consensus(&restarted, &keys, 1, restored.valid_after).await?;
+
+    for node in nodes {
+        node.shutdown().await;
+    }
+    restarted.shutdown().await;
+    std::fs::remove_dir_all(data_dir)?;
+    Ok(())
+}
+
+async fn registered_identities(authority: &AuthorityHandle) -> anyhow::Result<Vec<String>> {
+    let descriptors: Vec<SignedDescriptor> =
+        reqwest::get(format!("{}/descriptors", authority.url()))
+            .await?
+            .json()
+            .await?;
+    descriptors
+        .iter()
+        .map(|descriptor| Ok(descriptor.verify(SystemTime::now())?.identity))
+        .collect()
+}
+
+#[tokio::test]
+async fn heartbeats_keep_nodes_registered() -> anyhow::Result<()> {
+    init_tracing();
+
+    let authority = Authority::new(SigningKey::generate(&mut rand::rngs::OsRng))
+        .node_ttl(Duration::from_secs(1))
+        .probe_interval(Duration::from_millis(100))
+        .start()
+        .await?;
+    let info = NodeInfo::default();
+    let node = NodeServer::new()
+        .directory(authority.url())
+        .keys(info.keys.clone())
+        .heartbeat_interval(Duration::from_millis(200))
+        .start()
+        .await?;
+    // Registered once and never heard from again
+    let silent = NodeInfo::default();
+    let descriptor = silent.descriptor(
+        "127.0.0.1:1".parse()?,
+        &ExitPolicy::default(),
+        None,
+        SystemTime::now(),
+    );
+    node_directory::add_node(&authority.url(), &silent, descriptor).await?;
+    assert_eq!(registered_identities(&authority).await?.len(), 2);
+    // Its last heartbeat replayed by someone who captured it
+    let captured = silent.heartbeat("127.0.0.1:1".parse()?, 0, 0, SystemTime::now());
+    assert!(node_directory::heartbeat(&authority.url(), &captured).await?);
+    let url = authority.url();
+    let replays = tokio::spawn(async move {
+        loop {
+            let replayed = node_directory::heartbeat(&url, &captured).await;
+            assert!(
+                !replayed.unwrap_or(false),
+                "Replayed heartbeat was accepted"
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(3500)).await;
+    assert!(!replays.is_finished());
+    replays.abort();
+    assert_eq!(
+        registered_identities(&authority).await?,
+        vec![node.identity()]
+    );
+
+    // Only the node itself can remove it
+    let other = NodeInfo::default();
+    assert!(
+        node_directory::remove_node(&authority.url(), &other, node.advertised_addr())
+            .await
+            .is_err()
+    );
+    // A directory that lost the node gets it back with the next heartbeat
+    node_directory::remove_node(&authority.url(), &info, node.advertised_addr()).await?;
+    assert!(registered_identities(&authority).await?.is_empty());
+    timeout(Duration::from_secs(2), async {
+        while registered_identities(&authority).await?.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+
+    node.shutdown().await;
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn probes_require_a_handshake() -> anyhow::Result<()> {
+    init_tracing();
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let authority = Authority::new(key.clone())
+        .probe_interval(Duration::from_millis(100))
+        .probe_timeout(Duration::from_millis(300))
+        .probe_concurrency(4)
+        .voting_interval(Duration::from_secs(1))
+        .start()
+        .await?;
+    let node = NodeServer::new().directory(authority.url()).start().await?;
+    // Accept connections but never answer a handshake, more of them than are probed at once
+    let mut silent = vec![];
+    for _ in 0..8 {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let info = NodeInfo::default();
+        let descriptor = info.descriptor(
+            listener.local_addr()?,
+            &ExitPolicy::default(),
+            None,
+            SystemTime::now(),
+        );
+        node_directory::add_node(&authority.url(), &info, descriptor).await?;
+        silent.push(tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        }));
+    }
+
+    let consensus = timeout(Duration::from_secs(10), async {
+        loop {
+            let response = reqwest::get(format!("{}/consensus", authority.url())).await?;
+            if response.status().is_success() {
+                let signed: SignedConsensus = response.json().await?;
+                let consensus = signed.verify(&[key.verifying_key()], 1, SystemTime::now())?;
+                let probed = consensus
+                    .nodes
+                    .iter()
+                    .any(|node| node.flags.contains(&Flag::Running));
+                if consensus.nodes.len() == 9 && probed {
+                    return anyhow::Ok(consensus);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await??;
+    let running = consensus
+        .nodes
+        .iter()
+        .filter(|node| node.flags.contains(&Flag::Running))
+        .map(|node| node.identity.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(running, vec![node.identity()]);
+    assert_eq!(
+        consensus
+            .valid_nodes()
+            .map(|node| node.identity.clone())
+            .collect::<Vec<_>>(),
+        running
+    );
+
+    for listener in silent {
+        listener.abort();
+    }
+    node.shutdown().await;
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn registrations_are_limited_and_approved() -> anyhow::Result<()> {
+    init_tracing();
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let authority = Authority::new(key.clone())
+        .voting_interval(Duration::from_secs(1))
+        .registration_limits(RegistrationLimits {
+            per_ip: 2,
+            ..Default::default()
+        })
+        .require_approval(true)
+        .admin_token("secret")
+        .start()
+        .await?;
+    let url = authority.url();
+    let http = reqwest::Client::new();
+    let register = |info: NodeInfo, port: u16| {
+        let url = url.clone();
+        async move {
+            let descriptor = info.descriptor(
+                SocketAddr::from(([127, 0, 0, 1], port)),
+                &ExitPolicy::default(),
+                None,
+                SystemTime::now(),
+            );
+            node_directory::add_node(&url, &info, descriptor).await
+        }
+    };
+
+    let approved = NodeInfo::default();
+    register(approved.clone(), 1).await?;
+    // A challenge is only good for one registration
+    let challenge: String = http
+        .get(format!("{}/challenge", url))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let registration = approved.register(
+        approved.descriptor(
+            "127.0.0.1:1".parse()?,
+            &ExitPolicy::default(),
+            None,
+            SystemTime::now(),
+        ),
+        &challenge,
+    );
+    let post = || {
+        http.post(format!("{}/add_node", url))
+            .json(&registration)
+            .send()
+    };
+    assert_eq!(post().await?.status(), reqwest::StatusCode::OK);
+    assert_eq!(post().await?.status(), reqwest::StatusCode::FORBIDDEN);
+
+    let rejected = NodeInfo::default();
+    register(rejected.clone(), 2).await?;
+    // Every new node counts towards the address' limit
+    assert!(register(NodeInfo::default(), 3).await.is_err());
+    assert_eq!(registered_identities(&authority).await?.len(), 2);
+
+    let admin = |path: &str, token: &str| {
+        http.post(format!("{}/admin/{}", url, path))
+            .bearer_auth(token)
+            .send()
+    };
+    assert_eq!(
+        admin(&format!("approve/{}", approved.keys.identity()), "wrong")
+            .await?
+            .status(),
+        reqwest::StatusCode::UNAUTHORIZED
+    );
+    let pending = |token| {
+        http.get(format!("{}/admin/pending", url))
+            .bearer_auth(token)
+            .send()
+    };
+    let pending_identities = |response: serde_json::Value| {
+        response
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|node| node["identity"].as_str().unwrap_or_default().to_string())
+            .collect::<BTreeSet<_>>()
+    };
+    let listed = pending_identities(pending("secret").await?.json().await?);
+    assert_eq!(
+        listed,
+        BTreeSet::from([approved.keys.identity(), rejected.keys.identity()])
+    );
+    admin(&format!("approve/{}", approved.keys.identity()), "secret")
+        .await?
+        .error_for_status()?;
+    admin(&format!("reject/{}", rejected.keys.identity()), "secret")
+        .await?
+        .error_for_status()?;
+    assert!(pending_identities(pending("secret").await?.json().await?).is_empty());
+    assert_eq!(
+        registered_identities(&authority).await?,
+        vec![approved.keys.identity()]
+    );
+
+    // Only the approved node makes it into the consensus
+    let consensus = timeout(Duration::from_secs(5), async {
+        loop {
+            let response = http.get(format!("{}/consensus", url)).send().await?;
+            if response.status().is_success() {
+                let signed: SignedConsensus = response.json().await?;
+                let consensus = signed.verify(&[key.verifying_key()], 1, SystemTime::now())?;
+                if !consensus.nodes.is_empty() {
+                    return anyhow::Ok(consensus);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await??;
+    let identities = consensus
+        .nodes
+        .iter()
+        .map(|node| node.identity.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(identities, vec![approved.keys.identity()]);
+
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn junk_registrations_dont_use_up_limits() -> anyhow::Result<()> {
+    init_tracing();
+
+    let authority = Authority::new(SigningKey::generate(&mut rand::rngs::OsRng))
+        .registration_limits(RegistrationLimits {
+            per_ip: 2,
+            per_subnet: 2,
+            ..Default::default()
+        })
+        .start()
+        .await?;
+    let url = authority.url();
+    let http = reqwest::Client::new();
+    let descriptor = |info: &NodeInfo, port: u16| {
+        info.descriptor(
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            &ExitPolicy::default(),
+            None,
+            SystemTime::now(),
+        )
+    };
+
+    // Forged signatures and made up challenges from the same address
+    let attacker = NodeInfo::default();
+    for i in 0..10 {
+        let mut forged = attacker.register(descriptor(&attacker, 1), "made up");
+        if i % 2 == 0 {
+            forged.challenge = "changed".to_string();
+        }
+        let status = http
+            .post(format!("{}/add_node", url))
+            .json(&forged)
+            .send()
+            .await?
+            .status();
+        assert!(status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let honest = NodeInfo::default();
+    let signed = descriptor(&honest, 2);
+    node_directory::add_node(&url, &honest, signed.clone()).await?;
+    // Known nodes registering again, like after a restart, don't count
+    for _ in 0..3 {
+        node_directory::add_node(&url, &honest, signed.clone()).await?;
+    }
+    let second = NodeInfo::default();
+    node_directory::add_node(&url, &second, descriptor(&second, 3)).await?;
+    let third = NodeInfo::default();
+    assert!(
+        node_directory::add_node(&url, &third, descriptor(&third, 4))
+            .await
+            .is_err()
+    );
+    assert_eq!(registered_identities(&authority).await?.len(), 2);
+
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn authorities_measure_bandwidth() -> anyhow::Result<()> {
+    init_tracing();
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let authority = Authority::new(key.clone())
+        .probe_interval(Duration::from_millis(100))
+        .voting_interval(Duration::from_secs(1))
+        .start()
+        .await?;
+    let info = NodeInfo::default();
+    let node = NodeServer::new()
+        .directory(authority.url())
+        .keys(info.keys.clone())
+        .start()
+        .await?;
+    // The node's own claims are ignored
+    let heartbeat = info.heartbeat(node.advertised_addr(), 0, u64::MAX, SystemTime::now());
+    assert!(node_directory::heartbeat(&authority.url(), &heartbeat).await?);
+
+    let bandwidth = timeout(Duration::from_secs(10), async {
+        loop {
+            let response = reqwest::get(format!("{}/consensus", authority.url())).await?;
+            if response.status().is_success() {
+                let signed: SignedConsensus = response.json().await?;
+                let consensus = signed.verify(&[key.verifying_key()], 1, SystemTime::now())?;
+                if let Some(bandwidth) = consensus.nodes.first().and_then(|node| node.bandwidth) {
+                    return anyhow::Ok(bandwidth);
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await??;
+    assert!(bandwidth > 0 && bandwidth < u64::MAX, "{}", bandwidth);
+    // Measuring relayed the data through the node
+    assert!(node.stats().bytes_forward > 0);
+
+    node.shutdown().await;
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn families_count_running_members() -> anyhow::Result<()> {
+    init_tracing();
+
+    let authority = Authority::new(SigningKey::generate(&mut rand::rngs::OsRng))
+        .probe_interval(Duration::from_millis(100))
+        .registration_limits(RegistrationLimits {
+            per_ip: 1000,
+            per_subnet: 1000,
+            max_family_size: 1,
+            ..Default::default()
+        })
+        .start()
+        .await?;
+    let url = authority.url();
+    let register = |port: u16| {
+        let url = url.clone();
+        async move {
+            let info = NodeInfo {
+                family: Some("victim".to_string()),
+                ..Default::default()
+            };
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let descriptor = info.descriptor(addr, &ExitPolicy::default(), None, SystemTime::now());
+            node_directory::add_node(&url, &info, descriptor).await
+        }
+    };
+
+    // Unreachable nodes claiming the family don't lock its operator out
+    register(1).await?;
+    let node = NodeServer::new()
+        .directory(authority.url())
+        .family(Some("victim".to_string()))
+        .start()
+        .await?;
+    // Once the node is probed the family is full
+    let refused = timeout(Duration::from_secs(5), async {
+        for port in 2.. {
+            if let Err(err) = register(port).await {
+                return err;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        unreachable!()
+    })
+    .await?;
+    assert!(
+        format!("{:#}", refused).contains("Family victim"),
+        "{:#}",
+        refused
+    );
+
+    node.shutdown().await;
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn registrations_cant_take_over_nodes() -> anyhow::Result<()> {
+    init_tracing();
+
+    let authority = Authority::new(SigningKey::generate(&mut rand::rngs::OsRng))
+        .start()
+        .await?;
+    let url = authority.url();
+    let addr = "127.0.0.1:1".parse()?;
+    let descriptor = |info: &NodeInfo, published: SystemTime| {
+        info.descriptor(addr, &ExitPolicy::default(), None, published)
+    };
+    let now = SystemTime::now();
+    let node = NodeInfo::default();
+    node_directory::add_node(&url, &node, descriptor(&node, now)).await?;
+
+    // Another identity can't claim the address while the node is alive
+    let impostor = NodeInfo::default();
+    assert!(
+        node_directory::add_node(&url, &impostor, descriptor(&impostor, now))
+            .await
+            .is_err()
+    );
+    // An older descriptor of the node can't replace the current one
+    let older = descriptor(&node, now - Duration::from_secs(60));
+    assert!(node_directory::add_node(&url, &node, older).await.is_err());
+    let newer = descriptor(&node, now + Duration::from_secs(1));
+    node_directory::add_node(&url, &node, newer).await?;
+    assert_eq!(
+        registered_identities(&authority).await?,
+        vec![node.keys.identity()]
+    );
+
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn admins_flag_and_ban_nodes() -> anyhow::Result<()> {
+    init_tracing();
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let authority = Authority::new(key.clone())
+        .voting_interval(Duration::from_secs(1))
+        .admin_token("secret")
+        .start()
+        .await?;
+    let url = authority.url();
+    let http = reqwest::Client::new();
+    let register = |info: NodeInfo, addr: &str| {
+        let url = url.clone();
+        let addr = addr.parse::<SocketAddr>();
+        async move {
+            let descriptor =
+                info.descriptor(addr?, &ExitPolicy::default(), None, SystemTime::now());
+            node_directory::add_node(&url, &info, descriptor).await
+        }
+    };
+    let get = |path: &str| {
+        http.get(format!("{}/admin/{}", url, path))
+            .bearer_auth("secret")
+            .send()
+    };
+    let post = |path: &str, body: serde_json::Value| {
+        http.post(format!("{}/admin/{}", url, path))
+            .bearer_auth("secret")
+            .json(&body)
+            .send()
+    };
+
+    let flagged = NodeInfo::default();
+    let banned = NodeInfo::default();
+    register(flagged.clone(), "127.0.0.2:9001").await?;
+    register(banned.clone(), "127.0.0.3:9001").await?;
+
+    post(
+        &format!("flags/{}", flagged.keys.identity()),
+        serde_json::json!({"assign": ["BadExit"], "remove": ["Guard"]}),
+    )
+    .await?
+    .error_for_status()?;
+    let nodes: serde_json::Value = get("nodes").await?.error_for_status()?.json().await?;
+    let report = nodes
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|node| node["identity"] == flagged.keys.identity())
+        .expect("Flagged node is listed");
+    assert_eq!(report["flags"], serde_json::json!(["BadExit"]));
+    assert_eq!(report["approved"], true);
+    assert_eq!(nodes.as_array().map(Vec::len), Some(2));
+
+    // The next consensus carries the flag
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let response = http.get(format!("{}/consensus", url)).send().await?;
+            if response.status().is_success() {
+                let signed: SignedConsensus = response.json().await?;
+                let consensus = signed.verify(&[key.verifying_key()], 1, SystemTime::now())?;
+                if consensus.nodes.iter().any(|node| {
+                    node.identity == flagged.keys.identity() && node.flags.contains(&Flag::BadExit)
+                }) {
+                    return anyhow::Ok(());
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await??;
+
+    // Banned nodes are dropped at once and can't register again
+    post("ban", serde_json::json!({"addr": "127.0.0.3"}))
+        .await?
+        .error_for_status()?;
+    assert_eq!(
+        registered_identities(&authority).await?,
+        vec![flagged.keys.identity()]
+    );
+    assert!(register(banned.clone(), "127.0.0.3:9002").await.is_err());
+    post(
+        "ban",
+        serde_json::json!({"identity": flagged.keys.identity()}),
+    )
+    .await?
+    .error_for_status()?;
+    assert!(registered_identities(&authority).await?.is_empty());
+    assert!(register(flagged.clone(), "127.0.0.4:9001").await.is_err());
+
+    post("unban", serde_json::json!({"addr": "127.0.0.3"}))
+        .await?
+        .error_for_status()?;
+    register(banned.clone(), "127.0.0.3:9001").await?;
+    assert_eq!(
+        post("unban", serde_json::json!({"addr": "127.0.0.3"}))
+            .await?
+            .status(),
+        reqwest::StatusCode::NOT_FOUND
+    );
+
+    let audit: serde_json::Value = get("audit").await?.error_for_status()?.json().await?;
+    let actions = audit
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry["action"].as_object()?.keys().next().cloned())
+        .collect::<Vec<_>>();
+    assert_eq!(actions, ["flags", "ban", "ban", "unban"]);
+    assert_eq!(
+        http.get(format!("{}/admin/audit", url))
+            .send()
+            .await?
+            .status(),
+        reqwest::StatusCode::UNAUTHORIZED
+    );
+
+    authority.shutdown().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn directory_switch_is_all_or_nothing() -> anyhow::Result<()> {
+    init_tracing();
+
+    let start = || Authority::new(SigningKey::generate(&mut rand::rngs::OsRng)).start();
+    let (first, second) = (start().await?, start().await?);
+    let node = NodeServer::new().directory(first.url()).start().await?;
+    let unreachable = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+    // The reachable directory added along with an unreachable one is rolled back
+    let failed = node
+        .update_directories(vec![second.url(), format!("http://{}", unreachable)])
+        .await;
+    assert!(failed.is_err());
+    assert_eq!(node.directories(), vec![first.url()]);
+    assert!(registered_identities(&second).await?.is_empty());
+    assert_eq!(registered_identities(&first).await?, vec![node.identity()]);
+
+    node.update_directories(vec![second.url()]).await?;
+    assert_eq!(node.directories(), vec![second.url()]);
+    assert!(registered_identities(&first).await?.is_empty());
+    assert_eq!(registered_identities(&second).await?, vec![node.identity()]);
+
+    node.shutdown().await;
+    first.shutdown().await;
+    second.shutdown().await;
+    Ok(())
 }
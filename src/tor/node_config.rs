@@ -0,0 +1,396 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{level_filters::LevelFilter, warn};
+
+use crate::logging::{self, LogFormat};
+
+use super::{
+    bandwidth::BandwidthLimit,
+    control::ControlConfig,
+    descriptor::{valid_nickname, NodeKeys, MAX_NICKNAME_LENGTH},
+    exit_policy::ExitPolicy,
+    node_directory::DEFAULT_DIRECTORY,
+    node_server::{NodeHandle, NodeLimits, NodeServer, NodeSettings},
+};
+
+/// Node configuration, loaded from a TOML file
+///
+/// ```toml
+/// bind_address = "0.0.0.0:9001"
+/// advertised_address = "203.0.113.5:9001"
+/// directory_urls = ["http://localhost:30000"]
+/// nickname = "myrelay"
+/// family = "myrelays"
+/// exit_policy = ["accept *:443", "reject *:*"]
+///
+/// [bandwidth]
+/// rate = 1048576
+/// burst = 2097152
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Address the node listens on
+    pub bind_address: SocketAddr,
+    /// Address other nodes and clients reach the node at, defaults to the bound address
+    pub advertised_address: Option<SocketAddr>,
+    pub directory_urls: Vec<String>,
+    pub nickname: String,
+    pub contact_info: Option<String>,
+    /// Shared by every node of the operator so clients never use two of them in one circuit
+    pub family: Option<String>,
+    pub exit_policy: ExitPolicy,
+    /// Node wide bandwidth limit, unlimited when missing
+    pub bandwidth: Option<BandwidthLimit>,
+    pub max_circuits: Option<u64>,
+    /// Where the node's keys are kept, the node gets a new identity on every start when missing
+    pub data_directory: Option<PathBuf>,
+    /// Seconds existing circuits get to finish on shutdown
+    pub drain_secs: u64,
+    /// Seconds between heartbeats to the directories
+    pub heartbeat_secs: u64,
+    /// Maximum log level, `RUST_LOG` can only lower it
+    pub log_level: String,
+    pub log_format: LogFormat,
+    /// Loopback address for the control port, disabled when missing
+    pub control_address: Option<SocketAddr>,
+    pub control_password: Option<String>,
+    /// Address prometheus metrics are served at, disabled when missing
+    pub metrics_address: Option<SocketAddr>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+            advertised_address: None,
+            directory_urls: vec![DEFAULT_DIRECTORY.to_string()],
+            nickname: "Unnamed".to_string(),
+            contact_info: None,
+            family: None,
+            exit_policy: ExitPolicy::default(),
+            bandwidth: None,
+            max_circuits: None,
+            data_directory: None,
+            drain_secs: 10,
+            heartbeat_secs: 60,
+            log_level: "info".to_string(),
+            log_format: LogFormat::default(),
+            control_address: None,
+            control_password: None,
+            metrics_address: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed reading config file {}", path.display()))?;
+        let config = toml::from_str(&content)
+            .with_context(|| format!("Failed parsing config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Checks the configuration and reports every problem found
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = vec![];
+
+        if self.advertised_address.is_none() && self.bind_address.ip().is_unspecified() {
+            errors.push(format!(
+                "bind_address {} is unspecified, advertised_address must be set",
+                self.bind_address
+            ));
+        }
+        if let Some(advertised) = self.advertised_address {
+            if advertised.ip().is_unspecified() || advertised.port() == 0 {
+                errors.push(format!(
+                    "advertised_address {} must have a concrete ip and port",
+                    advertised
+                ));
+            }
+        }
+
+        if self.directory_urls.is_empty() {
+            errors.push("directory_urls must contain at least one url".to_string());
+        }
+        for url in &self.directory_urls {
+            if reqwest::Url::parse(url)
+                .map_or(true, |url| !matches!(url.scheme(), "http" | "https"))
+            {
+                errors.push(format!("directory url {:?} is not a valid http url", url));
+            }
+        }
+
+        if !valid_nickname(&self.nickname) {
+            errors.push(format!(
+                "nickname {:?} must be 1-{} ascii letters or digits",
+                self.nickname, MAX_NICKNAME_LENGTH
+            ));
+        }
+
+        if self.heartbeat_secs == 0 {
+            errors.push("heartbeat_secs must be positive".to_string());
+        }
+
+        if let Some(bandwidth) = self.bandwidth {
+            if bandwidth.rate == 0 {
+                errors.push("bandwidth.rate must be positive".to_string());
+            }
+            if bandwidth.burst < bandwidth.rate {
+                errors.push(format!(
+                    "bandwidth.burst {} must be at least bandwidth.rate {}",
+                    bandwidth.burst, bandwidth.rate
+                ));
+            }
+        }
+
+        if self.max_circuits == Some(0) {
+            errors.push("max_circuits must be positive".to_string());
+        }
+
+        if self.log_level.parse::<LevelFilter>().is_err() {
+            errors.push(format!(
+                "log_level {:?} must be one of off, error, warn, info, debug or trace",
+                self.log_level
+            ));
+        }
+
+        if let Some(control_address) = self.control_address {
+            if !control_address.ip().is_loopback() {
+                errors.push(format!(
+                    "control_address {} must be a loopback address",
+                    control_address
+                ));
+            }
+            if self
+                .control_password
+                .as_ref()
+                .is_none_or(|password| password.is_empty())
+            {
+                errors.push("control_password must be set to use the control port".to_string());
+            }
+        }
+
+        if let Some(data_directory) = &self.data_directory {
+            if data_directory.exists() && !data_directory.is_dir() {
+                errors.push(format!(
+                    "data_directory {} is not a directory",
+                    data_directory.display()
+                ));
+            }
+        }
+
+        if !errors.is_empty() {
+            anyhow::bail!("Invalid node configuration:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.log_level.parse().unwrap_or(LevelFilter::INFO)
+    }
+
+    /// Settings new circuits are started with
+    pub fn settings(&self) -> NodeSettings {
+        NodeSettings {
+            exit_policy: self.exit_policy.clone(),
+            limits: NodeLimits {
+                max_circuits: self.max_circuits,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Loads the node's keys from the data directory, which has to exist
+    pub fn node_server(&self) -> anyhow::Result<NodeServer> {
+        let settings = self.settings();
+        let mut server = NodeServer::new()
+            .bind(self.bind_address)
+            .nickname(&self.nickname)
+            .contact(self.contact_info.clone())
+            .family(self.family.clone())
+            .exit_policy(settings.exit_policy)
+            .limits(settings.limits)
+            .bandwidth(self.bandwidth)
+            .drain_period(Duration::from_secs(self.drain_secs))
+            .heartbeat_interval(Duration::from_secs(self.heartbeat_secs));
+        if let Some(advertised) = self.advertised_address {
+            server = server.advertise(advertised);
+        }
+        for directory in &self.directory_urls {
+            server = server.directory(directory);
+        }
+        if let (Some(address), Some(password)) = (self.control_address, &self.control_password) {
+            server = server.control(ControlConfig {
+                address,
+                password: password.clone(),
+            });
+        }
+        if let Some(metrics_address) = self.metrics_address {
+            server = server.metrics(metrics_address);
+        }
+        if let Some(data_directory) = &self.data_directory {
+            server = server.keys(NodeKeys::load_or_generate(data_directory)?);
+        }
+        Ok(server)
+    }
+
+    /// Options that changed in `new` but only take effect after a restart
+    pub fn restart_required(&self, new: &NodeConfig) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.bind_address != new.bind_address {
+            changed.push("bind_address");
+        }
+        if self.advertised_address != new.advertised_address {
+            changed.push("advertised_address");
+        }
+        if self.nickname != new.nickname {
+            changed.push("nickname");
+        }
+        if self.contact_info != new.contact_info {
+            changed.push("contact_info");
+        }
+        if self.family != new.family {
+            changed.push("family");
+        }
+        if self.log_format != new.log_format {
+            changed.push("log_format");
+        }
+        if self.data_directory != new.data_directory {
+            changed.push("data_directory");
+        }
+        if self.drain_secs != new.drain_secs {
+            changed.push("drain_secs");
+        }
+        if self.heartbeat_secs != new.heartbeat_secs {
+            changed.push("heartbeat_secs");
+        }
+        if self.control_address != new.control_address {
+            changed.push("control_address");
+        }
+        if self.control_password != new.control_password {
+            changed.push("control_password");
+        }
+        if self.metrics_address != new.metrics_address {
+            changed.push("metrics_address");
+        }
+        changed
+    }
+
+    /// Applies the options that can change while the node is running.
+    /// Existing circuits keep the exit policy and limits they started with. Nothing is applied
+    /// when the node can't switch to the new directories.
+    pub async fn apply_live(&self, node: &NodeHandle) -> anyhow::Result<()> {
+        // The published descriptor has to describe the new settings, they're put back when
+        // the directories refuse the node
+        let (settings, bandwidth) = (node.settings(), node.bandwidth());
+        node.update_settings(self.settings());
+        node.update_bandwidth(self.bandwidth);
+        if let Err(err) = node.update_directories(self.directory_urls.clone()).await {
+            node.update_settings(NodeSettings::clone(&settings));
+            node.update_bandwidth(bandwidth);
+            return Err(err);
+        }
+        if let Err(err) = logging::set_level(self.log_level()) {
+            warn!("Failed changing the log level: {:?}", err);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_config() -> anyhow::Result<()> {
+        let config: NodeConfig = toml::from_str(
+            r#"
+            bind_address = "0.0.0.0:9001"
+            advertised_address = "10.0.0.5:9001"
+            directory_urls = ["http://10.0.0.1:30000", "https://dir.example.com"]
+            nickname = "relay1"
+            contact_info = "admin@example.com"
+            family = "example"
+            exit_policy = ["accept *:443", "reject *:*"]
+            max_circuits = 100
+            data_directory = "/var/lib/rustor"
+            drain_secs = 30
+
+            [bandwidth]
+            rate = 1000
+            burst = 2000
+            "#,
+        )?;
+        config.validate()?;
+
+        assert_eq!(config.advertised_address, Some("10.0.0.5:9001".parse()?));
+        assert!(config.exit_policy.allows(&"1.1.1.1:443".parse()?));
+        assert!(!config.exit_policy.allows(&"1.1.1.1:80".parse()?));
+        assert_eq!(
+            config.bandwidth,
+            Some(BandwidthLimit {
+                rate: 1000,
+                burst: 2000
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn example_config_is_valid() -> anyhow::Result<()> {
+        let config: NodeConfig = toml::from_str(include_str!("../../node.example.toml"))?;
+        config.validate()
+    }
+
+    #[test]
+    fn defaults_are_valid() -> anyhow::Result<()> {
+        let config: NodeConfig = toml::from_str("")?;
+        assert_eq!(config, NodeConfig::default());
+        config.validate()
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let config = NodeConfig {
+            bind_address: "0.0.0.0:9001".parse().unwrap(),
+            directory_urls: vec!["localhost:30000".to_string()],
+            nickname: "not a nickname".to_string(),
+            bandwidth: Some(BandwidthLimit { rate: 0, burst: 0 }),
+            ..Default::default()
+        };
+
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("advertised_address must be set"));
+        assert!(error.contains("not a valid http url"));
+        assert!(error.contains("nickname"));
+        assert!(error.contains("bandwidth.rate"));
+    }
+
+    #[test]
+    fn restart_required_changes() -> anyhow::Result<()> {
+        let config = NodeConfig::default();
+        let reloaded = NodeConfig {
+            bind_address: "127.0.0.1:9001".parse()?,
+            exit_policy: ExitPolicy::reject_all(),
+            log_level: "debug".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.restart_required(&reloaded), vec!["bind_address"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        assert!(toml::from_str::<NodeConfig>(r#"exit_policy = ["allow *:*"]"#).is_err());
+        assert!(toml::from_str::<NodeConfig>(r#"unknown_field = 1"#).is_err());
+    }
+}
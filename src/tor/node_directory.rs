@@ -1,36 +1,282 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    time::SystemTime,
+};
 
 use const_format::concatcp;
+use ed25519_dalek::VerifyingKey;
 use reqwest;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use super::{
+    consensus::{
+        Consensus, ConsensusError, ConsensusNode, NodeQuery, NodeSelection, SignedConsensus,
+    },
+    descriptor::{NodeDescriptor, NodeInfo, SignedDescriptor, SignedHeartbeat},
+    error::TorError,
+};
 
 const PORT: u16 = 30000;
-const BASE_URL: &str = concatcp!("http://localhost:", PORT);
+pub const DEFAULT_DIRECTORY: &str = concatcp!("http://localhost:", PORT);
 
-pub async fn add_node(node: &SocketAddr) -> anyhow::Result<()> {
+/// Uploads the node's descriptor, replacing the one it registered before. The node signs a
+/// challenge of the directory with its identity key to prove it owns the descriptor
+pub async fn add_node(
+    directory: &str,
+    info: &NodeInfo,
+    descriptor: SignedDescriptor,
+) -> anyhow::Result<()> {
     let client = reqwest::Client::new();
-    let _ = client
-        .post(concatcp!(BASE_URL, "/add_node"))
-        .json(&node)
+    let challenge = challenge(&client, directory).await?;
+    let response = client
+        .post(format!("{}/add_node", directory))
+        .json(&info.register(descriptor, &challenge))
         .send()
-        .await?
-        .error_for_status()?;
+        .await?;
+    if let Err(err) = response.error_for_status_ref() {
+        let reason = response.text().await.unwrap_or_default();
+        return Err(anyhow::Error::new(err).context(reason));
+    }
 
     Ok(())
 }
 
-pub async fn get_nodes(n: u8) -> anyhow::Result<Vec<SocketAddr>> {
-    // Making GET request to /get_nodes endpoint
+/// Reports that the node is still up, false when the directory doesn't know the node and it has
+/// to register again
+pub async fn heartbeat(directory: &str, heartbeat: &SignedHeartbeat) -> anyhow::Result<bool> {
     let client = reqwest::Client::new();
     let response = client
-        .get(concatcp!(BASE_URL, "/get_nodes"))
-        .query(&[("amount", n)])
+        .post(format!("{}/heartbeat", directory))
+        .json(heartbeat)
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// Removes the node from the directory, signing a challenge of the directory with the identity
+/// key registered at `node`
+pub async fn remove_node(directory: &str, info: &NodeInfo, node: SocketAddr) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let challenge = challenge(&client, directory).await?;
+    let _ = client
+        .post(format!("{}/remove_node", directory))
+        .json(&info.deregister(node, &challenge))
         .send()
         .await?
         .error_for_status()?;
 
-    let nodes: Vec<SocketAddr> = response.json().await?;
+    Ok(())
+}
+
+/// A challenge for the node to sign, good for one registration or deregistration
+async fn challenge(client: &reqwest::Client, directory: &str) -> anyhow::Result<String> {
+    let challenge = client
+        .get(format!("{}/challenge", directory))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(challenge)
+}
+
+async fn fetch<T: DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T, TorError> {
+    request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|err| TorError::DirectoryUnavailable(err.into()))?
+        .json()
+        .await
+        .map_err(|err| TorError::DirectoryUnavailable(err.into()))
+}
+
+/// The descriptor of the node with `identity`, checked against its own signature only
+pub async fn get_descriptor(directory: &str, identity: &str) -> Result<NodeDescriptor, TorError> {
+    let client = reqwest::Client::new();
+    let descriptor: SignedDescriptor =
+        fetch(client.get(format!("{}/descriptor/{}", directory, identity))).await?;
+    descriptor
+        .verify(SystemTime::now())
+        .map_err(|err| TorError::DirectoryUnavailable(err.into()))
+}
+
+fn query_pairs(query: &NodeQuery) -> Vec<(&'static str, String)> {
+    let join = |items: Vec<String>| items.join(",");
+    let mut pairs = vec![("amount", query.amount.to_string())];
+    if !query.flags.is_empty() {
+        pairs.push((
+            "flags",
+            join(query.flags.iter().map(ToString::to_string).collect()),
+        ));
+    }
+    if let Some(port) = query.port {
+        pairs.push(("port", port.to_string()));
+    }
+    if !query.exclude.is_empty() {
+        pairs.push(("exclude", join(query.exclude.iter().cloned().collect())));
+    }
+    pairs
+}
+
+/// The consensus and the descriptors of its nodes, unverified
+pub async fn get_network(directory: &str) -> Result<NetworkDocument, TorError> {
+    let client = reqwest::Client::new();
+    Ok(NetworkDocument {
+        consensus: fetch(client.get(format!("{}/consensus", directory))).await?,
+        descriptors: fetch(client.get(format!("{}/descriptors", directory))).await?,
+    })
+}
+
+/// The consensus with the descriptors of its nodes, all a client needs to select paths itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkDocument {
+    pub consensus: SignedConsensus,
+    pub descriptors: Vec<SignedDescriptor>,
+}
+
+impl NetworkDocument {
+    /// Checks that at least `threshold` of `authorities` signed the consensus and that it's
+    /// current. Nodes whose descriptor is missing or doesn't match the consensus are left out.
+    pub fn verify(
+        &self,
+        authorities: &[VerifyingKey],
+        threshold: usize,
+        now: SystemTime,
+    ) -> Result<Network, TorError> {
+        let mut consensus = self.consensus.verify(authorities, threshold, now)?;
+        let descriptors = self
+            .descriptors
+            .iter()
+            .map(|descriptor| (descriptor.digest(), descriptor))
+            .collect::<HashMap<_, _>>();
+        let descriptors = consensus
+            .valid_nodes()
+            .filter_map(|node| Some((node.identity.clone(), matching(node, &descriptors, now)?)))
+            .collect::<HashMap<_, _>>();
+        consensus
+            .nodes
+            .retain(|node| descriptors.contains_key(&node.identity));
+        Ok(Network {
+            consensus,
+            descriptors,
+        })
+    }
+}
 
-    Ok(nodes)
+/// A verified consensus, paths are selected from it without asking the directory
+#[derive(Debug, Clone)]
+pub struct Network {
+    /// Only the valid nodes with a matching descriptor
+    consensus: Consensus,
+    descriptors: HashMap<String, NodeDescriptor>,
+}
+
+impl Network {
+    pub fn consensus(&self) -> &Consensus {
+        &self.consensus
+    }
+
+    /// Descriptors of every usable node, in consensus order
+    pub fn nodes(&self) -> Vec<NodeDescriptor> {
+        self.consensus
+            .nodes
+            .iter()
+            .map(|node| self.descriptors[&node.identity].clone())
+            .collect()
+    }
+
+    /// Up to `query.amount` nodes matching the query, picked weighted by bandwidth like the
+    /// directory's `/get_nodes` but without telling the directory
+    pub fn get_nodes(&self, query: &NodeQuery) -> Vec<NodeDescriptor> {
+        self.consensus
+            .select(query, &mut rand::thread_rng())
+            .into_iter()
+            .map(|node| self.descriptors[&node.identity].clone())
+            .collect()
+    }
+}
+
+/// The descriptor `node` refers to, when it's valid and describes the node
+fn matching(
+    node: &ConsensusNode,
+    descriptors: &HashMap<String, &SignedDescriptor>,
+    now: SystemTime,
+) -> Option<NodeDescriptor> {
+    let Some(descriptor) = descriptors.get(&node.descriptor) else {
+        warn!(addr = %node.addr, "Directory has no descriptor for the node");
+        return None;
+    };
+    match descriptor.verify(now) {
+        Ok(descriptor) if descriptor.identity == node.identity && descriptor.addr == node.addr => {
+            Some(descriptor)
+        }
+        Ok(_) => {
+            warn!(addr = %node.addr, "Descriptor doesn't match the consensus");
+            None
+        }
+        Err(err) => {
+            warn!(addr = %node.addr, "Invalid descriptor: {}", err);
+            None
+        }
+    }
+}
+
+/// Descriptors of the nodes the directory picked for `query` from its consensus, which at least
+/// `threshold` of `authorities` have to have signed. Picks that aren't in the consensus or don't
+/// match the query fail, nodes whose descriptor doesn't match the consensus are left out.
+///
+/// The directory learns which nodes the caller is about to use, clients select paths from
+/// [`get_network`] instead.
+pub async fn query_nodes(
+    directory: &str,
+    query: &NodeQuery,
+    authorities: &[VerifyingKey],
+    threshold: usize,
+) -> Result<Vec<NodeDescriptor>, TorError> {
+    let client = reqwest::Client::new();
+    let selection: NodeSelection = fetch(
+        client
+            .get(format!("{}/get_nodes", directory))
+            .query(&query_pairs(query)),
+    )
+    .await?;
+    let now = SystemTime::now();
+    let consensus = selection.consensus.verify(authorities, threshold, now)?;
+    let mut seen = BTreeSet::new();
+    let picked = selection
+        .nodes
+        .iter()
+        .take(query.amount)
+        .map(|identity| {
+            consensus
+                .nodes
+                .iter()
+                .find(|node| node.identity == *identity && query.matches(node))
+                .filter(|_| seen.insert(identity))
+                .ok_or_else(|| ConsensusError::BadSelection {
+                    identity: identity.clone(),
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let descriptors: Vec<SignedDescriptor> =
+        fetch(client.get(format!("{}/descriptors", directory))).await?;
+    let descriptors = descriptors
+        .iter()
+        .map(|descriptor| (descriptor.digest(), descriptor))
+        .collect::<HashMap<_, _>>();
+
+    Ok(picked
+        .into_iter()
+        .filter_map(|node| matching(node, &descriptors, now))
+        .collect())
 }
 
 #[cfg(test)]
@@ -38,14 +284,36 @@ mod tests {
     use std::net::{Ipv4Addr, SocketAddrV4};
 
     use super::*;
+    use crate::tor::{exit_policy::ExitPolicy, keys::parse_key};
 
+    #[tokio::test]
+    #[ignore = "requires a running node_directory with its key in directory.key.pub"]
     async fn add_nodes() -> anyhow::Result<()> {
         let node = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 123));
+        let authority = parse_key(&std::fs::read_to_string("directory.key.pub")?)?;
+        let info = NodeInfo::default();
 
-        add_node(&node).await?;
-        let nodes = get_nodes(3).await?;
-
+        add_node(
+            DEFAULT_DIRECTORY,
+            &info,
+            info.descriptor(node, &ExitPolicy::default(), None, SystemTime::now()),
+        )
+        .await?;
+        let descriptor = get_descriptor(DEFAULT_DIRECTORY, &info.keys.identity()).await?;
+        assert_eq!(descriptor.addr, node);
+        let query = NodeQuery {
+            amount: 3,
+            ..Default::default()
+        };
+        let nodes = query_nodes(DEFAULT_DIRECTORY, &query, &[authority], 1).await?;
         assert!(!nodes.is_empty());
+
+        let network =
+            get_network(DEFAULT_DIRECTORY)
+                .await?
+                .verify(&[authority], 1, SystemTime::now())?;
+        assert!(!network.nodes().is_empty());
+        assert!(!network.get_nodes(&query).is_empty());
         Ok(())
     }
 }
@@ -0,0 +1,710 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntGauge, Registry};
+use serde::Serialize;
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle, time::timeout};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, field, info, info_span, warn, Instrument};
+
+use crate::metrics;
+
+use super::{
+    bandwidth::{BandwidthLimit, BandwidthLimiter},
+    circuit_registry::CircuitRegistry,
+    consensus::unix_secs,
+    control::{self, ControlConfig, ControlContext},
+    descriptor::{NodeInfo, NodeKeys, SignedDescriptor},
+    exit_policy::ExitPolicy,
+    node::{handle_connection, CircuitContext},
+    node_directory,
+};
+
+/// Shortest time between heartbeats, shorter intervals are raised to it
+pub const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct NodeLimits {
+    /// Maximum amount of circuits going through the node at once, `None` for unlimited
+    pub max_circuits: Option<u64>,
+    /// Timeout for connecting to the next hop or the server
+    pub connect_timeout: Duration,
+}
+
+impl Default for NodeLimits {
+    fn default() -> Self {
+        Self {
+            max_circuits: None,
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Settings every new circuit takes a snapshot of
+#[derive(Debug, Clone, Default)]
+pub struct NodeSettings {
+    pub limits: NodeLimits,
+    pub exit_policy: ExitPolicy,
+}
+
+/// Node wide counters, also exported as prometheus metrics
+pub struct NodeStats {
+    pub(crate) circuits_opened: IntCounter,
+    pub(crate) circuits_failed: IntCounter,
+    pub(crate) circuits_active: IntGauge,
+    pub(crate) bytes_forward: IntCounter,
+    pub(crate) bytes_backward: IntCounter,
+    pub(crate) decrypt_failures: IntCounter,
+    pub(crate) queued_messages: IntGauge,
+    /// Time from accepting a circuit until the handshake response is sent
+    pub(crate) handshake_latency: Histogram,
+}
+
+impl Default for NodeStats {
+    fn default() -> Self {
+        let counter = |name, help| IntCounter::new(name, help).expect("Metric is valid");
+        let gauge = |name, help| IntGauge::new(name, help).expect("Metric is valid");
+        Self {
+            circuits_opened: counter("rustor_node_circuits_opened_total", "Circuits opened"),
+            circuits_failed: counter("rustor_node_circuits_failed_total", "Circuits that failed"),
+            circuits_active: gauge("rustor_node_circuits_active", "Circuits currently open"),
+            bytes_forward: counter(
+                "rustor_node_bytes_forward_total",
+                "Bytes relayed away from the client",
+            ),
+            bytes_backward: counter(
+                "rustor_node_bytes_backward_total",
+                "Bytes relayed towards the client",
+            ),
+            decrypt_failures: counter(
+                "rustor_node_decrypt_failures_total",
+                "Messages that failed decryption",
+            ),
+            queued_messages: gauge(
+                "rustor_node_queued_messages",
+                "Messages waiting to be relayed",
+            ),
+            handshake_latency: Histogram::with_opts(
+                HistogramOpts::new(
+                    "rustor_node_handshake_latency_seconds",
+                    "Time from accepting a circuit until the handshake response is sent",
+                )
+                .buckets(
+                    prometheus::exponential_buckets(0.0005, 2.0, 16).expect("Buckets are valid"),
+                ),
+            )
+            .expect("Metric is valid"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct NodeStatsSnapshot {
+    pub circuits_opened: u64,
+    pub circuits_failed: u64,
+    pub circuits_active: u64,
+    pub bytes_forward: u64,
+    pub bytes_backward: u64,
+    pub decrypt_failures: u64,
+    pub queued_messages: u64,
+}
+
+impl NodeStats {
+    pub fn snapshot(&self) -> NodeStatsSnapshot {
+        NodeStatsSnapshot {
+            circuits_opened: self.circuits_opened.get(),
+            circuits_failed: self.circuits_failed.get(),
+            circuits_active: self.circuits_active.get() as u64,
+            bytes_forward: self.bytes_forward.get(),
+            bytes_backward: self.bytes_backward.get(),
+            decrypt_failures: self.decrypt_failures.get(),
+            queued_messages: self.queued_messages.get() as u64,
+        }
+    }
+
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.circuits_opened.clone()))?;
+        registry.register(Box::new(self.circuits_failed.clone()))?;
+        registry.register(Box::new(self.circuits_active.clone()))?;
+        registry.register(Box::new(self.bytes_forward.clone()))?;
+        registry.register(Box::new(self.bytes_backward.clone()))?;
+        registry.register(Box::new(self.decrypt_failures.clone()))?;
+        registry.register(Box::new(self.queued_messages.clone()))?;
+        registry.register(Box::new(self.handshake_latency.clone()))?;
+        Ok(())
+    }
+}
+
+/// Builder for a tor node running inside the current tokio runtime
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let node = rustor::tor::node_server::NodeServer::new()
+///     .bind("127.0.0.1:0".parse()?)
+///     .start()
+///     .await?;
+/// println!("Listening on {}", node.local_addr());
+/// node.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct NodeServer {
+    bind: SocketAddr,
+    advertised: Option<SocketAddr>,
+    directories: Vec<String>,
+    heartbeat_interval: Duration,
+    drain_period: Duration,
+    bandwidth: Option<BandwidthLimit>,
+    control: Option<ControlConfig>,
+    metrics: Option<SocketAddr>,
+    settings: NodeSettings,
+    info: NodeInfo,
+}
+
+impl Default for NodeServer {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+            advertised: None,
+            directories: vec![],
+            heartbeat_interval: Duration::from_secs(60),
+            drain_period: Duration::ZERO,
+            bandwidth: None,
+            control: None,
+            metrics: None,
+            settings: NodeSettings::default(),
+            info: NodeInfo::default(),
+        }
+    }
+}
+
+impl NodeServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// Address registered at the directories, defaults to the bound address
+    pub fn advertise(mut self, addr: SocketAddr) -> Self {
+        self.advertised = Some(addr);
+        self
+    }
+
+    /// Adds a directory to register the node at, nodes without a directory are not registered
+    pub fn directory(mut self, url: impl Into<String>) -> Self {
+        self.directories.push(url.into());
+        self
+    }
+
+    /// Time between heartbeats to the directories, which drop nodes that stop sending them. At
+    /// least [`MIN_HEARTBEAT_INTERVAL`]
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval.max(MIN_HEARTBEAT_INTERVAL);
+        self
+    }
+
+    pub fn bandwidth(mut self, bandwidth: Option<BandwidthLimit>) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Keys the node is identified by, fresh keys are generated when missing
+    pub fn keys(mut self, keys: NodeKeys) -> Self {
+        self.info.keys = keys;
+        self
+    }
+
+    pub fn nickname(mut self, nickname: impl Into<String>) -> Self {
+        self.info.nickname = nickname.into();
+        self
+    }
+
+    pub fn contact(mut self, contact: Option<String>) -> Self {
+        self.info.contact = contact;
+        self
+    }
+
+    /// Clients never use two nodes of the same family in one circuit
+    pub fn family(mut self, family: Option<String>) -> Self {
+        self.info.family = family;
+        self
+    }
+
+    /// Time existing circuits get to finish on shutdown before they are torn down
+    pub fn drain_period(mut self, drain_period: Duration) -> Self {
+        self.drain_period = drain_period;
+        self
+    }
+
+    /// Enables the control port, see [`control`] for the protocol
+    pub fn control(mut self, control: ControlConfig) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Serves prometheus metrics over http at `/metrics`
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics = Some(addr);
+        self
+    }
+
+    pub fn limits(mut self, limits: NodeLimits) -> Self {
+        self.settings.limits = limits;
+        self
+    }
+
+    pub fn exit_policy(mut self, exit_policy: ExitPolicy) -> Self {
+        self.settings.exit_policy = exit_policy;
+        self
+    }
+
+    pub async fn start(self) -> anyhow::Result<NodeHandle> {
+        let listener = TcpListener::bind(self.bind).await?;
+        let local_addr = listener.local_addr()?;
+        let advertised_addr = self.advertised.unwrap_or(local_addr);
+        let control_listener = match &self.control {
+            Some(control) => Some(TcpListener::bind(control.address).await?),
+            None => None,
+        };
+        let stats = Arc::new(NodeStats::default());
+        let stop_control = CancellationToken::new();
+        let metrics_addr = match self.metrics {
+            Some(addr) => {
+                let registry = Registry::new();
+                stats.register(&registry)?;
+                let (metrics_addr, server) =
+                    metrics::bind(addr, registry, stop_control.clone()).await?;
+                tokio::spawn(server);
+                Some(metrics_addr)
+            }
+            None => None,
+        };
+
+        let published = SystemTime::now();
+        let descriptor = self.info.descriptor(
+            advertised_addr,
+            &self.settings.exit_policy,
+            self.bandwidth,
+            published,
+        );
+        if let Err(err) = register(&self.directories, &self.info, &descriptor).await {
+            stop_control.cancel();
+            return Err(err);
+        }
+        info!(
+            "Listening on {}, advertised as {}",
+            local_addr, advertised_addr
+        );
+
+        let bandwidth = Arc::new(BandwidthLimiter::new(self.bandwidth));
+        let registry = Arc::new(CircuitRegistry::default());
+        let control_addr = match (control_listener, self.control) {
+            (Some(control_listener), Some(control)) => {
+                let control_addr = control_listener.local_addr()?;
+                info!("Control port listening on {}", control_addr);
+                tokio::spawn(control::serve(
+                    control_listener,
+                    ControlContext {
+                        password: control.password.into(),
+                        circuits: registry.clone(),
+                        stats: stats.clone(),
+                    },
+                    stop_control.clone(),
+                ));
+                Some(control_addr)
+            }
+            _ => None,
+        };
+        let stop_accepting = CancellationToken::new();
+        let teardown = CancellationToken::new();
+        let circuits = TaskTracker::new();
+        let (settings, settings_receiver) = watch::channel(Arc::new(self.settings));
+        let task = tokio::spawn(accept_loop(
+            listener,
+            settings_receiver,
+            NodeShared {
+                stats: stats.clone(),
+                bandwidth: bandwidth.clone(),
+                registry: registry.clone(),
+            },
+            stop_accepting.clone(),
+            teardown.clone(),
+            circuits.clone(),
+        ));
+
+        let registration = Arc::new(Registration {
+            info: self.info,
+            addr: advertised_addr,
+            directories: RwLock::new(self.directories),
+            settings: settings.subscribe(),
+            bandwidth: bandwidth.clone(),
+            published: Mutex::new(unix_secs(published)),
+        });
+        let heartbeat = tokio::spawn(heartbeat_loop(
+            registration.clone(),
+            stats.clone(),
+            self.heartbeat_interval,
+        ));
+
+        Ok(NodeHandle {
+            local_addr,
+            advertised_addr,
+            control_addr,
+            metrics_addr,
+            registry,
+            stop_control,
+            registration,
+            heartbeat,
+            drain_period: self.drain_period,
+            settings,
+            bandwidth,
+            stats,
+            stop_accepting,
+            teardown,
+            circuits,
+            task,
+        })
+    }
+}
+
+/// Registers at every directory, succeeding if at least one accepted the node
+async fn register(
+    directories: &[String],
+    info: &NodeInfo,
+    descriptor: &SignedDescriptor,
+) -> anyhow::Result<()> {
+    let mut registered = directories.is_empty();
+    for directory in directories {
+        match node_directory::add_node(directory, info, descriptor.clone()).await {
+            Ok(()) => registered = true,
+            Err(err) => warn!("Failed registering at {}: {:?}", directory, err),
+        }
+    }
+    if !registered {
+        anyhow::bail!(
+            "Failed registering at any of the directories {:?}",
+            directories
+        )
+    }
+    Ok(())
+}
+
+/// What the node publishes about itself, shared with the heartbeat task
+struct Registration {
+    info: NodeInfo,
+    addr: SocketAddr,
+    directories: RwLock<Vec<String>>,
+    settings: watch::Receiver<Arc<NodeSettings>>,
+    bandwidth: Arc<BandwidthLimiter>,
+    /// When the last descriptor was published
+    published: Mutex<u64>,
+}
+
+impl Registration {
+    fn directories(&self) -> Vec<String> {
+        self.directories
+            .read()
+            .expect("Directories lock poisoned")
+            .clone()
+    }
+
+    /// A freshly signed descriptor of the node's current settings. Directories only take
+    /// descriptors newer than the one they have, so it's published after the previous one
+    fn descriptor(&self) -> SignedDescriptor {
+        let mut published = self.published.lock().expect("Published lock poisoned");
+        *published = unix_secs(SystemTime::now()).max(*published + 1);
+        self.info.descriptor(
+            self.addr,
+            &self.settings.borrow().exit_policy,
+            self.bandwidth.limit(),
+            UNIX_EPOCH + Duration::from_secs(*published),
+        )
+    }
+}
+
+/// Sends the node's load to every directory, registering again at directories that lost the node
+async fn heartbeat_loop(
+    registration: Arc<Registration>,
+    stats: Arc<NodeStats>,
+    interval: Duration,
+) {
+    let relayed = || stats.bytes_forward.get() + stats.bytes_backward.get();
+    let mut ticker = tokio::time::interval(interval);
+    // The node registered right before
+    ticker.tick().await;
+    let mut last = (Instant::now(), relayed());
+    loop {
+        ticker.tick().await;
+        let now = (Instant::now(), relayed());
+        let bandwidth = (now.1 - last.1) as f64 / (now.0 - last.0).as_secs_f64();
+        last = now;
+
+        let heartbeat = registration.info.heartbeat(
+            registration.addr,
+            stats.circuits_active.get() as u64,
+            bandwidth as u64,
+            SystemTime::now(),
+        );
+        for directory in registration.directories() {
+            match node_directory::heartbeat(&directory, &heartbeat).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("{} doesn't know the node, registering again", directory);
+                    let descriptor = registration.descriptor();
+                    let info = &registration.info;
+                    if let Err(err) = node_directory::add_node(&directory, info, descriptor).await {
+                        warn!("Failed registering at {}: {:?}", directory, err);
+                    }
+                }
+                Err(err) => warn!("Failed sending heartbeat to {}: {:?}", directory, err),
+            }
+        }
+    }
+}
+
+/// State every circuit of the node shares
+struct NodeShared {
+    stats: Arc<NodeStats>,
+    bandwidth: Arc<BandwidthLimiter>,
+    registry: Arc<CircuitRegistry>,
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    settings: watch::Receiver<Arc<NodeSettings>>,
+    shared: NodeShared,
+    stop_accepting: CancellationToken,
+    teardown: CancellationToken,
+    circuits: TaskTracker,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            _ = stop_accepting.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("Failed accepting connection: {:?}", err);
+                    continue;
+                }
+            }
+        };
+
+        // Circuits keep the settings they started with, reloads only affect new circuits
+        let settings = settings.borrow().clone();
+        if let Some(max_circuits) = settings.limits.max_circuits {
+            if shared.stats.circuits_active.get() as u64 >= max_circuits {
+                warn!("Circuit limit reached, dropping connection from {}", addr);
+                continue;
+            }
+        }
+        let stats = shared.stats.clone();
+        let registry = shared.registry.clone();
+        stats.circuits_opened.inc();
+        stats.circuits_active.inc();
+        let cancellation = teardown.child_token();
+        let circuit = registry.open(addr, cancellation.clone());
+        let context = CircuitContext {
+            settings,
+            stats: stats.clone(),
+            bandwidth: shared.bandwidth.clone(),
+            circuit: circuit.clone(),
+        };
+        let span = info_span!(
+            "circuit",
+            id = circuit.id(),
+            peer = %addr,
+            role = field::Empty,
+            forward_local = field::Empty
+        );
+        circuits.spawn(
+            async move {
+                info!("Circuit opened");
+                let result = handle_connection(stream, context, cancellation).await;
+                match &result {
+                    Ok(()) => info!("Circuit closed"),
+                    Err(err) => {
+                        error!("Circuit failed: {:?}", err);
+                        stats.circuits_failed.inc();
+                    }
+                }
+                registry.close(circuit.id(), result.err().as_ref());
+                stats.circuits_active.dec();
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Handle to a running [`NodeServer`]
+pub struct NodeHandle {
+    local_addr: SocketAddr,
+    advertised_addr: SocketAddr,
+    control_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    registry: Arc<CircuitRegistry>,
+    stop_control: CancellationToken,
+    registration: Arc<Registration>,
+    heartbeat: JoinHandle<()>,
+    drain_period: Duration,
+    settings: watch::Sender<Arc<NodeSettings>>,
+    bandwidth: Arc<BandwidthLimiter>,
+    stats: Arc<NodeStats>,
+    stop_accepting: CancellationToken,
+    teardown: CancellationToken,
+    circuits: TaskTracker,
+    task: JoinHandle<()>,
+}
+
+impl NodeHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn advertised_addr(&self) -> SocketAddr {
+        self.advertised_addr
+    }
+
+    pub fn control_addr(&self) -> Option<SocketAddr> {
+        self.control_addr
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Encoded identity key
+    pub fn identity(&self) -> String {
+        self.registration.info.keys.identity()
+    }
+
+    /// A freshly signed descriptor of the node's current settings
+    pub fn descriptor(&self) -> SignedDescriptor {
+        self.registration.descriptor()
+    }
+
+    pub fn stats(&self) -> NodeStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    pub fn circuits(&self) -> &CircuitRegistry {
+        &self.registry
+    }
+
+    pub fn settings(&self) -> Arc<NodeSettings> {
+        self.settings.borrow().clone()
+    }
+
+    /// Replaces the settings used by new circuits, existing circuits keep their settings
+    pub fn update_settings(&self, settings: NodeSettings) {
+        self.settings.send_replace(Arc::new(settings));
+    }
+
+    pub fn bandwidth(&self) -> Option<BandwidthLimit> {
+        self.bandwidth.limit()
+    }
+
+    /// Changes the node wide bandwidth limit, applies to existing circuits as well
+    pub fn update_bandwidth(&self, bandwidth: Option<BandwidthLimit>) {
+        self.bandwidth.set_limit(bandwidth);
+    }
+
+    pub fn directories(&self) -> Vec<String> {
+        self.registration.directories()
+    }
+
+    /// Publishes a descriptor of the current settings to every directory and deregisters from
+    /// ones that were removed. Nothing changes unless every added directory accepts the node
+    pub async fn update_directories(&self, directories: Vec<String>) -> anyhow::Result<()> {
+        let previous = self.directories();
+        let descriptor = self.descriptor();
+        let (kept, added): (Vec<_>, Vec<_>) = directories
+            .iter()
+            .partition(|directory| previous.contains(directory));
+
+        let mut registered = vec![];
+        for directory in added {
+            let result =
+                node_directory::add_node(directory, &self.registration.info, descriptor.clone())
+                    .await;
+            if let Err(err) = result {
+                for directory in registered {
+                    self.deregister(directory).await;
+                }
+                return Err(err.context(format!("Failed registering at {}", directory)));
+            }
+            registered.push(directory);
+        }
+        // The heartbeats register the node again at directories that miss the new descriptor
+        for directory in kept {
+            let result =
+                node_directory::add_node(directory, &self.registration.info, descriptor.clone())
+                    .await;
+            if let Err(err) = result {
+                warn!("Failed registering at {}: {:?}", directory, err);
+            }
+        }
+        for directory in previous.iter().filter(|d| !directories.contains(d)) {
+            self.deregister(directory).await;
+        }
+
+        *self
+            .registration
+            .directories
+            .write()
+            .expect("Directories lock poisoned") = directories;
+        Ok(())
+    }
+
+    async fn deregister(&self, directory: &str) {
+        if let Err(err) =
+            node_directory::remove_node(directory, &self.registration.info, self.advertised_addr)
+                .await
+        {
+            warn!("Failed deregistering from {}: {:?}", directory, err);
+        }
+    }
+
+    /// Deregisters from the directory and stops accepting connections.
+    /// Circuits still open after the drain period are sent a destroy message and closed.
+    pub async fn shutdown(mut self) {
+        // A heartbeat after deregistering would register the node again
+        self.heartbeat.abort();
+        let _ = (&mut self.heartbeat).await;
+        for directory in &self.directories() {
+            self.deregister(directory).await;
+        }
+
+        self.stop_accepting.cancel();
+        if let Err(err) = self.task.await {
+            error!("Node accept loop panicked: {:?}", err);
+        }
+
+        self.circuits.close();
+        if timeout(self.drain_period, self.circuits.wait())
+            .await
+            .is_err()
+        {
+            info!(
+                "Tearing down {} remaining circuits",
+                self.stats.circuits_active.get()
+            );
+        }
+        self.teardown.cancel();
+        self.circuits.wait().await;
+        self.stop_control.cancel();
+    }
+
+    /// Waits until the node stops
+    pub async fn join(self) -> anyhow::Result<()> {
+        self.task.await?;
+        Ok(())
+    }
+}
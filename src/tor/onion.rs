@@ -1,6 +1,9 @@
 use crate::encryption::{Encryptor, PublicKeyBytes};
 
-use super::tor_message::{Next, TorMessage};
+use super::{
+    error::TorError,
+    tor_message::{Next, TorMessage},
+};
 
 pub fn onion_wrap_tor_message(
     nodes: &[(Option<&Encryptor>, Next)],
@@ -77,15 +80,24 @@ pub fn onion_wrap_handshake(
 pub fn decrypt_onion_layers(
     encryptors: &[&Encryptor],
     data: TorMessage,
-) -> anyhow::Result<TorMessage> {
-    encryptors.iter().try_fold(data, |current_data, encryptor| {
-        let TorMessage::NotForYou { data: encrypted } = current_data else {
-            anyhow::bail!("Invalid packet, didn't receive notforyou");
-        };
-        let decrypted = encryptor.decrypt(&encrypted)?;
-        let deserialized = bincode::deserialize(&decrypted[..])?;
-        Ok(deserialized)
-    })
+) -> Result<TorMessage, TorError> {
+    encryptors
+        .iter()
+        .enumerate()
+        .try_fold(data, |current_data, (i, encryptor)| {
+            // A node tearing down the circuit sends destroy without any of the outer layers
+            if let TorMessage::Destroy(_) = current_data {
+                return Ok(current_data);
+            }
+            let TorMessage::NotForYou { data: encrypted } = current_data else {
+                return Err(TorError::UnexpectedMessage("didn't receive notforyou"));
+            };
+            let decrypted = encryptor
+                .decrypt(&encrypted)
+                .map_err(|_| TorError::HopDecryptFailed { hop: i + 1 })?;
+            let deserialized = bincode::deserialize(&decrypted[..])?;
+            Ok(deserialized)
+        })
 }
 
 #[cfg(test)]
@@ -93,7 +105,7 @@ mod tests {
     use std::net::{Ipv4Addr, SocketAddrV4};
 
     use super::*;
-    use crate::encryption::KeyPair;
+    use crate::{encryption::KeyPair, tor::tor_message::DestroyReason};
 
     const BOB_NODE: Next = Next::Node(std::net::SocketAddr::V4(SocketAddrV4::new(
         Ipv4Addr::new(1, 1, 1, 1),
@@ -184,4 +196,52 @@ mod tests {
         };
         assert_eq!(pubkey, bob.initial_public_message());
     }
+
+    #[test]
+    fn test_decrypt_destroy_from_inner_node() -> anyhow::Result<()> {
+        let alice = KeyPair::default();
+        let client_alice = KeyPair::default();
+        let alice_public = alice.initial_public_message();
+        let alice = alice.handshake(client_alice.initial_public_message());
+        let client_alice = client_alice.handshake(alice_public);
+
+        let bob = KeyPair::default();
+        let client_bob = KeyPair::default().handshake(bob.initial_public_message());
+
+        // Alice wraps the destroy bob sent, bob's layer is never added
+        let destroy = TorMessage::NotForYou {
+            data: alice.encrypt(&bincode::serialize(&TorMessage::Destroy(
+                DestroyReason::Teardown,
+            ))?),
+        };
+
+        let message = decrypt_onion_layers(&[&client_alice, &client_bob], destroy)?;
+        assert_eq!(message, TorMessage::Destroy(DestroyReason::Teardown));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_reports_failing_hop() {
+        let alice = KeyPair::default();
+        let client_alice = KeyPair::default();
+        let alice_public = alice.initial_public_message();
+        let alice = alice.handshake(client_alice.initial_public_message());
+        let client_alice = client_alice.handshake(alice_public);
+        let client_bob = KeyPair::default().handshake(KeyPair::default().initial_public_message());
+
+        // Alice's layer is valid, the inner layer wasn't encrypted for the client's bob key
+        let message = TorMessage::NotForYou {
+            data: alice.encrypt(
+                &bincode::serialize(&TorMessage::NotForYou {
+                    data: alice.encrypt(b"data"),
+                })
+                .unwrap(),
+            ),
+        };
+
+        assert!(matches!(
+            decrypt_onion_layers(&[&client_alice, &client_bob], message),
+            Err(TorError::HopDecryptFailed { hop: 2 })
+        ));
+    }
 }
@@ -0,0 +1,473 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    sync::Arc,
+};
+
+use rand::{rngs::OsRng, seq::SliceRandom};
+use serde::{Deserialize, Serialize};
+
+use super::{consensus::selection_weight, descriptor::NodeDescriptor, error::TorError};
+
+/// A node that can be picked for a circuit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Relay {
+    pub addr: SocketAddr,
+    /// Nodes run by the same operator share a family, at most one of them is used per circuit
+    pub family: Option<String>,
+    /// Bytes per second the authorities measured, unknown when missing
+    pub bandwidth: Option<u64>,
+    /// Ports the relay exits to for any address
+    pub exit_ports: Vec<RangeInclusive<u16>>,
+}
+
+impl From<SocketAddr> for Relay {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            family: None,
+            bandwidth: None,
+            exit_ports: vec![0..=u16::MAX],
+        }
+    }
+}
+
+impl From<NodeDescriptor> for Relay {
+    fn from(descriptor: NodeDescriptor) -> Self {
+        Self {
+            addr: descriptor.addr,
+            family: descriptor.family,
+            // The descriptor only has the node's own claim, the consensus has the measurement
+            bandwidth: None,
+            exit_ports: descriptor.exit_ports,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Entry,
+    Middle,
+    Exit,
+}
+
+/// Picks the relay for each position of a path
+pub trait PathStrategy: Send + Sync {
+    /// `candidates` satisfy every constraint of the selector and is never empty
+    fn choose<'a>(&self, position: Position, candidates: &[&'a Relay]) -> &'a Relay;
+}
+
+/// Picks uniformly at random
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomStrategy;
+
+impl PathStrategy for RandomStrategy {
+    fn choose<'a>(&self, _: Position, candidates: &[&'a Relay]) -> &'a Relay {
+        candidates.choose(&mut OsRng).expect("Isn't empty")
+    }
+}
+
+/// Picks at random in proportion to the measured bandwidth like the directories do, see
+/// [`selection_weight`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BandwidthWeightedStrategy;
+
+impl PathStrategy for BandwidthWeightedStrategy {
+    fn choose<'a>(&self, _: Position, candidates: &[&'a Relay]) -> &'a Relay {
+        candidates
+            .choose_weighted(&mut OsRng, |relay| selection_weight(relay.bandwidth))
+            .copied()
+            .expect("Weights are positive")
+    }
+}
+
+/// Chooses which relays a circuit goes through
+///
+/// ```
+/// use rustor::tor::path::{PathSelector, Relay};
+///
+/// let relays = ["10.0.0.1:9001", "10.1.0.1:9001", "10.2.0.1:9001"]
+///     .map(|addr| Relay::from(addr.parse::<std::net::SocketAddr>().unwrap()));
+/// let path = PathSelector::default().hops(3).select(&relays).unwrap();
+/// assert_eq!(path.len(), 3);
+/// ```
+#[derive(Clone)]
+pub struct PathSelector {
+    hops: usize,
+    entry_nodes: Vec<SocketAddr>,
+    exit_nodes: Vec<SocketAddr>,
+    exclude_nodes: Vec<SocketAddr>,
+    exit_port: Option<u16>,
+    distinct_subnets: bool,
+    strategy: Arc<dyn PathStrategy>,
+}
+
+impl Default for PathSelector {
+    fn default() -> Self {
+        Self {
+            hops: 3,
+            entry_nodes: vec![],
+            exit_nodes: vec![],
+            exclude_nodes: vec![],
+            exit_port: None,
+            distinct_subnets: true,
+            strategy: Arc::new(BandwidthWeightedStrategy),
+        }
+    }
+}
+
+impl fmt::Debug for PathSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathSelector")
+            .field("hops", &self.hops)
+            .field("entry_nodes", &self.entry_nodes)
+            .field("exit_nodes", &self.exit_nodes)
+            .field("exclude_nodes", &self.exclude_nodes)
+            .field("exit_port", &self.exit_port)
+            .field("distinct_subnets", &self.distinct_subnets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PathSelector {
+    /// Nodes in every circuit, panics when `hops` is zero
+    pub fn hops(mut self, hops: usize) -> Self {
+        assert!(hops > 0, "A circuit needs at least one hop");
+        self.hops = hops;
+        self
+    }
+
+    pub fn hop_count(&self) -> usize {
+        self.hops
+    }
+
+    /// The first hop is one of these, any node when empty
+    pub fn entry_nodes(mut self, nodes: Vec<SocketAddr>) -> Self {
+        self.entry_nodes = nodes;
+        self
+    }
+
+    /// The last hop is one of these, any node when empty
+    pub fn exit_nodes(mut self, nodes: Vec<SocketAddr>) -> Self {
+        self.exit_nodes = nodes;
+        self
+    }
+
+    /// Never used, even when listed as entry or exit
+    pub fn exclude_nodes(mut self, nodes: Vec<SocketAddr>) -> Self {
+        self.exclude_nodes = nodes;
+        self
+    }
+
+    /// A copy that also never uses `nodes`
+    pub fn excluding(&self, nodes: &[SocketAddr]) -> Self {
+        let mut selector = self.clone();
+        selector.exclude_nodes.extend_from_slice(nodes);
+        selector
+    }
+
+    /// The exit has to accept this port for any address
+    pub fn exit_port(mut self, port: u16) -> Self {
+        self.exit_port = Some(port);
+        self
+    }
+
+    /// Whether two hops may share an IPv4 /16 or IPv6 /32, only worth disabling for local networks
+    pub fn distinct_subnets(mut self, distinct: bool) -> Self {
+        self.distinct_subnets = distinct;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: impl PathStrategy + 'static) -> Self {
+        self.strategy = Arc::new(strategy);
+        self
+    }
+
+    /// Picks the exit first, then the entry and the middle hops, ordered from entry to exit
+    pub fn select(&self, relays: &[Relay]) -> Result<Vec<SocketAddr>, TorError> {
+        if relays.len() < self.hops {
+            return Err(TorError::NotEnoughNodes {
+                needed: self.hops,
+                available: relays.len(),
+            });
+        }
+
+        let mut chosen: Vec<&Relay> = Vec::with_capacity(self.hops);
+        let mut pick = |position: Position, hop: usize| {
+            let candidates = relays
+                .iter()
+                .filter(|relay| self.allows(relay, position) && self.diverse(relay, &chosen))
+                .collect::<Vec<_>>();
+            if candidates.is_empty() {
+                return Err(TorError::NoEligibleRelay { hop });
+            }
+            chosen.push(self.strategy.choose(position, &candidates));
+            Ok(())
+        };
+
+        pick(Position::Exit, self.hops)?;
+        if self.hops > 1 {
+            pick(Position::Entry, 1)?;
+        }
+        for hop in 2..self.hops {
+            pick(Position::Middle, hop)?;
+        }
+
+        // Reorder from exit, entry, middles to entry, middles, exit
+        chosen.rotate_left(1);
+        Ok(chosen.into_iter().map(|relay| relay.addr).collect())
+    }
+
+    /// Whether the entry, exit and excluded nodes let `relay` take `position`
+    pub fn allows(&self, relay: &Relay, position: Position) -> bool {
+        let listed = |nodes: &[SocketAddr]| nodes.is_empty() || nodes.contains(&relay.addr);
+        // A single hop is the entry and the exit at once
+        let entry = position == Position::Entry || (position == Position::Exit && self.hops == 1);
+        let exit_port = |relay: &Relay| {
+            self.exit_port
+                .is_none_or(|port| relay.exit_ports.iter().any(|ports| ports.contains(&port)))
+        };
+        !self.exclude_nodes.contains(&relay.addr)
+            && (!entry || listed(&self.entry_nodes))
+            && (position != Position::Exit || (listed(&self.exit_nodes) && exit_port(relay)))
+    }
+
+    fn diverse(&self, relay: &Relay, chosen: &[&Relay]) -> bool {
+        chosen.iter().all(|other| {
+            other.addr != relay.addr
+                && (!self.distinct_subnets || subnet(other.addr.ip()) != subnet(relay.addr.ip()))
+                && (relay.family.is_none() || other.family != relay.family)
+        })
+    }
+}
+
+/// The /16 of IPv4 addresses and /32 of IPv6 addresses
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            Ipv4Addr::new(a, b, 0, 0).into()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, ..] = ip.segments();
+            Ipv6Addr::new(a, b, 0, 0, 0, 0, 0, 0).into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tor::consensus::MAX_SELECTION_BANDWIDTH;
+
+    fn relay(addr: &str, family: Option<&str>) -> Relay {
+        Relay {
+            family: family.map(str::to_string),
+            ..Relay::from(addr.parse::<SocketAddr>().unwrap())
+        }
+    }
+
+    fn relays(addrs: &[&str]) -> Vec<Relay> {
+        addrs.iter().map(|addr| relay(addr, None)).collect()
+    }
+
+    /// Always picks the first candidate
+    struct First;
+
+    impl PathStrategy for First {
+        fn choose<'a>(&self, _: Position, candidates: &[&'a Relay]) -> &'a Relay {
+            candidates[0]
+        }
+    }
+
+    #[test]
+    fn picks_hop_count_distinct_relays() {
+        let relays = relays(&[
+            "10.0.0.1:1",
+            "10.1.0.1:1",
+            "10.2.0.1:1",
+            "10.3.0.1:1",
+            "10.4.0.1:1",
+        ]);
+        for hops in 1..=5 {
+            let path = PathSelector::default().hops(hops).select(&relays).unwrap();
+            assert_eq!(path.len(), hops);
+            let mut unique = path.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), hops);
+        }
+    }
+
+    #[test]
+    fn hops_never_share_a_subnet() {
+        let relays = relays(&[
+            "10.0.0.1:1",
+            "10.0.200.1:1",
+            "10.0.0.1:2",
+            "10.1.0.1:1",
+            "[2001:db8::1]:1",
+            "[2001:db8:ffff::1]:1",
+        ]);
+        for _ in 0..50 {
+            let path = PathSelector::default().select(&relays).unwrap();
+            let mut subnets = path
+                .iter()
+                .map(|addr| subnet(addr.ip()))
+                .collect::<Vec<_>>();
+            subnets.sort();
+            subnets.dedup();
+            assert_eq!(subnets.len(), 3);
+        }
+
+        let same_subnet = relays[..3].to_vec();
+        assert!(matches!(
+            PathSelector::default().select(&same_subnet),
+            Err(TorError::NoEligibleRelay { hop: 1 })
+        ));
+        assert!(PathSelector::default()
+            .distinct_subnets(false)
+            .select(&same_subnet)
+            .is_ok());
+    }
+
+    #[test]
+    fn hops_never_share_a_family() {
+        let relays = vec![
+            relay("10.0.0.1:1", Some("alice")),
+            relay("10.1.0.1:1", Some("alice")),
+            relay("10.2.0.1:1", Some("bob")),
+            relay("10.3.0.1:1", None),
+            relay("10.4.0.1:1", None),
+        ];
+        for _ in 0..50 {
+            let path = PathSelector::default().hops(4).select(&relays).unwrap();
+            let alice = path
+                .iter()
+                .filter(|addr| {
+                    relays.iter().any(|relay| {
+                        relay.addr == **addr && relay.family.as_deref() == Some("alice")
+                    })
+                })
+                .count();
+            assert_eq!(alice, 1);
+        }
+        assert!(matches!(
+            PathSelector::default().hops(5).select(&relays),
+            Err(TorError::NoEligibleRelay { .. })
+        ));
+    }
+
+    #[test]
+    fn entry_exit_and_excluded_nodes() {
+        let relays = relays(&[
+            "10.0.0.1:1",
+            "10.1.0.1:1",
+            "10.2.0.1:1",
+            "10.3.0.1:1",
+            "10.4.0.1:1",
+        ]);
+        let addr = |i: usize| relays[i].addr;
+        let selector = PathSelector::default()
+            .entry_nodes(vec![addr(0), addr(1)])
+            .exit_nodes(vec![addr(1), addr(4)])
+            .exclude_nodes(vec![addr(1), addr(2)]);
+        for _ in 0..50 {
+            let path = selector.select(&relays).unwrap();
+            assert_eq!(path, vec![addr(0), addr(3), addr(4)]);
+        }
+
+        let selector = selector.exclude_nodes(vec![addr(4)]);
+        for _ in 0..50 {
+            let path = selector.select(&relays).unwrap();
+            assert!(path[0] == addr(0) || path[0] == addr(1));
+            assert!(path[2] == addr(1));
+            assert!(!path[1..].contains(&addr(4)));
+        }
+
+        let single = PathSelector::default()
+            .hops(1)
+            .entry_nodes(vec![addr(0), addr(2)])
+            .exit_nodes(vec![addr(2), addr(3)]);
+        assert_eq!(single.select(&relays).unwrap(), vec![addr(2)]);
+    }
+
+    #[test]
+    fn strategy_decides_among_candidates() {
+        let relays = relays(&["10.0.0.1:1", "10.1.0.1:1", "10.2.0.1:1", "10.3.0.1:1"]);
+        let path = PathSelector::default()
+            .strategy(First)
+            .select(&relays)
+            .unwrap();
+        // The exit is picked first
+        assert_eq!(path, vec![relays[1].addr, relays[2].addr, relays[0].addr]);
+    }
+
+    #[test]
+    fn reports_missing_relays() {
+        let relays = relays(&["10.0.0.1:1", "10.1.0.1:1"]);
+        assert!(matches!(
+            PathSelector::default().select(&relays),
+            Err(TorError::NotEnoughNodes {
+                needed: 3,
+                available: 2
+            })
+        ));
+        assert!(matches!(
+            PathSelector::default()
+                .hops(2)
+                .exit_nodes(vec!["10.9.0.1:1".parse().unwrap()])
+                .select(&relays),
+            Err(TorError::NoEligibleRelay { hop: 2 })
+        ));
+    }
+
+    #[test]
+    fn exit_accepts_the_port() {
+        let mut relays = relays(&["10.0.0.1:1", "10.1.0.1:1", "10.2.0.1:1", "10.3.0.1:1"]);
+        relays[0].exit_ports = vec![443..=443];
+        relays[1].exit_ports = vec![];
+        relays[2].exit_ports = vec![80..=80, 1000..=2000];
+        relays[3].exit_ports = vec![];
+
+        let selector = PathSelector::default().hops(2);
+        for _ in 0..50 {
+            assert_eq!(
+                selector.clone().exit_port(443).select(&relays).unwrap()[1],
+                relays[0].addr
+            );
+            assert_eq!(
+                selector.clone().exit_port(1500).select(&relays).unwrap()[1],
+                relays[2].addr
+            );
+        }
+        assert!(matches!(
+            selector.exit_port(22).select(&relays),
+            Err(TorError::NoEligibleRelay { hop: 2 })
+        ));
+    }
+
+    #[test]
+    fn weights_by_bandwidth() {
+        let mut relays = relays(&["10.0.0.1:1", "10.1.0.1:1", "10.2.0.1:1", "10.3.0.1:1"]);
+        relays[0].bandwidth = Some(MAX_SELECTION_BANDWIDTH / 8);
+        // Counts as the largest bandwidth, the weights can't overflow
+        relays[1].bandwidth = Some(u64::MAX);
+        relays[2].bandwidth = Some(u64::MAX);
+        // Unmeasured relays count as the smallest bandwidth
+        relays[3].bandwidth = None;
+        let candidates = relays.iter().collect::<Vec<_>>();
+
+        let mut picks = [0usize; 4];
+        for _ in 0..10_000 {
+            let relay = BandwidthWeightedStrategy.choose(Position::Middle, &candidates);
+            picks[relays.iter().position(|other| other == relay).unwrap()] += 1;
+        }
+        // Expected 588, 4706, 4706 and 0
+        assert!((350..850).contains(&picks[0]), "{picks:?}");
+        assert!((4200..5200).contains(&picks[1]), "{picks:?}");
+        assert!((4200..5200).contains(&picks[2]), "{picks:?}");
+        assert!(picks[3] < 10, "{picks:?}");
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf},
+    net::TcpStream,
+};
+
+use super::{
+    client::{nodes_handshake, TorClient},
+    error::TorError,
+};
+
+/// Largest payload sent in a single message, larger writes are split
+pub const MAX_PAYLOAD: usize = 8 * 1024;
+
+type ClientFuture<T, O> = Pin<Box<dyn Future<Output = (TorClient<T>, O)> + Send>>;
+
+fn into_io_error(err: TorError) -> io::Error {
+    match err {
+        TorError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// A circuit as a byte stream, usable by any tokio based protocol library
+///
+/// ```no_run
+/// # async fn run(nodes: Vec<std::net::SocketAddr>, server: std::net::SocketAddr) -> anyhow::Result<()> {
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+///
+/// let mut stream = rustor::tor::stream::TorStream::connect(nodes, server).await?;
+/// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+/// stream.flush().await?;
+/// let mut response = vec![];
+/// stream.read_to_end(&mut response).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TorStream {
+    reader: TorReadHalf,
+    writer: TorWriteHalf,
+}
+
+impl TorStream {
+    pub fn new(
+        reader: TorClient<ReadHalf<TcpStream>>,
+        writer: TorClient<WriteHalf<TcpStream>>,
+    ) -> Self {
+        Self {
+            reader: TorReadHalf {
+                state: ReadState::Idle(reader),
+                buffer: Vec::new(),
+                position: 0,
+            },
+            writer: TorWriteHalf {
+                state: WriteState::Idle(writer),
+            },
+        }
+    }
+
+    /// Builds a circuit through `nodes` to `server`
+    pub async fn connect(nodes: Vec<SocketAddr>, server: SocketAddr) -> Result<Self, TorError> {
+        let (reader, writer) = nodes_handshake(nodes, server).await?;
+        Ok(Self::new(reader, writer))
+    }
+
+    pub fn split(self) -> (TorReadHalf, TorWriteHalf) {
+        (self.reader, self.writer)
+    }
+}
+
+impl AsyncRead for TorStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TorStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+enum ReadState {
+    Idle(TorClient<ReadHalf<TcpStream>>),
+    Reading(ClientFuture<ReadHalf<TcpStream>, Result<Vec<u8>, TorError>>),
+    /// The server closed the connection
+    Eof,
+}
+
+/// Reading half of a [`TorStream`], keeps the unread part of the last message
+pub struct TorReadHalf {
+    state: ReadState,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl AsyncRead for TorReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.position < this.buffer.len() {
+                let amount = buf.remaining().min(this.buffer.len() - this.position);
+                buf.put_slice(&this.buffer[this.position..this.position + amount]);
+                this.position += amount;
+                return Poll::Ready(Ok(()));
+            }
+
+            match std::mem::replace(&mut this.state, ReadState::Eof) {
+                ReadState::Idle(mut client) => {
+                    this.state = ReadState::Reading(Box::pin(async move {
+                        let result = client.read().await;
+                        (client, result)
+                    }));
+                }
+                ReadState::Reading(mut read) => {
+                    let Poll::Ready((client, result)) = read.as_mut().poll(cx) else {
+                        this.state = ReadState::Reading(read);
+                        return Poll::Pending;
+                    };
+                    match result {
+                        // An empty message ends the stream, the state is left at eof
+                        Ok(message) if message.is_empty() => return Poll::Ready(Ok(())),
+                        Ok(message) => {
+                            this.state = ReadState::Idle(client);
+                            this.buffer = message;
+                            this.position = 0;
+                        }
+                        Err(err) => {
+                            this.state = ReadState::Idle(client);
+                            return Poll::Ready(Err(into_io_error(err)));
+                        }
+                    }
+                }
+                ReadState::Eof => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+enum WriteState {
+    Idle(TorClient<WriteHalf<TcpStream>>),
+    Writing(ClientFuture<WriteHalf<TcpStream>, Result<(), TorError>>),
+    ShuttingDown(ClientFuture<WriteHalf<TcpStream>, Result<(), TorError>>),
+    Closed,
+}
+
+/// Writing half of a [`TorStream`], each write sends at most [`MAX_PAYLOAD`] bytes
+pub struct TorWriteHalf {
+    state: WriteState,
+}
+
+impl TorWriteHalf {
+    /// Drives the pending write or shutdown until the client is idle again
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let (WriteState::Writing(pending) | WriteState::ShuttingDown(pending)) = &mut self.state
+        else {
+            return Poll::Ready(Ok(()));
+        };
+        let (client, result) = ready!(pending.as_mut().poll(cx));
+        self.state = if matches!(self.state, WriteState::ShuttingDown(_)) {
+            WriteState::Closed
+        } else {
+            WriteState::Idle(client)
+        };
+        Poll::Ready(result.map_err(into_io_error))
+    }
+}
+
+impl AsyncWrite for TorWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_idle(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let WriteState::Idle(mut client) = std::mem::replace(&mut self.state, WriteState::Closed)
+        else {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        };
+        let payload = buf[..buf.len().min(MAX_PAYLOAD)].to_vec();
+        let written = payload.len();
+        self.state = WriteState::Writing(Box::pin(async move {
+            let result = client.write(&payload).await;
+            (client, result)
+        }));
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_idle(cx)
+    }
+
+    /// Flushes and closes the circuit, the read half ends as well
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_idle(cx))?;
+            match std::mem::replace(&mut self.state, WriteState::Closed) {
+                WriteState::Idle(mut client) => {
+                    self.state = WriteState::ShuttingDown(Box::pin(async move {
+                        let result = client.shutdown().await;
+                        (client, result)
+                    }));
+                }
+                _ => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
@@ -4,9 +4,38 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum TorMessage {
-    NotForYou { data: Vec<u8> },
-    NextNode { next_encrypted: Vec<u8> },
+    NotForYou {
+        data: Vec<u8>,
+    },
+    NextNode {
+        next_encrypted: Vec<u8>,
+    },
     HandShake([u8; 32]),
+    /// Sent backward by a node that is tearing the circuit down
+    Destroy(DestroyReason),
+    /// Sent backward by the exit once it connected to the server
+    Connected,
+}
+
+/// Why a node tore a circuit down
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, thiserror::Error)]
+pub enum DestroyReason {
+    /// The node is shutting down or the circuit was closed through the control port
+    #[error("torn down by the node")]
+    Teardown,
+    /// The server closed its connection to the exit
+    #[error("server closed the connection")]
+    Finished,
+    #[error("next node is unreachable")]
+    RelayUnreachable,
+    #[error("rejected by the exit policy")]
+    ExitPolicy,
+    #[error("connection refused")]
+    ConnectRefused,
+    #[error("host unreachable")]
+    HostUnreachable,
+    #[error("connection timed out")]
+    ConnectTimeout,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]